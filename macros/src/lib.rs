@@ -0,0 +1,707 @@
+//! Procedural macros for `properly-concurrent`.
+//!
+//! Written against bare `proc_macro` to keep the dependency tree
+//! empty: the inputs we accept are simple enough to walk by
+//! hand.
+
+use proc_macro::{
+  Delimiter, Group, Ident, Punct, Spacing, Span, TokenStream,
+  TokenTree,
+};
+
+/// Turns a function taking
+/// `&mut properly_concurrent::explore::Choices` into a `#[test]`
+/// which runs it under the exploration loop.
+///
+/// Arguments are forwarded to `explore::Config` builder methods:
+/// `#[properly_concurrent::test(exhaustive)]` calls
+/// `.exhaustive()`,
+/// `#[properly_concurrent::test(iterations = 1000)]` calls
+/// `.iterations(1000)`.
+///
+/// A function without arguments becomes a plain `#[test]`, so
+/// that importing this macro in place of the built-in one is
+/// harmless.
+#[proc_macro_attribute]
+pub fn test(
+  attr: TokenStream,
+  item: TokenStream,
+) -> TokenStream {
+  match expand_test(attr, item) {
+    Ok(it) => it,
+    Err((span, message)) => compile_error(span, &message),
+  }
+}
+
+type Error = (Span, String);
+
+/// Points every `std::sync::atomic` path in the annotated item,
+/// usually a module, at the instrumented atomics, but only under
+/// `cfg(test)`:
+///
+/// ```text
+/// #[properly_concurrent::instrument_atomics]
+/// mod queue {
+///   use std::sync::atomic::{AtomicUsize, Ordering};
+///   ..
+/// }
+/// ```
+///
+/// The item is emitted twice, untouched under `cfg(not(test))`.
+/// A different condition can be passed as the argument, as in
+/// `#[instrument_atomics(any(test, feature = "instrument"))]`.
+/// Only paths spelled out through `std::sync::atomic` or
+/// `core::sync::atomic` are rewritten, including a `use` of the
+/// module itself, so `use std::sync::{atomic::AtomicU32, Mutex}`
+/// is left alone.
+#[proc_macro_attribute]
+pub fn instrument_atomics(
+  attr: TokenStream,
+  item: TokenStream,
+) -> TokenStream {
+  let condition =
+    if attr.is_empty() { ident("test").into() } else { attr };
+  let negated = [
+    ident("not"),
+    group(Delimiter::Parenthesis, condition.clone()),
+  ]
+  .into_iter()
+  .collect();
+  let mut result = cfg(negated);
+  result.extend(item.clone());
+  result.extend(cfg(condition));
+  result.extend(rewrite_atomics(item));
+  result
+}
+
+/// `#[cfg(condition)]`.
+fn cfg(condition: TokenStream) -> TokenStream {
+  let attr =
+    [ident("cfg"), group(Delimiter::Parenthesis, condition)];
+  [
+    punct('#'),
+    group(Delimiter::Bracket, attr.into_iter().collect()),
+  ]
+  .into_iter()
+  .collect()
+}
+
+/// Swaps `std` or `core` for `properly_concurrent` and `atomic`
+/// for `instrumented_atomic` in `std::sync::atomic`, keeping the
+/// spans. A path which ends there, as in
+/// `use std::sync::atomic;`, gets an `as atomic`, so that
+/// `atomic::AtomicU32` still resolves.
+fn rewrite_atomics(stream: TokenStream) -> TokenStream {
+  let mut tokens: Vec<TokenTree> = stream.into_iter().collect();
+  let mut aliases = Vec::new();
+  for i in 0..tokens.len() {
+    if let TokenTree::Group(it) = &tokens[i] {
+      let mut rewritten =
+        Group::new(it.delimiter(), rewrite_atomics(it.stream()));
+      rewritten.set_span(it.span());
+      tokens[i] = TokenTree::Group(rewritten);
+      continue;
+    }
+    let Some(window) = tokens.get(i..i + 7) else { continue };
+    let matches = window.iter().enumerate().all(|(j, token)| {
+      match (j % 3, token) {
+        (1 | 2, TokenTree::Punct(it)) => it.as_char() == ':',
+        (0, TokenTree::Ident(it)) => {
+          let name = it.to_string();
+          name == ["std", "sync", "atomic"][j / 3]
+            || (j == 0 && name == "core")
+        }
+        _ => false,
+      }
+    });
+    if matches {
+      for (j, name) in
+        [(0, "properly_concurrent"), (6, "instrumented_atomic")]
+      {
+        let span = tokens[i + j].span();
+        tokens[i + j] = TokenTree::Ident(Ident::new(name, span));
+      }
+      let goes_on = match tokens.get(i + 7) {
+        Some(TokenTree::Punct(it)) => it.as_char() == ':',
+        Some(TokenTree::Ident(it)) => it.to_string() == "as",
+        _ => false,
+      };
+      if !goes_on {
+        aliases.push(i + 7);
+      }
+    }
+  }
+  for at in aliases.into_iter().rev() {
+    tokens.splice(at..at, [ident("as"), ident("atomic")]);
+  }
+  tokens.into_iter().collect()
+}
+
+fn expand_test(
+  attr: TokenStream,
+  item: TokenStream,
+) -> Result<TokenStream, Error> {
+  let calls = config_calls(attr)?;
+
+  let mut tokens = item.into_iter().peekable();
+  let mut outer_attrs = Vec::new();
+  while let Some(TokenTree::Punct(p)) = tokens.peek() {
+    if p.as_char() != '#' {
+      break;
+    }
+    outer_attrs.push(tokens.next().unwrap());
+    match tokens.next() {
+      Some(group @ TokenTree::Group(_)) => {
+        outer_attrs.push(group)
+      }
+      _ => {
+        return Err((
+          Span::call_site(),
+          "malformed attribute".into(),
+        ))
+      }
+    }
+  }
+  let inner: Vec<TokenTree> = tokens.collect();
+  let name = fn_name(&inner)?;
+
+  // Spelled out in full, a bare `test` could resolve to this
+  // macro.
+  let test = path(&["core", "prelude", "v1", "test"]);
+  let test_attr = [punct('#'), group(Delimiter::Bracket, test)];
+
+  if !takes_arguments(&inner) {
+    let mut result = TokenStream::new();
+    result.extend(test_attr);
+    result.extend(outer_attrs);
+    result.extend(inner);
+    return Ok(result);
+  }
+
+  let mut body = TokenStream::new();
+  body.extend(inner);
+  body.extend(path(&[
+    "properly_concurrent",
+    "explore",
+    "Config",
+    "new",
+  ]));
+  body
+    .extend([group(Delimiter::Parenthesis, TokenStream::new())]);
+  body.extend(calls);
+  body.extend([
+    punct('.'),
+    ident("env"),
+    group(Delimiter::Parenthesis, TokenStream::new()),
+    punct('.'),
+    ident("run"),
+    group(
+      Delimiter::Parenthesis,
+      TokenTree::Ident(name.clone()).into(),
+    ),
+    punct(';'),
+  ]);
+
+  let mut result = TokenStream::new();
+  result.extend(test_attr);
+  result.extend(outer_attrs);
+  result.extend([ident("fn"), TokenTree::Ident(name)]);
+  result.extend([
+    group(Delimiter::Parenthesis, TokenStream::new()),
+    group(Delimiter::Brace, body),
+  ]);
+  Ok(result)
+}
+
+/// `a, b = 1` becomes `.a().b(1)`.
+fn config_calls(
+  attr: TokenStream,
+) -> Result<TokenStream, Error> {
+  let mut result = TokenStream::new();
+  let mut tokens = attr.into_iter().peekable();
+  while let Some(token) = tokens.next() {
+    let TokenTree::Ident(method) = token else {
+      return Err((
+        token.span(),
+        "expected a config option".into(),
+      ));
+    };
+    let mut args = TokenStream::new();
+    if let Some(TokenTree::Punct(p)) = tokens.peek() {
+      if p.as_char() == '=' {
+        tokens.next();
+        while let Some(token) = tokens.peek() {
+          let comma = matches!(
+            token,
+            TokenTree::Punct(p) if p.as_char() == ','
+          );
+          if comma {
+            break;
+          }
+          args.extend([tokens.next().unwrap()]);
+        }
+        if args.is_empty() {
+          return Err((
+            method.span(),
+            "expected a value".into(),
+          ));
+        }
+      }
+    }
+    match tokens.next() {
+      None => (),
+      Some(TokenTree::Punct(p)) if p.as_char() == ',' => (),
+      Some(token) => {
+        return Err((token.span(), "expected `,`".into()));
+      }
+    }
+    result.extend([
+      punct('.'),
+      TokenTree::Ident(method),
+      group(Delimiter::Parenthesis, args),
+    ]);
+  }
+  Ok(result)
+}
+
+fn fn_name(item: &[TokenTree]) -> Result<Ident, Error> {
+  let mut tokens = item.iter();
+  while let Some(token) = tokens.next() {
+    if let TokenTree::Ident(it) = token {
+      if it.to_string() == "fn" {
+        if let Some(TokenTree::Ident(name)) = tokens.next() {
+          return Ok(name.clone());
+        }
+        break;
+      }
+    }
+  }
+  Err((Span::call_site(), "expected a function".into()))
+}
+
+fn takes_arguments(item: &[TokenTree]) -> bool {
+  let fn_keyword = item.iter().position(|token| {
+    matches!(
+      token,
+      TokenTree::Ident(it) if it.to_string() == "fn"
+    )
+  });
+  let params =
+    item[fn_keyword.unwrap_or(0)..].iter().find_map(|token| {
+      match token {
+        TokenTree::Group(group)
+          if group.delimiter() == Delimiter::Parenthesis =>
+        {
+          Some(group.stream())
+        }
+        _ => None,
+      }
+    });
+  params.is_some_and(|it| !it.is_empty())
+}
+
+/// Implements `model_test::Op` for an enum of operations, along
+/// with `Debug`:
+///
+/// ```text
+/// #[derive(ConcurrentOps)]
+/// #[ops(real = Stack, model = Vec<u32>)]
+/// enum StackOp {
+///   #[op(weight = 2)]
+///   Push(u32),
+///   #[op(when = |model| !model.is_empty())]
+///   Pop,
+/// }
+/// ```
+///
+/// Each variant calls the method of the same name, in snake
+/// case, with the fields as arguments, on both the real value
+/// and the model. Fields are generated with
+/// `model_test::Generate`. The output of an op is the `Debug` of
+/// what the method returned, and an op's postcondition is that
+/// the real value and the model returned the same. `weight` and
+/// `when`, a closure from the model to `bool`, are as in
+/// `Generators::op_if`.
+#[proc_macro_derive(ConcurrentOps, attributes(ops, op))]
+pub fn derive_concurrent_ops(item: TokenStream) -> TokenStream {
+  match expand_concurrent_ops(item) {
+    Ok(it) => it,
+    Err((span, message)) => compile_error(span, &message),
+  }
+}
+
+struct Variant {
+  name: Ident,
+  fields: usize,
+  weight: String,
+  when: Option<String>,
+}
+
+fn expand_concurrent_ops(
+  item: TokenStream,
+) -> Result<TokenStream, Error> {
+  let mut real = None;
+  let mut model = None;
+  let mut tokens = item.into_iter().peekable();
+  let mut name = None;
+  let mut body = None;
+  while let Some(token) = tokens.next() {
+    match token {
+      TokenTree::Punct(p) if p.as_char() == '#' => {
+        let Some(TokenTree::Group(attr)) = tokens.next() else {
+          return Err((p.span(), "malformed attribute".into()));
+        };
+        if let Some(args) = attribute_args(&attr, "ops") {
+          for (key, value) in key_values(args)? {
+            match key.to_string().as_str() {
+              "real" => real = Some(value),
+              "model" => model = Some(value),
+              _ => {
+                return Err((
+                  key.span(),
+                  "expected `real` or `model`".into(),
+                ))
+              }
+            }
+          }
+        }
+      }
+      TokenTree::Ident(it) if it.to_string() == "enum" => {
+        let Some(TokenTree::Ident(it)) = tokens.next() else {
+          return Err((it.span(), "expected a name".into()));
+        };
+        name = Some(it);
+        match tokens.next() {
+          Some(TokenTree::Group(group))
+            if group.delimiter() == Delimiter::Brace =>
+          {
+            body = Some(group.stream())
+          }
+          _ => {
+            return Err((
+              Span::call_site(),
+              "generic enums are not supported".into(),
+            ))
+          }
+        }
+      }
+      _ => (),
+    }
+  }
+  let (Some(name), Some(body)) = (name, body) else {
+    return Err((Span::call_site(), "expected an enum".into()));
+  };
+  let (Some(real), Some(model)) = (real, model) else {
+    return Err((
+      name.span(),
+      "expected `#[ops(real = .., model = ..)]`".into(),
+    ));
+  };
+  let variants = variants(body)?;
+
+  // Assembled as text: the generated code refers to the user's
+  // types and functions only by name, so spans matter little.
+  let krate = "::properly_concurrent::model_test";
+  let mut generators =
+    format!("{krate}::Generators::<{model}, Self>::new()");
+  let mut debug = String::new();
+  let mut apply = String::new();
+  let mut apply_model = String::new();
+  for Variant { name: variant, fields, weight, when } in
+    &variants
+  {
+    let args: Vec<String> =
+      (0..*fields).map(|i| format!("a{i}")).collect();
+    let pattern = if *fields == 0 {
+      format!("{name}::{variant}")
+    } else {
+      format!("{name}::{variant}({})", args.join(", "))
+    };
+    let generated =
+      vec![format!("{krate}::Generate::generate(g)"); *fields];
+    let make = if *fields == 0 {
+      format!("|_| {name}::{variant}")
+    } else {
+      format!("|g| {name}::{variant}({})", generated.join(", "))
+    };
+    let when = when.as_deref().unwrap_or("|_| true");
+    generators
+      .push_str(&format!(".op_if({weight}, {when}, {make})"));
+
+    let mut fmt = format!("f.debug_tuple(\"{variant}\")");
+    for arg in &args {
+      fmt.push_str(&format!(".field({arg})"));
+    }
+    debug.push_str(&format!("{pattern} => {fmt}.finish(),"));
+
+    let cloned: Vec<String> = args
+      .iter()
+      .map(|arg| format!("::core::clone::Clone::clone({arg})"))
+      .collect();
+    let method = snake_case(&variant.to_string());
+    let call = |target: &str| {
+      format!(
+        "{pattern} => \
+         ::std::format!(\"{{:?}}\", {target}.{method}({})),",
+        cloned.join(", ")
+      )
+    };
+    apply.push_str(&call("real"));
+    apply_model.push_str(&call("model"));
+  }
+
+  let code = format!(
+    "
+    impl ::core::fmt::Debug for {name} {{
+      fn fmt(
+        &self,
+        f: &mut ::core::fmt::Formatter<'_>,
+      ) -> ::core::fmt::Result {{
+        match self {{ {debug} }}
+      }}
+    }}
+
+    impl {krate}::Op<{real}, {model}> for {name} {{
+      type Output = ::std::string::String;
+
+      fn generators() -> {krate}::Generators<{model}, Self> {{
+        {generators}
+      }}
+
+      fn apply(&self, real: &{real}) -> ::std::string::String {{
+        match self {{ {apply} }}
+      }}
+
+      fn apply_model(
+        &self,
+        model: &mut {model},
+      ) -> ::std::string::String {{
+        match self {{ {apply_model} }}
+      }}
+
+      fn postcondition(
+        &self,
+        real: &::std::string::String,
+        model: &::std::string::String,
+      ) -> bool {{
+        real == model
+      }}
+    }}
+    "
+  );
+  code
+    .parse()
+    .map_err(|_| (name.span(), "failed to expand".to_string()))
+}
+
+/// The arguments of `#[name(..)]`.
+fn attribute_args(
+  attr: &Group,
+  name: &str,
+) -> Option<TokenStream> {
+  let mut tokens = attr.stream().into_iter();
+  match (tokens.next(), tokens.next()) {
+    (
+      Some(TokenTree::Ident(it)),
+      Some(TokenTree::Group(args)),
+    ) if it.to_string() == name => Some(args.stream()),
+    _ => None,
+  }
+}
+
+/// `a = X, b = Y<Z, W>` as pairs, with the values as text. A
+/// value may be a type or an expression, so `<..>` are no
+/// delimiters: instead, a part which doesn't start with `key =`
+/// continues the value before it.
+fn key_values(
+  args: TokenStream,
+) -> Result<Vec<(Ident, String)>, Error> {
+  let mut parts: Vec<TokenStream> = Vec::new();
+  for part in split_commas(args, false) {
+    let tokens: Vec<TokenTree> =
+      part.clone().into_iter().collect();
+    let starts_key = matches!(
+      tokens.as_slice(),
+      [TokenTree::Ident(_), TokenTree::Punct(eq), ..]
+        if eq.as_char() == '=' && eq.spacing() == Spacing::Alone
+    );
+    match parts.last_mut() {
+      Some(last) if !starts_key => {
+        last.extend([punct(',')]);
+        last.extend(part);
+      }
+      _ => parts.push(part),
+    }
+  }
+  let mut result = Vec::new();
+  for part in parts {
+    let mut tokens = part.into_iter();
+    let (
+      Some(TokenTree::Ident(key)),
+      Some(TokenTree::Punct(eq)),
+    ) = (tokens.next(), tokens.next())
+    else {
+      return Err((
+        Span::call_site(),
+        "expected `key = value`".into(),
+      ));
+    };
+    let value: TokenStream = tokens.collect();
+    if eq.as_char() != '=' || value.is_empty() {
+      return Err((key.span(), "expected `key = value`".into()));
+    }
+    result.push((key, value.to_string()));
+  }
+  Ok(result)
+}
+
+fn variants(body: TokenStream) -> Result<Vec<Variant>, Error> {
+  let mut result = Vec::new();
+  for part in split_commas(body, false) {
+    let mut tokens = part.into_iter();
+    let mut weight = "1".to_string();
+    let mut when = None;
+    let name = loop {
+      match tokens.next() {
+        Some(TokenTree::Punct(p)) if p.as_char() == '#' => {
+          let Some(TokenTree::Group(attr)) = tokens.next()
+          else {
+            return Err((
+              p.span(),
+              "malformed attribute".into(),
+            ));
+          };
+          let Some(args) = attribute_args(&attr, "op") else {
+            continue;
+          };
+          for (key, value) in key_values(args)? {
+            match key.to_string().as_str() {
+              "weight" => weight = value,
+              "when" => when = Some(value),
+              _ => {
+                return Err((
+                  key.span(),
+                  "expected `weight` or `when`".into(),
+                ))
+              }
+            }
+          }
+        }
+        Some(TokenTree::Ident(name)) => break name,
+        _ => {
+          return Err((
+            Span::call_site(),
+            "expected a variant".into(),
+          ))
+        }
+      }
+    };
+    let fields = match tokens.next() {
+      None => 0,
+      Some(TokenTree::Group(group))
+        if group.delimiter() == Delimiter::Parenthesis =>
+      {
+        split_commas(group.stream(), true).len()
+      }
+      Some(token) => {
+        return Err((
+          token.span(),
+          "only unit and tuple variants are supported".into(),
+        ))
+      }
+    };
+    result.push(Variant { name, fields, weight, when });
+  }
+  Ok(result)
+}
+
+/// Splits on the commas outside of groups, dropping empty parts.
+/// With `generics`, `<..>` counts as a group too, which is only
+/// right for types: in `when = |m| m.len() < 3` it's a
+/// comparison.
+fn split_commas(
+  stream: TokenStream,
+  generics: bool,
+) -> Vec<TokenStream> {
+  let mut parts = vec![TokenStream::new()];
+  let mut depth = 0;
+  for token in stream {
+    if let TokenTree::Punct(p) = &token {
+      match p.as_char() {
+        '<' if generics => depth += 1,
+        '>' if generics => depth -= 1,
+        ',' if depth == 0 => {
+          parts.push(TokenStream::new());
+          continue;
+        }
+        _ => (),
+      }
+    }
+    parts.last_mut().unwrap().extend([token]);
+  }
+  parts.retain(|it| !it.is_empty());
+  parts
+}
+
+/// `PushBack` becomes `push_back`.
+fn snake_case(name: &str) -> String {
+  let mut result = String::new();
+  for (i, ch) in name.chars().enumerate() {
+    if ch.is_uppercase() {
+      if i > 0 {
+        result.push('_');
+      }
+      result.extend(ch.to_lowercase());
+    } else {
+      result.push(ch);
+    }
+  }
+  result
+}
+
+fn path(segments: &[&str]) -> TokenStream {
+  let mut result = TokenStream::new();
+  for segment in segments {
+    result.extend([
+      TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+      punct(':'),
+      ident(segment),
+    ]);
+  }
+  result
+}
+
+fn ident(name: &str) -> TokenTree {
+  TokenTree::Ident(Ident::new(name, Span::call_site()))
+}
+
+fn punct(ch: char) -> TokenTree {
+  TokenTree::Punct(Punct::new(ch, Spacing::Alone))
+}
+
+fn group(
+  delimiter: Delimiter,
+  stream: TokenStream,
+) -> TokenTree {
+  TokenTree::Group(Group::new(delimiter, stream))
+}
+
+fn compile_error(span: Span, message: &str) -> TokenStream {
+  let literal = proc_macro::Literal::string(message);
+  let mut result: TokenStream = path(&["core", "compile_error"]);
+  result.extend([
+    TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+    group(
+      Delimiter::Parenthesis,
+      TokenTree::Literal(literal).into(),
+    ),
+    punct(';'),
+  ]);
+  result
+    .into_iter()
+    .map(|mut token| {
+      token.set_span(span);
+      token
+    })
+    .collect()
+}
@@ -0,0 +1,108 @@
+//! Allocation failure injection.
+//!
+//! Install [`FailingAlloc`] as the `#[global_allocator]` of a
+//! test binary and wrap the code which handles allocation
+//! failure, like a `try_reserve`, in [`fallible`]. Under
+//! [`crate::explore::Config::alloc_failures`], one allocation
+//! per thread and iteration inside `fallible` then fails, at a
+//! point the explorer picks.
+//!
+//! Only allocations inside `fallible` may fail: anywhere else,
+//! like in `Box::new`, a failure aborts the process, and an
+//! allocator must not panic instead.
+
+use std::{
+  alloc::{GlobalAlloc, Layout, System},
+  cell::Cell,
+};
+
+use crate::loom_compat;
+
+/// Wraps a global allocator to inject failures, see the module
+/// docs.
+pub struct FailingAlloc<A = System>(pub A);
+
+thread_local! {
+  static FALLIBLE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Lets allocations made by `f` on this thread fail.
+pub fn fallible<R>(f: impl FnOnce() -> R) -> R {
+  struct Reset(bool);
+
+  impl Drop for Reset {
+    fn drop(&mut self) {
+      FALLIBLE.with(|it| it.set(self.0))
+    }
+  }
+
+  let _reset = Reset(FALLIBLE.with(|it| it.replace(true)));
+  f()
+}
+
+fn fail() -> bool {
+  // Off while asking the explorer, which may allocate itself.
+  let fallible = FALLIBLE.try_with(|it| it.replace(false));
+  if fallible != Ok(true) {
+    return false;
+  }
+  let fail = loom_compat::alloc_failure();
+  FALLIBLE.with(|it| it.set(true));
+  fail
+}
+
+// SAFETY: forwards to `A`, or reports failure with a null
+// pointer.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for FailingAlloc<A> {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    if fail() {
+      return std::ptr::null_mut();
+    }
+    unsafe { self.0.alloc(layout) }
+  }
+
+  unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+    if fail() {
+      return std::ptr::null_mut();
+    }
+    unsafe { self.0.alloc_zeroed(layout) }
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe { self.0.dealloc(ptr, layout) }
+  }
+
+  unsafe fn realloc(
+    &self,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+  ) -> *mut u8 {
+    if fail() {
+      return std::ptr::null_mut();
+    }
+    unsafe { self.0.realloc(ptr, layout, new_size) }
+  }
+}
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOC: FailingAlloc = FailingAlloc(System);
+
+#[test]
+fn allocations_fail_where_fallible() {
+  use std::{collections::HashSet, sync::Mutex};
+
+  static FAILED: Mutex<Option<HashSet<bool>>> = Mutex::new(None);
+
+  let config = crate::explore::Config::new().exhaustive();
+  loom_compat::run(config.alloc_failures(), || {
+    let mut v: Vec<u64> = Vec::new();
+    let reserved = fallible(|| v.try_reserve(16));
+    v.push(1);
+    let mut failed = FAILED.lock().unwrap();
+    failed.get_or_insert_default().insert(reserved.is_err());
+  });
+  let failed = FAILED.lock().unwrap().take().unwrap();
+  assert_eq!(failed.len(), 2);
+}
@@ -0,0 +1,501 @@
+//! The instrumented atomic types, which only need `core`.
+//!
+//! Every operation calls a pause hook before and after touching
+//! the atomic. With the `std` feature, the default hook hands
+//! control to the managed thread scheduler, see
+//! [`managed_thread`]. Without it, for example when building
+//! firmware for a `no_std` target, it does nothing unless a hook
+//! is installed with [`set_pause_hook`], so the same types can
+//! be used in the shipped code and in host-side tests.
+//!
+//! Without the `pause` feature, which is on by default, there
+//! are no hooks: each type is a `#[repr(transparent)]` wrapper
+//! whose methods only call the `core` operation, and which has
+//! no drop glue, as a `const` assertion checks. A library can
+//! then turn default features off for the shipped code and
+//! enable `pause` only as a dev-dependency.
+//!
+#![cfg_attr(
+  feature = "std",
+  doc = "[`managed_thread`]: crate::managed_thread"
+)]
+#![cfg_attr(
+  not(feature = "std"),
+  doc = "[`managed_thread`]: self"
+)]
+
+use core::{
+  cell::UnsafeCell, fmt, panic::Location, sync::atomic::Ordering,
+};
+
+/// Called around every instrumented atomic operation.
+pub trait PauseHook: Sync {
+  fn pause(&self);
+}
+
+/// Installs the process-wide pause hook, replacing the default.
+/// Can be done only once, returns `Err` on subsequent calls.
+pub fn set_pause_hook(
+  hook: &'static dyn PauseHook,
+) -> Result<(), &'static dyn PauseHook> {
+  HOOK.set(hook)
+}
+
+static HOOK: HookCell = HookCell {
+  state: core::sync::atomic::AtomicU8::new(UNSET),
+  hook: UnsafeCell::new(None),
+};
+
+const UNSET: u8 = 0;
+const SETTING: u8 = 1;
+const SET: u8 = 2;
+
+/// `OnceLock` for `core`.
+struct HookCell {
+  state: core::sync::atomic::AtomicU8,
+  hook: UnsafeCell<Option<&'static dyn PauseHook>>,
+}
+
+// SAFETY: `hook` is written once, before `state` is released as
+// `SET`, and only read after `state` is acquired as `SET`.
+unsafe impl Sync for HookCell {}
+
+impl HookCell {
+  fn set(
+    &self,
+    hook: &'static dyn PauseHook,
+  ) -> Result<(), &'static dyn PauseHook> {
+    if self
+      .state
+      .compare_exchange(
+        UNSET,
+        SETTING,
+        Ordering::Acquire,
+        Ordering::Relaxed,
+      )
+      .is_err()
+    {
+      return Err(hook);
+    }
+    // SAFETY: we won the race to `SETTING`, nobody else touches
+    // `hook`.
+    unsafe { *self.hook.get() = Some(hook) };
+    self.state.store(SET, Ordering::Release);
+    Ok(())
+  }
+
+  fn get(&self) -> Option<&'static dyn PauseHook> {
+    if self.state.load(Ordering::Acquire) != SET {
+      return None;
+    }
+    // SAFETY: `SET` is final, see `set`.
+    unsafe { *self.hook.get() }
+  }
+}
+
+/// Whether operations call the hook and go through the memory
+/// model, see the module docs.
+const INSTRUMENTED: bool = cfg!(any(test, feature = "pause"));
+
+#[inline]
+fn pause() {
+  match HOOK.get() {
+    Some(hook) => hook.pause(),
+    #[cfg(feature = "std")]
+    None => crate::managed_thread::pause(),
+    #[cfg(not(feature = "std"))]
+    None => (),
+  }
+}
+
+/// The pause before an operation at `site`.
+#[inline]
+fn pause_at(site: &'static Location<'static>) {
+  if !INSTRUMENTED {
+    return;
+  }
+  model::reached(site);
+  model::check_managed(site);
+  pause()
+}
+
+/// Weak memory, see [`crate::memory`]. Values travel as `u64`.
+#[cfg(feature = "std")]
+mod model {
+  pub(crate) use crate::loom_compat::{
+    reached, spurious_cas_failure,
+  };
+  pub(crate) use crate::managed_thread::check_managed;
+  #[cfg(any(test, feature = "pause"))]
+  pub(crate) use crate::memory::forget;
+  pub(crate) use crate::memory::{fence, load, rmw, store};
+}
+
+#[cfg(not(feature = "std"))]
+mod model {
+  use core::sync::atomic::Ordering;
+
+  type Site = &'static core::panic::Location<'static>;
+
+  pub(crate) fn load(
+    _: usize,
+    latest: u64,
+    _: Ordering,
+    _: Site,
+  ) -> u64 {
+    latest
+  }
+
+  pub(crate) fn store(
+    _: usize,
+    _: u64,
+    _: u64,
+    _: Ordering,
+    _: Site,
+  ) {
+  }
+
+  pub(crate) fn rmw(
+    _: usize,
+    _: u64,
+    _: u64,
+    _: Ordering,
+    _: Site,
+  ) {
+  }
+
+  pub(crate) fn fence(_: Ordering, _: Site) {}
+
+  #[cfg(any(test, feature = "pause"))]
+  pub(crate) fn forget(_: usize) {}
+
+  pub(crate) fn reached(_: Site) {}
+
+  pub(crate) fn check_managed(_: Site) {}
+
+  pub(crate) fn spurious_cas_failure() -> bool {
+    false
+  }
+}
+
+/// A scheduling point at a boundary of the algorithm rather than
+/// an atomic, like between two steps of a protocol which go
+/// through plain memory or a third-party call. Calls the pause
+/// hook like an operation does, so outside of a managed thread
+/// it does nothing.
+///
+/// For a point a [`crate::script`] can stop at, see
+#[cfg_attr(
+  feature = "std",
+  doc = "[`crate::managed_thread::label`]."
+)]
+#[cfg_attr(
+  not(feature = "std"),
+  doc = "`managed_thread::label`."
+)]
+#[track_caller]
+pub fn checkpoint() {
+  pause_at(Location::caller())
+}
+
+/// An instrumented [`core::sync::atomic::fence`].
+#[track_caller]
+pub fn fence(ordering: Ordering) {
+  if !INSTRUMENTED {
+    return core::sync::atomic::fence(ordering);
+  }
+  let site = Location::caller();
+  pause_at(site);
+  core::sync::atomic::fence(ordering);
+  model::fence(ordering, site);
+}
+
+macro_rules! atomic_int {
+  ($($atomic:ident($int:ty, $width:literal),)*) => {$(
+    #[cfg(target_has_atomic = $width)]
+    #[derive(Default)]
+    #[repr(transparent)]
+    pub struct $atomic {
+      inner: core::sync::atomic::$atomic,
+    }
+
+    #[cfg(target_has_atomic = $width)]
+    impl $atomic {
+      /// `const`, for `static`s. Those keep their value from one
+      /// exploration iteration to the next, unless wrapped in
+      /// the instrumented `sync::LazyLock`.
+      #[inline]
+      pub const fn new(value: $int) -> $atomic {
+        let inner = core::sync::atomic::$atomic::new(value);
+        $atomic { inner }
+      }
+
+      #[inline]
+      #[track_caller]
+      pub fn load(&self, ordering: Ordering) -> $int {
+        if !INSTRUMENTED {
+          return self.inner.load(ordering);
+        }
+        let site = Location::caller();
+        pause_at(site);
+        let latest = self.inner.load(ordering);
+        let latest = latest as u64;
+        let addr = self.addr();
+        let result =
+          model::load(addr, latest, ordering, site) as $int;
+        pause();
+        result
+      }
+
+      #[inline]
+      #[track_caller]
+      pub fn store(&self, value: $int, ordering: Ordering) {
+        if !INSTRUMENTED {
+          return self.inner.store(value, ordering);
+        }
+        let site = Location::caller();
+        pause_at(site);
+        let old = self.inner.load(Ordering::Relaxed);
+        self.inner.store(value, ordering);
+        let (old, new) = (old as u64, value as u64);
+        model::store(self.addr(), old, new, ordering, site);
+        pause();
+      }
+
+      /// No scheduling point: the borrow rules out other
+      /// threads.
+      #[inline]
+      pub fn get_mut(&mut self) -> &mut $int {
+        self.inner.get_mut()
+      }
+
+      #[inline]
+      pub fn into_inner(mut self) -> $int {
+        *self.inner.get_mut()
+      }
+
+      #[inline]
+      #[track_caller]
+      pub fn swap(
+        &self,
+        value: $int,
+        ordering: Ordering,
+      ) -> $int {
+        if !INSTRUMENTED {
+          return self.inner.swap(value, ordering);
+        }
+        let site = Location::caller();
+        pause_at(site);
+        let result = self.inner.swap(value, ordering);
+        let (old, new) = (result as u64, value as u64);
+        model::rmw(self.addr(), old, new, ordering, site);
+        pause();
+        result
+      }
+
+      #[inline]
+      #[track_caller]
+      pub fn fetch_add(
+        &self,
+        value: $int,
+        ordering: Ordering,
+      ) -> $int {
+        if !INSTRUMENTED {
+          return self.inner.fetch_add(value, ordering);
+        }
+        let site = Location::caller();
+        pause_at(site);
+        let result = self.inner.fetch_add(value, ordering);
+        let (old, new) =
+          (result as u64, result.wrapping_add(value) as u64);
+        model::rmw(self.addr(), old, new, ordering, site);
+        pause();
+        result
+      }
+
+      #[inline]
+      #[track_caller]
+      pub fn fetch_sub(
+        &self,
+        value: $int,
+        ordering: Ordering,
+      ) -> $int {
+        if !INSTRUMENTED {
+          return self.inner.fetch_sub(value, ordering);
+        }
+        let site = Location::caller();
+        pause_at(site);
+        let result = self.inner.fetch_sub(value, ordering);
+        let (old, new) =
+          (result as u64, result.wrapping_sub(value) as u64);
+        model::rmw(self.addr(), old, new, ordering, site);
+        pause();
+        result
+      }
+
+      #[inline]
+      #[track_caller]
+      pub fn compare_exchange(
+        &self,
+        current: $int,
+        new: $int,
+        success: Ordering,
+        failure: Ordering,
+      ) -> Result<$int, $int> {
+        if !INSTRUMENTED {
+          return self.inner.compare_exchange(
+            current, new, success, failure,
+          );
+        }
+        let site = Location::caller();
+        pause_at(site);
+        let result = self.inner.compare_exchange(
+          current, new, success, failure,
+        );
+        let result =
+          self.model_cas(result, new, success, failure, site);
+        pause();
+        result
+      }
+
+      /// May fail spuriously under exploration, see
+      /// `Config::spurious_cas_failures`.
+      #[inline]
+      #[track_caller]
+      pub fn compare_exchange_weak(
+        &self,
+        current: $int,
+        new: $int,
+        success: Ordering,
+        failure: Ordering,
+      ) -> Result<$int, $int> {
+        if !INSTRUMENTED {
+          return self.inner.compare_exchange_weak(
+            current, new, success, failure,
+          );
+        }
+        let site = Location::caller();
+        pause_at(site);
+        let result = if model::spurious_cas_failure() {
+          Err(self.inner.load(failure))
+        } else {
+          self.inner.compare_exchange_weak(
+            current, new, success, failure,
+          )
+        };
+        let result =
+          self.model_cas(result, new, success, failure, site);
+        pause();
+        result
+      }
+
+      /// A successful exchange is a read-modify-write, a failed
+      /// one just a load.
+      #[inline]
+      fn model_cas(
+        &self,
+        result: Result<$int, $int>,
+        new: $int,
+        success: Ordering,
+        failure: Ordering,
+        site: &'static Location<'static>,
+      ) -> Result<$int, $int> {
+        match result {
+          Ok(old) => {
+            model::rmw(
+              self.addr(),
+              old as u64,
+              new as u64,
+              success,
+              site,
+            );
+            Ok(old)
+          }
+          Err(latest) => Err(model::load(
+            self.addr(),
+            latest as u64,
+            failure,
+            site,
+          ) as $int),
+        }
+      }
+
+      /// The latest value, without a scheduling point, for
+      /// deciding whether a blocked thread may go on.
+      #[cfg(feature = "std")]
+      #[allow(dead_code)]
+      pub(crate) fn latest(&self) -> $int {
+        self.inner.load(Ordering::SeqCst)
+      }
+
+      #[inline]
+      fn addr(&self) -> usize {
+        self as *const $atomic as usize
+      }
+    }
+
+    #[cfg(all(
+      target_has_atomic = $width,
+      any(test, feature = "pause"),
+    ))]
+    impl Drop for $atomic {
+      #[inline]
+      fn drop(&mut self) {
+        model::forget(self.addr())
+      }
+    }
+
+    #[cfg(all(
+      target_has_atomic = $width,
+      not(any(test, feature = "pause")),
+    ))]
+    const _: () = assert!(!core::mem::needs_drop::<$atomic>());
+
+    #[cfg(target_has_atomic = $width)]
+    impl From<$int> for $atomic {
+      #[inline]
+      fn from(value: $int) -> $atomic {
+        $atomic::new(value)
+      }
+    }
+
+    /// The latest value, read without a scheduling point so that
+    /// printing doesn't change the schedule.
+    #[cfg(target_has_atomic = $width)]
+    impl fmt::Debug for $atomic {
+      fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner.load(Ordering::Relaxed), f)
+      }
+    }
+  )*};
+}
+
+atomic_int! {
+  AtomicU8(u8, "8"),
+  AtomicU16(u16, "16"),
+  AtomicU32(u32, "32"),
+  AtomicU64(u64, "64"),
+  AtomicUsize(usize, "ptr"),
+  AtomicI8(i8, "8"),
+  AtomicI16(i16, "16"),
+  AtomicI32(i32, "32"),
+  AtomicI64(i64, "64"),
+  AtomicIsize(isize, "ptr"),
+}
+
+#[test]
+fn std_api() {
+  let mut value = AtomicU32::from(1);
+  assert_eq!(value.swap(2, Ordering::SeqCst), 1);
+  *value.get_mut() += 1;
+  assert_eq!(format!("{value:?}"), "3");
+  assert_eq!(value.into_inner(), 3);
+}
+
+#[test]
+fn statics() {
+  static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+  let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+  assert_eq!(id, 1);
+  assert_eq!(NEXT_ID.load(Ordering::Relaxed), 2);
+}
@@ -0,0 +1,47 @@
+use std::process::ExitCode;
+
+use properly_concurrent::{
+  cli, explore::Choices, managed_thread, Counter,
+};
+
+fn main() -> ExitCode {
+  cli::main(&[("counter", counter)])
+}
+
+/// The racy counter from the blog post, two threads, up to five
+/// increments.
+fn counter(g: &mut Choices) {
+  let counter = Counter::default();
+  let increment_count = g.gen(5) as u32;
+  let mut counter_model: u32 = 0;
+
+  std::thread::scope(|scope| {
+    let threads = [
+      managed_thread::spawn(scope, &counter),
+      managed_thread::spawn(scope, &counter),
+    ];
+    loop {
+      let actionable: Vec<usize> = (0..threads.len())
+        .filter(|&t| {
+          threads[t].is_paused()
+            || counter_model < increment_count
+        })
+        .collect();
+      if actionable.is_empty() {
+        break;
+      }
+      let t = &threads[actionable[g.pick_thread(&actionable)]];
+      if t.is_paused() {
+        t.unpause();
+      } else {
+        t.submit(|c| c.increment());
+        counter_model += 1;
+      }
+    }
+    for t in threads {
+      t.join()
+    }
+  });
+
+  assert_eq!(counter_model, counter.get());
+}
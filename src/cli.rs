@@ -0,0 +1,129 @@
+//! Command-line front end for long-running explorations, behind
+//! the `cli` feature.
+//!
+//! ```text
+//! properly-concurrent <name> [--iterations N] [--seed 0x..]
+//!     [--scheduler random|exhaustive|pct:DEPTH|hybrid:MAX]
+//!     [--replay-file PATH]
+//! properly-concurrent --list
+//! ```
+//!
+//! The `properly-concurrent` binary serves the explorations
+//! shipped with this crate; a project with its own builds a
+//! binary which calls [`main`] with them.
+
+use std::process::ExitCode;
+
+use crate::explore::{Choices, Config};
+
+pub type Exploration = (&'static str, fn(&mut Choices));
+
+pub fn main(explorations: &[Exploration]) -> ExitCode {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let args = match parse(&args) {
+    Ok(it) => it,
+    Err(err) => {
+      eprintln!("error: {err}");
+      return ExitCode::FAILURE;
+    }
+  };
+  if args.list {
+    for (name, _) in explorations {
+      println!("{name}");
+    }
+    return ExitCode::SUCCESS;
+  }
+  let Some(name) = args.name else {
+    eprintln!("error: expected an exploration name, see --list");
+    return ExitCode::FAILURE;
+  };
+  let Some((_, body)) =
+    explorations.iter().find(|(it, _)| *it == name)
+  else {
+    eprintln!("error: no exploration named `{name}`");
+    return ExitCode::FAILURE;
+  };
+
+  let stats = args.config.run(body);
+  println!("{name}: {stats}");
+  ExitCode::SUCCESS
+}
+
+#[derive(Default)]
+struct Args {
+  name: Option<String>,
+  list: bool,
+  config: Config,
+}
+
+fn parse(args: &[String]) -> Result<Args, String> {
+  let mut result = Args::default();
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    let mut value = || {
+      args.next().ok_or_else(|| format!("{arg} expects a value"))
+    };
+    let config = std::mem::take(&mut result.config);
+    result.config = match arg.as_str() {
+      "--list" => {
+        result.list = true;
+        config
+      }
+      "--iterations" => {
+        let value = value()?;
+        let iterations = u32::try_from(parse_int(value)?)
+          .map_err(|err| {
+            format!("invalid number `{value}`: {err}")
+          })?;
+        config.iterations(iterations)
+      }
+      "--seed" => config.seed(parse_int(value()?)?),
+      "--scheduler" => config.scheduler(value()?)?,
+      "--replay-file" => {
+        let path = value()?;
+        let schedule = std::fs::read_to_string(path)
+          .map_err(|err| format!("can't read {path}: {err}"))?;
+        config.replay(&schedule)
+      }
+      flag if flag.starts_with("--") => {
+        return Err(format!("unknown flag `{flag}`"))
+      }
+      name => {
+        result.name = Some(name.to_string());
+        config
+      }
+    };
+  }
+  Ok(result)
+}
+
+fn parse_int(text: &str) -> Result<u64, String> {
+  let parsed = match text.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+    None => text.replace('_', "").parse(),
+  };
+  parsed.map_err(|err| format!("invalid number `{text}`: {err}"))
+}
+
+#[test]
+fn parses_flags() {
+  let args = |args: &[&str]| {
+    parse(
+      &args.iter().map(|it| it.to_string()).collect::<Vec<_>>(),
+    )
+  };
+  let parsed = args(&[
+    "counter",
+    "--seed",
+    "0x9c2a_13a6",
+    "--scheduler",
+    "pct:3",
+  ])
+  .unwrap();
+  assert_eq!(parsed.name.as_deref(), Some("counter"));
+  assert!(args(&["--list"]).unwrap().list);
+  assert!(args(&["--seed"]).is_err());
+  assert!(args(&["--iterations", "0x1_0000_0000"]).is_err());
+  assert!(args(&["--scheduler", "fifo"]).is_err());
+  assert!(args(&["--verbose"]).is_err());
+}
@@ -0,0 +1,166 @@
+//! Epoch-based reclamation, in the style of crossbeam-epoch, on
+//! top of the instrumented atomics.
+//!
+//! Pinning, unpinning, deferring and collecting are all
+//! scheduling points, so the explorer also tries the
+//! interleavings where memory is reclaimed under a reader,
+//! rather than only those of the CAS loops around it.
+//!
+//! Each participant announces the global epoch it is pinned in.
+//! The global epoch only advances once every pinned participant
+//! has seen it, so garbage deferred in epoch `e` is unreachable
+//! by the time the epoch is `e + 2`.
+
+use std::sync::{atomic::Ordering::SeqCst, Arc, Mutex};
+
+use crate::managed_thread::AtomicUsize;
+
+type Deferred = Box<dyn FnOnce() + Send>;
+
+#[derive(Clone, Default)]
+pub struct Collector {
+  global: Arc<Global>,
+}
+
+#[derive(Default)]
+struct Global {
+  epoch: AtomicUsize,
+  participants: Mutex<Vec<Arc<Participant>>>,
+  /// Tagged with the epoch they were deferred in.
+  garbage: Mutex<Vec<(usize, Deferred)>>,
+}
+
+/// `epoch << 1 | pinned`.
+#[derive(Default)]
+struct Participant {
+  state: AtomicUsize,
+}
+
+impl Collector {
+  pub fn new() -> Collector {
+    Collector::default()
+  }
+
+  /// Adds a participant, usually one per thread.
+  pub fn register(&self) -> LocalHandle {
+    let local = Arc::new(Participant::default());
+    let mut participants =
+      self.global.participants.lock().unwrap();
+    participants.push(Arc::clone(&local));
+    LocalHandle { global: Arc::clone(&self.global), local }
+  }
+}
+
+pub struct LocalHandle {
+  global: Arc<Global>,
+  local: Arc<Participant>,
+}
+
+impl LocalHandle {
+  /// Pins the participant until the guard is dropped. Doesn't
+  /// nest.
+  pub fn pin(&self) -> Guard<'_> {
+    loop {
+      let epoch = self.global.epoch.load(SeqCst);
+      self.local.state.store(epoch << 1 | 1, SeqCst);
+      // The epoch may have moved on before it was announced.
+      if self.global.epoch.load(SeqCst) == epoch {
+        return Guard { handle: self, epoch };
+      }
+    }
+  }
+
+  /// Advances the global epoch if every pinned participant is in
+  /// it, and runs the garbage which is two epochs old.
+  pub fn collect(&self) {
+    let epoch = self.global.epoch.load(SeqCst);
+    let participants =
+      self.global.participants.lock().unwrap().clone();
+    for participant in participants {
+      let state = participant.state.load(SeqCst);
+      if state & 1 == 1 && state >> 1 != epoch {
+        return;
+      }
+    }
+    let _ = self.global.epoch.compare_exchange(
+      epoch,
+      epoch + 1,
+      SeqCst,
+      SeqCst,
+    );
+    let epoch = self.global.epoch.load(SeqCst);
+    let ready: Vec<Deferred> = {
+      let mut garbage = self.global.garbage.lock().unwrap();
+      let (ready, later) = std::mem::take(&mut *garbage)
+        .into_iter()
+        .partition(|&(deferred, _)| deferred + 2 <= epoch);
+      *garbage = later;
+      ready.into_iter().map(|(_, f)| f).collect()
+    };
+    for f in ready {
+      f()
+    }
+  }
+}
+
+pub struct Guard<'a> {
+  handle: &'a LocalHandle,
+  epoch: usize,
+}
+
+impl Guard<'_> {
+  /// Runs `f` once no participant pinned now can still be
+  /// pinned.
+  pub fn defer(&self, f: impl FnOnce() + Send + 'static) {
+    crate::managed_thread::pause();
+    let garbage = &self.handle.global.garbage;
+    garbage.lock().unwrap().push((self.epoch, Box::new(f)));
+  }
+}
+
+impl Drop for Guard<'_> {
+  fn drop(&mut self) {
+    self.handle.local.state.store(0, SeqCst)
+  }
+}
+
+#[test]
+fn pinned_readers_outlive_garbage() {
+  use std::sync::atomic::AtomicBool;
+
+  use crate::{
+    explore,
+    loom_compat::{run, thread},
+  };
+
+  // Too many pause points to go through exhaustively, and a bug
+  // needs the reader to wait out most of the writer.
+  run(explore::Config::new().iterations(1_000).pct(2), || {
+    let collector = Collector::new();
+    let published = Arc::new(AtomicUsize::new(1));
+    let freed = Arc::new(AtomicBool::new(false));
+    let reader = thread::spawn({
+      let handle = collector.register();
+      let published = Arc::clone(&published);
+      let freed = Arc::clone(&freed);
+      move || {
+        let _guard = handle.pin();
+        if published.load(SeqCst) == 1 {
+          assert!(!freed.load(SeqCst), "freed under a reader");
+        }
+      }
+    });
+    let handle = collector.register();
+    let guard = handle.pin();
+    published.store(0, SeqCst);
+    guard.defer({
+      let freed = Arc::clone(&freed);
+      move || freed.store(true, SeqCst)
+    });
+    drop(guard);
+    for _ in 0..2 {
+      handle.collect();
+    }
+    reader.join().unwrap();
+  })
+}
@@ -0,0 +1,2740 @@
+//! The exploration loop: runs a test body over and over, feeding
+//! it scheduling decisions, and reports how to reproduce a
+//! failure.
+//!
+//! A body asks [`Choices`] for decisions the same way `pbt` asks
+//! the arbtest `rng` and `exhaustytest` asks the exhaustigen
+//! `Gen`. Every answer is recorded into a [`Schedule`], which is
+//! printed on failure and can be fed back via
+//! [`Config::replay`].
+//!
+//! Schedules and seeds reproduce across operating systems and
+//! architectures. Only one managed thread runs at a time, so OS
+//! wakeup order doesn't matter, and no decision depends on a
+//! hash seed or an address. The one caveat is a body which
+//! itself asks for more than `u32::MAX` options, as bounds are
+//! `usize`.
+
+use crate::memory::MemoryModel;
+use arbtest::arbitrary::{self, Unstructured};
+use std::{
+  any::Any,
+  collections::{hash_map::RandomState, HashSet},
+  fmt,
+  hash::{BuildHasher, DefaultHasher, Hash, Hasher},
+  panic::{self, AssertUnwindSafe},
+  path::{Path, PathBuf},
+  str::FromStr,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+#[derive(Clone)]
+pub struct Config {
+  iterations: u32,
+  seed: Option<u64>,
+  exhaustive: bool,
+  max_interleavings: Option<u64>,
+  hybrid: Option<u64>,
+  replay: Option<Schedule>,
+  fuzz: Option<Vec<u8>>,
+  pct_depth: Option<u32>,
+  miri: bool,
+  miri_iterations: u64,
+  memory_model: MemoryModel,
+  reorder_window: Option<usize>,
+  time_budget: Option<Duration>,
+  spurious_wakeups: bool,
+  spurious_cas_failures: bool,
+  alloc_failures: bool,
+  io_errors: bool,
+  channel_disconnects: bool,
+  thread_crashes: bool,
+  priorities: bool,
+  inject_yields: bool,
+  cache: Option<PathBuf>,
+  print_stats: bool,
+  coverage: bool,
+  triage: Option<u32>,
+  nondeterminism: bool,
+  on_progress: Option<OnProgress>,
+  debugger: Option<Debugger>,
+  rr: Option<PathBuf>,
+}
+
+type OnProgress = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+type Debugger = Arc<Mutex<dyn FnMut(&Prompt) -> usize + Send>>;
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      iterations: 100,
+      seed: None,
+      exhaustive: false,
+      max_interleavings: None,
+      hybrid: None,
+      replay: None,
+      fuzz: None,
+      pct_depth: None,
+      miri: cfg!(miri),
+      miri_iterations: 1,
+      memory_model: MemoryModel::SeqCst,
+      reorder_window: None,
+      time_budget: None,
+      spurious_wakeups: false,
+      spurious_cas_failures: false,
+      alloc_failures: false,
+      io_errors: false,
+      channel_disconnects: false,
+      thread_crashes: false,
+      priorities: false,
+      inject_yields: false,
+      cache: None,
+      print_stats: false,
+      coverage: false,
+      triage: None,
+      nondeterminism: false,
+      on_progress: None,
+      debugger: None,
+      rr: None,
+    }
+  }
+}
+
+impl Config {
+  pub fn new() -> Config {
+    Config::default()
+  }
+
+  /// Number of random iterations to run. Ignored in exhaustive
+  /// mode.
+  pub fn iterations(mut self, iterations: u32) -> Config {
+    self.iterations = iterations;
+    self
+  }
+
+  /// Runs random iterations for about `budget` of wall-clock
+  /// time instead of a fixed count: a run stops before an
+  /// iteration of average length would exceed it, after at least
+  /// one. Lets a suite pick, say, two seconds locally and thirty
+  /// in CI, whatever an iteration of each test costs.
+  ///
+  /// Ignored in exhaustive mode, unless there's a
+  /// [`Config::cache`] to pick up from next time.
+  pub fn time_budget(mut self, budget: Duration) -> Config {
+    self.time_budget = Some(budget);
+    self
+  }
+
+  /// Runs exactly one random iteration with the given seed.
+  pub fn seed(mut self, seed: u64) -> Config {
+    self.seed = Some(seed);
+    self
+  }
+
+  /// Enumerates every sequence of decisions instead of sampling.
+  pub fn exhaustive(mut self) -> Config {
+    self.exhaustive = true;
+    self
+  }
+
+  /// Fails an exhaustive run as soon as it gets to interleaving
+  /// `n + 1`, so that a space which outgrew what the test was
+  /// written for, say after someone added an atomic, fails CI
+  /// rather than makes it a hundred times slower. Counts the
+  /// iterations of this run, not those [`Config::cache`] skips.
+  pub fn fail_if_interleavings_exceed(
+    mut self,
+    n: u64,
+  ) -> Config {
+    self.max_interleavings = Some(n);
+    self
+  }
+
+  /// Runs the random iterations, then, if they suggest there are
+  /// at most `max` sequences of decisions, enumerates all of
+  /// them as [`Config::exhaustive`] does. Small tests get
+  /// exhausted and large ones sampled, without picking a mode
+  /// for each.
+  ///
+  /// The estimate is Knuth's: along a uniformly random path, the
+  /// product of the number of options at each decision averages
+  /// to the number of paths. It's rough for lopsided spaces,
+  /// where one rare branch holds most of the paths.
+  pub fn hybrid(mut self, max: u64) -> Config {
+    self.hybrid = Some(max);
+    self
+  }
+
+  /// Picks threads with PCT, probabilistic concurrency testing,
+  /// instead of uniformly at random: threads get random
+  /// priorities, the highest priority runnable thread always
+  /// runs, and at `depth - 1` random steps the running thread is
+  /// demoted. A bug needing `depth` ordering constraints is
+  /// found with a probability that doesn't decay with the length
+  /// of the run.
+  ///
+  /// Only affects [`Choices::pick_thread`] in random mode.
+  /// Schedules record the picked threads, so they replay the
+  /// same regardless of this setting.
+  pub fn pct(mut self, depth: u32) -> Config {
+    assert!(depth > 0);
+    self.pct_depth = Some(depth);
+    self
+  }
+
+  /// Whether to behave as if running under Miri, which is
+  /// detected automatically.
+  ///
+  /// Every step of the explorer is a pair of thread handoffs,
+  /// which Miri executes orders of magnitude slower than native
+  /// code, so under Miri random and exhaustive runs stop after
+  /// [`Config::miri_iterations`]. Seeds and replays are
+  /// unaffected: the intended workflow is to find an interesting
+  /// interleaving natively and then re-run the same test under
+  /// Miri with `replay` to check it for undefined behavior.
+  pub fn miri(mut self, miri: bool) -> Config {
+    self.miri = miri;
+    self
+  }
+
+  /// Iteration cap under Miri, defaults to 1.
+  pub fn miri_iterations(mut self, iterations: u64) -> Config {
+    self.miri_iterations = iterations;
+    self
+  }
+
+  /// Simulates weak memory: loads which aren't `SeqCst` may
+  /// return stale values, unless an `Acquire`/`Release` edge
+  /// rules them out. Which value a load reads becomes one more
+  /// decision, so exhaustive mode enumerates every value each
+  /// load may see, random mode samples them, and schedules
+  /// replay them. See [`crate::memory`].
+  ///
+  /// Threads spawned through [`crate::loom_compat`] are
+  /// modelled, operations submitted to
+  /// [`crate::managed_thread`]s are `SeqCst`.
+  pub fn weak_memory(mut self) -> Config {
+    self.memory_model = MemoryModel::Weak;
+    self
+  }
+
+  /// Like [`Config::weak_memory`], but simulates x86's memory
+  /// model, TSO, no matter what orderings the code uses: the
+  /// only reordering is a load going ahead of the thread's
+  /// earlier stores. Enough for code which only ever runs on
+  /// x86.
+  pub fn tso(mut self) -> Config {
+    self.memory_model = MemoryModel::Tso;
+    self
+  }
+
+  /// Under weak memory, lets a load read at most `window` stores
+  /// older than the newest one it may see, which shrinks the
+  /// space to explore. Lowering it until a failure goes away
+  /// also tells how far values have to be reordered to trigger
+  /// it: zero makes every load read the newest value.
+  pub fn reorder_window(mut self, window: usize) -> Config {
+    self.reorder_window = Some(window);
+    self
+  }
+
+  /// Remembers exhaustive progress in the file at `path`, one
+  /// file per test. While the test binary and the rest of the
+  /// config stay the same, an exploration which completed is
+  /// skipped, and one cut short by [`Config::miri_iterations`]
+  /// or [`Config::time_budget`] resumes where it stopped, so
+  /// repeated runs add up to the full space. Progress is also
+  /// saved every second or so, in case a run gets killed. Miri
+  /// needs `-Zmiri-disable-isolation` to access the file.
+  ///
+  /// Not part of the text form, as the path is local to a
+  /// machine.
+  pub fn cache(mut self, path: impl Into<PathBuf>) -> Config {
+    self.cache = Some(path.into());
+    self
+  }
+
+  /// On a failure, writes its schedule and a script which
+  /// records the test replaying it under `rr` into the directory
+  /// `dir`, to step back and forth through the code under test
+  /// in a debugger. The script runs the test binary directly, so
+  /// it only works for a test of a libtest harness, and one
+  /// which starts exploring from an entry point reading
+  /// [`Config::env`].
+  ///
+  /// Not part of the text form, as the path is local to a
+  /// machine.
+  pub fn rr(mut self, dir: impl Into<PathBuf>) -> Config {
+    self.rr = Some(dir.into());
+    self
+  }
+
+  /// Lets one [`thread::park`] of [`crate::loom_compat`] per
+  /// thread and iteration return without an unpark, and one
+  /// [`Condvar`] wait without a notification, to check that wait
+  /// loops re-check their condition. The explorer decides which
+  /// calls wake up.
+  ///
+  /// [`thread::park`]: crate::loom_compat::thread::park
+  /// [`Condvar`]: crate::loom_compat::sync::Condvar
+  pub fn spurious_wakeups(mut self) -> Config {
+    self.spurious_wakeups = true;
+    self
+  }
+
+  pub(crate) fn get_spurious_wakeups(&self) -> bool {
+    self.spurious_wakeups
+  }
+
+  /// Lets `compare_exchange_weak` fail despite a matching value,
+  /// once per thread and iteration, as it may on LL/SC hardware.
+  /// The explorer decides which call fails, so retry loops which
+  /// don't re-read the value get caught even on x86 hosts.
+  ///
+  /// Affects threads spawned through [`crate::loom_compat`].
+  pub fn spurious_cas_failures(mut self) -> Config {
+    self.spurious_cas_failures = true;
+    self
+  }
+
+  pub(crate) fn get_spurious_cas_failures(&self) -> bool {
+    self.spurious_cas_failures
+  }
+
+  /// Lets one allocation per thread and iteration fail, inside
+  /// [`alloc::fallible`] and under [`alloc::FailingAlloc`]. The
+  /// explorer picks which one.
+  ///
+  /// [`alloc::fallible`]: crate::alloc::fallible
+  /// [`alloc::FailingAlloc`]: crate::alloc::FailingAlloc
+  pub fn alloc_failures(mut self) -> Config {
+    self.alloc_failures = true;
+    self
+  }
+
+  pub(crate) fn get_alloc_failures(&self) -> bool {
+    self.alloc_failures
+  }
+
+  /// Lets one [`crate::io`] operation per thread and iteration
+  /// fail with an error, at a point the explorer picks.
+  pub fn io_errors(mut self) -> Config {
+    self.io_errors = true;
+    self
+  }
+
+  pub(crate) fn get_io_errors(&self) -> bool {
+    self.io_errors
+  }
+
+  /// Lets a [`loom_compat::sync::mpsc`] channel look hung up to
+  /// one operation per thread and iteration while the other side
+  /// is still there: a send fails, or a `try_recv` or
+  /// `recv_timeout` sees `Disconnected`. The explorer picks
+  /// which operation.
+  ///
+  /// [`loom_compat::sync::mpsc`]: crate::loom_compat::sync::mpsc
+  pub fn channel_disconnects(mut self) -> Config {
+    self.channel_disconnects = true;
+    self
+  }
+
+  pub(crate) fn get_channel_disconnects(&self) -> bool {
+    self.channel_disconnects
+  }
+
+  /// Lets the explorer kill one [`crate::loom_compat`] thread
+  /// per iteration, other than the main one, at a pause point of
+  /// its choice, see [`crate::managed_thread::Crash`]. Joining
+  /// the thread returns the `Crash` as an error. The crash
+  /// unwinds, and never lands between taking a lock and getting
+  /// its guard, nor halfway through an unlock.
+  pub fn thread_crashes(mut self) -> Config {
+    self.thread_crashes = true;
+    self
+  }
+
+  pub(crate) fn get_thread_crashes(&self) -> bool {
+    self.thread_crashes
+  }
+
+  /// Schedules [`crate::loom_compat`] threads by strict
+  /// priority, as an RTOS would: a thread runs only if no thread
+  /// of a higher [`set_priority`] is runnable. An iteration
+  /// which goes on for too long fails as starvation, which is
+  /// how a high priority thread spinning on a lock held by a low
+  /// priority one shows up.
+  ///
+  /// [`set_priority`]: crate::loom_compat::thread::set_priority
+  pub fn priorities(mut self) -> Config {
+    self.priorities = true;
+    self
+  }
+
+  pub(crate) fn get_priorities(&self) -> bool {
+    self.priorities
+  }
+
+  /// Adds scheduling points to operations which aren't atomics
+  /// but usually sit between them: accesses through the
+  /// [`UnsafeCell`] of [`crate::loom_compat`] and reads of the
+  /// [`time`] clock. Code with long stretches of plain memory
+  /// accesses gets more interleavings, at the cost of a larger
+  /// space to explore.
+  ///
+  /// [`UnsafeCell`]: crate::loom_compat::cell::UnsafeCell
+  /// [`time`]: crate::time
+  pub fn inject_yields(mut self) -> Config {
+    self.inject_yields = true;
+    self
+  }
+
+  pub(crate) fn get_inject_yields(&self) -> bool {
+    self.inject_yields
+  }
+
+  /// Prints the [`Stats`] of every run.
+  pub fn print_stats(mut self) -> Config {
+    self.print_stats = true;
+    self
+  }
+
+  /// Prints which instrumented operations were reached, see
+  /// [`crate::loom_compat::coverage`].
+  pub fn coverage(mut self) -> Config {
+    self.coverage = true;
+    self
+  }
+
+  pub(crate) fn get_coverage(&self) -> bool {
+    self.coverage
+  }
+
+  /// On a failure, replays its schedule `replays` times and
+  /// tries the schedules next to it before reporting, see
+  /// [`triage`]. A failure which doesn't come back on replay
+  /// depends on something the explorer doesn't control, like an
+  /// uninstrumented atomic, a real thread, or the clock.
+  pub fn triage(mut self, replays: u32) -> Config {
+    assert!(replays > 0);
+    self.triage = Some(replays);
+    self
+  }
+
+  /// Runs every passing iteration a second time along its own
+  /// schedule, and fails if the replay went differently: asked
+  /// for other decisions, paused elsewhere, or
+  /// [`Choices::observe`]d other values. Catches the real time,
+  /// real randomness and uninstrumented synchronization which
+  /// make replays miss, before a failure needs replaying. Halves
+  /// the iterations per second.
+  pub fn detect_nondeterminism(mut self) -> Config {
+    self.nondeterminism = true;
+    self
+  }
+
+  /// Calls `f` after every iteration, and once more if one
+  /// fails, to drive a progress bar or log a line now and then.
+  /// Called often, so `f` should throttle anything slow itself.
+  ///
+  /// Not part of the text form.
+  pub fn on_progress(
+    mut self,
+    f: impl Fn(&Progress) + Send + Sync + 'static,
+  ) -> Config {
+    self.on_progress = Some(Arc::new(f));
+    self
+  }
+
+  fn report(
+    &self,
+    iterations: u64,
+    failures: u64,
+    eta: Option<Duration>,
+  ) {
+    if let Some(f) = &self.on_progress {
+      f(&Progress { iterations, failures, eta })
+    }
+  }
+
+  pub(crate) fn memory_model(&self) -> MemoryModel {
+    self.memory_model
+  }
+
+  pub(crate) fn get_reorder_window(&self) -> Option<usize> {
+    self.reorder_window
+  }
+
+  /// Runs exactly one iteration following a schedule printed by
+  /// an earlier failure.
+  pub fn replay(mut self, schedule: &str) -> Config {
+    let schedule = schedule
+      .parse()
+      .unwrap_or_else(|err| panic!("invalid schedule: {err}"));
+    self.replay = Some(schedule);
+    self
+  }
+
+  /// Runs exactly one iteration taking its decisions from
+  /// `data`, for driving exploration from a coverage-guided
+  /// fuzzer:
+  ///
+  /// ```text
+  /// fuzz_target!(|data: &[u8]| {
+  ///   Config::new().fuzz(data).run(body);
+  /// });
+  /// ```
+  ///
+  /// Each decision reads as few bytes as its bound needs, and
+  /// zeros once `data` runs out, so every input is a valid
+  /// schedule. A failure prints the schedule taken, which
+  /// [`Config::replay`] reproduces without the fuzzer.
+  ///
+  /// Not part of the text form.
+  pub fn fuzz(mut self, data: &[u8]) -> Config {
+    self.fuzz = Some(data.to_vec());
+    self
+  }
+
+  /// Runs exactly one iteration, asking `f` for every decision,
+  /// to step through a counterexample by hand or to teach. Every
+  /// managed thread is paused while `f` runs, so it may inspect
+  /// whatever state of theirs it shares. Answers above the bound
+  /// are clamped.
+  ///
+  /// Not part of the text form.
+  pub fn debugger(
+    mut self,
+    f: impl FnMut(&Prompt) -> usize + Send + 'static,
+  ) -> Config {
+    self.debugger = Some(Arc::new(Mutex::new(f)));
+    self
+  }
+
+  /// A [`Config::debugger`] which prints each decision to stderr
+  /// and reads the answer from stdin, a number or an empty line
+  /// for 0.
+  pub fn interactive(self) -> Config {
+    self.debugger(|prompt| {
+      eprint!("{prompt} > ");
+      loop {
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+          Ok(0) | Err(_) => return 0,
+          Ok(_) => (),
+        }
+        match line.trim() {
+          "" => return 0,
+          line => match line.parse() {
+            Ok(answer) => return answer,
+            Err(_) => eprint!("expected a number > "),
+          },
+        }
+      }
+    })
+  }
+
+  /// Selects the scheduler by name: `random`, `exhaustive`,
+  /// `pct:DEPTH`, or `hybrid:MAX`.
+  pub fn scheduler(
+    mut self,
+    name: &str,
+  ) -> Result<Config, String> {
+    self.exhaustive = false;
+    self.hybrid = None;
+    self.pct_depth = None;
+    match name {
+      "random" => Ok(self),
+      "exhaustive" => Ok(self.exhaustive()),
+      _ => {
+        if let Some(Ok(depth)) =
+          name.strip_prefix("pct:").map(str::parse)
+        {
+          if depth > 0 {
+            return Ok(self.pct(depth));
+          }
+        }
+        match name.strip_prefix("hybrid:").map(str::parse) {
+          Some(Ok(max)) => Ok(self.hybrid(max)),
+          _ => Err(format!("unknown scheduler `{name}`")),
+        }
+      }
+    }
+  }
+
+  /// Applies overrides from the environment, so that CI can
+  /// change settings without recompiling the tests:
+  ///
+  /// - `PROPERLY_ITERS`: iteration count,
+  /// - `PROPERLY_TIME_BUDGET_MS`: see [`Config::time_budget`],
+  /// - `PROPERLY_SEED`: a seed to run,
+  /// - `PROPERLY_SCHEDULER`: see [`Config::scheduler`],
+  /// - `PROPERLY_REPLAY`: a schedule to replay,
+  /// - `PROPERLY_REPLAY_FILE`: a file containing a schedule to
+  ///   replay.
+  ///
+  /// Called by the entry points: [`crate::harness`], the
+  /// `#[test]` macro, [`crate::loom_compat::model`] and the
+  /// like, and [`crate::model_test::ModelTest`] runs.
+  /// [`Config::run`] itself doesn't, as the crate runs it
+  /// internally too, say to replay candidate schedules.
+  pub fn env(self) -> Config {
+    self
+      .env_with(|name| std::env::var(name).ok())
+      .unwrap_or_else(|err| panic!("{err}"))
+  }
+
+  fn env_with(
+    mut self,
+    var: impl Fn(&str) -> Option<String>,
+  ) -> Result<Config, String> {
+    let invalid = |name: &str, value: &str| {
+      format!("invalid {name}: `{value}`")
+    };
+    if let Some(value) = var("PROPERLY_ITERS") {
+      let iterations = value
+        .parse()
+        .map_err(|_| invalid("PROPERLY_ITERS", &value))?;
+      self = self.iterations(iterations);
+    }
+    if let Some(value) = var("PROPERLY_TIME_BUDGET_MS") {
+      let ms = value.parse().map_err(|_| {
+        invalid("PROPERLY_TIME_BUDGET_MS", &value)
+      })?;
+      self = self.time_budget(Duration::from_millis(ms));
+    }
+    if let Some(value) = var("PROPERLY_SEED") {
+      let seed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+      };
+      self = self.seed(
+        seed.map_err(|_| invalid("PROPERLY_SEED", &value))?,
+      );
+    }
+    if let Some(value) = var("PROPERLY_SCHEDULER") {
+      self = self.scheduler(&value)?;
+    }
+    let replay = match var("PROPERLY_REPLAY_FILE") {
+      Some(path) => {
+        Some(std::fs::read_to_string(&path).map_err(|err| {
+          format!(
+            "can't read PROPERLY_REPLAY_FILE {path}: {err}"
+          )
+        })?)
+      }
+      None => var("PROPERLY_REPLAY"),
+    };
+    if let Some(value) = replay {
+      let schedule = value
+        .parse()
+        .map_err(|_| invalid("schedule", &value))?;
+      self.replay = Some(schedule);
+    }
+    Ok(self)
+  }
+
+  /// Runs `body` for every iteration, returning what it went
+  /// through.
+  pub fn run(self, mut body: impl FnMut(&mut Choices)) -> Stats {
+    let start = Instant::now();
+    let mut tally = Tally::default();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+      self.run_in(&mut body, &mut tally)
+    }));
+    if let Err(payload) = result {
+      // The failing iteration never made it into the tally.
+      self.report(tally.iterations + 1, 1, None);
+      panic::resume_unwind(payload);
+    }
+    let stats = Stats {
+      iterations: tally.iterations,
+      distinct_schedules: tally.schedules.len() as u64,
+      duplicate_schedules: tally.iterations
+        - tally.schedules.len() as u64,
+      max_depth: tally.max_depth,
+      pause_points: tally.pause_points,
+      elapsed: start.elapsed(),
+      tree: tally.tree,
+    };
+    if self.print_stats {
+      eprintln!("{stats}");
+    }
+    stats
+  }
+
+  fn run_in(
+    &self,
+    mut body: impl FnMut(&mut Choices),
+    tally: &mut Tally,
+  ) {
+    let config = self;
+    if let Some(schedule) = config.replay.clone() {
+      let source = Source::Replay { schedule, pos: 0 };
+      tally.record(run_iteration(
+        &mut body,
+        source,
+        None,
+        None,
+        Some(config),
+      ));
+      return;
+    }
+    if let Some(debugger) = config.debugger.clone() {
+      let choices = run_iteration(
+        &mut body,
+        Source::Debugger(debugger),
+        None,
+        None,
+        Some(config),
+      );
+      eprintln!("Schedule: \"{}\"", choices.schedule);
+      tally.record(choices);
+      return;
+    }
+    if let Some(bytes) = config.fuzz.clone() {
+      let source = Source::Bytes { bytes, pos: 0 };
+      tally.record(run_iteration(
+        &mut body,
+        source,
+        None,
+        None,
+        Some(config),
+      ));
+      return;
+    }
+    // PCT needs to know roughly how long a run is to spread its
+    // change points, learn that from the previous iterations.
+    let mut steps_estimate = 16;
+    let pct = |seed, steps_estimate| {
+      let depth = config.pct_depth?;
+      let mut rng = Rng(seed ^ 0x7c7);
+      let change_points = (1..depth)
+        .map(|_| (rng.next() % steps_estimate as u64) as usize)
+        .collect();
+      Some(Pct {
+        depth,
+        change_points,
+        priorities: Vec::new(),
+        step: 0,
+      })
+    };
+    if let Some(seed) = config.seed {
+      let pct = pct(seed, steps_estimate);
+      let source = Source::Random(Rng(seed));
+      tally.record(run_iteration(
+        &mut body,
+        source,
+        pct,
+        Some(seed),
+        Some(config),
+      ));
+      return;
+    }
+    let limit = if config.miri {
+      config.miri_iterations
+    } else {
+      u64::MAX
+    };
+    if config.exhaustive {
+      config.run_exhaustive(&mut body, tally, limit);
+      return;
+    }
+    let mut seeds =
+      Rng(RandomState::new().build_hasher().finish());
+    let iterations = limit.min(config.iterations as u64);
+    let start = Instant::now();
+    let mut count = 0;
+    while count < limit {
+      match config.time_budget {
+        Some(budget) if over_budget(start, count, budget) => {
+          break
+        }
+        Some(_) => (),
+        None if count == iterations => break,
+        None => (),
+      }
+      count += 1;
+      let seed = seeds.next();
+      let source = Source::Random(Rng(seed));
+      let pct = pct(seed, steps_estimate);
+      let choices = run_iteration(
+        &mut body,
+        source,
+        pct,
+        Some(seed),
+        Some(config),
+      );
+      if let Some(pct) = &choices.pct {
+        steps_estimate = steps_estimate.max(pct.step);
+      }
+      tally.record(choices);
+      let elapsed = start.elapsed();
+      let eta = match config.time_budget {
+        Some(budget) => budget.saturating_sub(elapsed),
+        None => elapsed
+          .mul_f64((iterations - count) as f64 / count as f64),
+      };
+      config.report(tally.iterations, 0, Some(eta));
+    }
+    if let Some(max) = config.hybrid {
+      let estimate = tally.paths / count.max(1) as f64;
+      if estimate <= max as f64 {
+        eprintln!(
+          "about {estimate:.0} interleavings, exploring all of \
+           them"
+        );
+        config.run_exhaustive(&mut body, tally, limit);
+      } else {
+        eprintln!(
+          "about {estimate:.0} interleavings, too many to \
+           explore"
+        );
+      }
+    }
+  }
+
+  fn run_exhaustive(
+    &self,
+    mut body: impl FnMut(&mut Choices),
+    tally: &mut Tally,
+    limit: u64,
+  ) {
+    let config = self;
+    let cache = config
+      .cache
+      .as_ref()
+      .map(|path| Cache { path, key: config.cache_key() });
+    let mut g = match &cache {
+      Some(cache) => match cache.load() {
+        Some(None) => {
+          eprintln!(
+            "all interleavings were explored before, see {}",
+            cache.path.display()
+          );
+          return;
+        }
+        Some(Some(g)) => g,
+        None => Odometer::default(),
+      },
+      None => Odometer::default(),
+    };
+    let start = Instant::now();
+    let mut checkpoint = start;
+    let mut interleavings_count = 0;
+    let mut done = false;
+    while interleavings_count < limit {
+      if let (Some(budget), Some(_)) =
+        (config.time_budget, &cache)
+      {
+        if over_budget(start, interleavings_count, budget) {
+          break;
+        }
+      }
+      if g.done() {
+        done = true;
+        break;
+      }
+      if let Some(max) = config.max_interleavings {
+        assert!(
+          interleavings_count < max,
+          "more than {max} interleavings, see \
+           `Config::fail_if_interleavings_exceed`"
+        );
+      }
+      interleavings_count += 1;
+      let mut choices = run_iteration(
+        &mut body,
+        Source::Exhaustive(g),
+        None,
+        None,
+        Some(config),
+      );
+      g = match std::mem::replace(
+        &mut choices.source,
+        Source::Random(Rng(0)),
+      ) {
+        Source::Exhaustive(g) => g,
+        _ => unreachable!(),
+      };
+      tally.record(choices);
+      let eta = config
+        .time_budget
+        .filter(|_| cache.is_some())
+        .map(|budget| budget.saturating_sub(start.elapsed()));
+      config.report(tally.iterations, 0, eta);
+      if let Some(cache) = &cache {
+        if checkpoint.elapsed() > Duration::from_secs(1) {
+          cache.store(Some(&g));
+          checkpoint = Instant::now();
+        }
+      }
+    }
+    if let Some(cache) = &cache {
+      cache.store((!done).then_some(&g));
+    }
+    let mut tree = std::mem::take(&mut g.tree);
+    if !done {
+      tree.frontier = g
+        .digits
+        .iter()
+        .map(|&(v, bound)| (bound - v) as u64)
+        .sum();
+    }
+    tally.tree = Some(tree);
+    if done {
+      eprintln!(
+        "all {interleavings_count} interleavings are fine!"
+      );
+    } else {
+      eprintln!(
+        "first {interleavings_count} interleavings are fine"
+      );
+    }
+  }
+}
+
+/// Whether an iteration of average length would take a run which
+/// did `count` of them since `start` past `budget`. Never before
+/// the first one.
+fn over_budget(
+  start: Instant,
+  count: u64,
+  budget: Duration,
+) -> bool {
+  if count == 0 {
+    return false;
+  }
+  let elapsed = start.elapsed().as_secs_f64();
+  let next = elapsed * (count + 1) as f64 / count as f64;
+  next > budget.as_secs_f64()
+}
+
+/// Passed to [`Config::on_progress`].
+#[derive(Clone, Debug)]
+pub struct Progress {
+  /// Iterations done so far, the failing one included.
+  pub iterations: u64,
+  /// Exploration stops at the first failure, so this is 1 only
+  /// in the last call.
+  pub failures: u64,
+  /// Left of the iterations of a random run or of the
+  /// [`Config::time_budget`], `None` for an exhaustive run which
+  /// goes on until done.
+  pub eta: Option<Duration>,
+}
+
+/// What a [`Config::run`] went through, to tell a passing run
+/// which explored something from one which didn't.
+///
+/// Exploration doesn't hash program states, so repeated work
+/// shows up as iterations which retook an earlier schedule.
+#[derive(Clone, Debug)]
+pub struct Stats {
+  pub iterations: u64,
+  /// Iterations whose decisions no earlier iteration made.
+  pub distinct_schedules: u64,
+  /// Iterations which retook an earlier schedule, the states
+  /// that deduplication would have skipped.
+  pub duplicate_schedules: u64,
+  /// The most decisions made in one iteration.
+  pub max_depth: usize,
+  /// Points where the explorer picked which thread goes next.
+  pub pause_points: u64,
+  pub elapsed: Duration,
+  /// Only for exhaustive runs.
+  pub tree: Option<Tree>,
+}
+
+/// The shape of the decision tree an exhaustive run went
+/// through, to see why it is slow: which depths branch the most,
+/// and how much is left. Covers this run only, not what
+/// [`Config::cache`] resumed from.
+#[derive(Clone, Debug, Default)]
+pub struct Tree {
+  /// The decisions first reached at each depth.
+  pub levels: Vec<Level>,
+  /// Options never explored since the body ruled them out, like
+  /// those of [`Choices::weighted`] with a zero weight.
+  pub pruned: u64,
+  /// Branches off the last path taken which are left to explore,
+  /// zero unless the run was cut short.
+  pub frontier: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Level {
+  pub decisions: u64,
+  /// Options, summed over the decisions.
+  pub branches: u64,
+}
+
+impl Level {
+  pub fn branch_factor(&self) -> f64 {
+    self.branches as f64 / self.decisions.max(1) as f64
+  }
+}
+
+impl Stats {
+  /// Panics if fewer than `n` distinct schedules were explored,
+  /// say because a refactoring lost the pause points which made
+  /// the test concurrent.
+  #[track_caller]
+  pub fn assert_min_schedules(&self, n: u64) {
+    assert!(
+      self.distinct_schedules >= n,
+      "expected at least {n} distinct schedules, got {self}"
+    );
+  }
+}
+
+impl fmt::Display for Stats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} iterations, {} distinct schedules ({} duplicates), \
+      depth up to {}, {} pause points, in {:.2?}",
+      self.iterations,
+      self.distinct_schedules,
+      self.duplicate_schedules,
+      self.max_depth,
+      self.pause_points,
+      self.elapsed
+    )
+  }
+}
+
+#[derive(Default)]
+struct Tally {
+  iterations: u64,
+  /// Hashes of the schedules, which is cheaper than keeping
+  /// them.
+  schedules: HashSet<u64>,
+  max_depth: usize,
+  pause_points: u64,
+  /// Sum of [`Choices::paths`].
+  paths: f64,
+  tree: Option<Tree>,
+}
+
+impl Tally {
+  fn record(&mut self, choices: Choices) {
+    let decisions = choices.schedule.decisions();
+    let mut hasher = DefaultHasher::new();
+    hasher.write(&decisions.len().to_le_bytes());
+    for &it in decisions {
+      hasher.write_usize(it);
+    }
+    self.iterations += 1;
+    self.schedules.insert(hasher.finish());
+    self.max_depth = self.max_depth.max(decisions.len());
+    self.pause_points += choices.pause_points;
+    self.paths += choices.paths;
+  }
+}
+
+impl Config {
+  /// Identifies the test binary, by its size and modification
+  /// time rather than its contents which may run into hundreds
+  /// of megabytes, and the settings, see [`Config::cache`].
+  fn cache_key(&self) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    if let Ok(exe) =
+      std::env::current_exe().and_then(std::fs::metadata)
+    {
+      hasher.write_u64(exe.len());
+      if let Ok(modified) = exe.modified() {
+        modified.hash(&mut hasher);
+      }
+    }
+    hasher.write(self.to_string().as_bytes());
+    hasher.finish()
+  }
+}
+
+/// The file behind [`Config::cache`]: the key on the first line,
+/// then either `done` or the [`Odometer`] after the last
+/// explored iteration.
+struct Cache<'a> {
+  path: &'a Path,
+  key: u64,
+}
+
+impl Cache<'_> {
+  /// `Some(None)` if the exploration completed. Stale or
+  /// unreadable files are ignored.
+  fn load(&self) -> Option<Option<Odometer>> {
+    let text = std::fs::read_to_string(self.path).ok()?;
+    let (key, state) = text.split_once('\n')?;
+    if key != format!("{:#x}", self.key) {
+      return None;
+    }
+    match state.trim() {
+      "done" => Some(None),
+      state => Some(Some(state.parse().ok()?)),
+    }
+  }
+
+  fn store(&self, g: Option<&Odometer>) {
+    let state = match g {
+      Some(g) => g.to_string(),
+      None => "done".to_string(),
+    };
+    let text = format!("{:#x}\n{state}\n", self.key);
+    if let Err(err) = std::fs::write(self.path, text) {
+      eprintln!("can't write {}: {err}", self.path.display());
+    }
+  }
+}
+
+/// A single-line form, `iterations=100 seed=0x2a exhaustive`,
+/// for storing a configuration next to the failures it found and
+/// running it elsewhere.
+impl fmt::Display for Config {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "iterations={}", self.iterations)?;
+    if let Some(seed) = self.seed {
+      write!(f, " seed={seed:#x}")?;
+    }
+    if self.exhaustive {
+      write!(f, " exhaustive")?;
+    }
+    if let Some(max) = self.max_interleavings {
+      write!(f, " fail_if_interleavings_exceed={max}")?;
+    }
+    if let Some(max) = self.hybrid {
+      write!(f, " hybrid={max}")?;
+    }
+    if let Some(depth) = self.pct_depth {
+      write!(f, " pct={depth}")?;
+    }
+    if let Some(schedule) = &self.replay {
+      write!(f, " replay={schedule}")?;
+    }
+    match self.memory_model {
+      MemoryModel::SeqCst => (),
+      MemoryModel::Tso => write!(f, " tso")?,
+      MemoryModel::Weak => write!(f, " weak_memory")?,
+    }
+    if let Some(window) = self.reorder_window {
+      write!(f, " reorder_window={window}")?;
+    }
+    if let Some(budget) = self.time_budget {
+      write!(f, " time_budget_ms={}", budget.as_millis())?;
+    }
+    if self.spurious_wakeups {
+      write!(f, " spurious_wakeups")?;
+    }
+    if self.spurious_cas_failures {
+      write!(f, " spurious_cas_failures")?;
+    }
+    if self.alloc_failures {
+      write!(f, " alloc_failures")?;
+    }
+    if self.io_errors {
+      write!(f, " io_errors")?;
+    }
+    if self.channel_disconnects {
+      write!(f, " channel_disconnects")?;
+    }
+    if self.thread_crashes {
+      write!(f, " thread_crashes")?;
+    }
+    if self.priorities {
+      write!(f, " priorities")?;
+    }
+    if self.inject_yields {
+      write!(f, " inject_yields")?;
+    }
+    if self.print_stats {
+      write!(f, " print_stats")?;
+    }
+    if self.coverage {
+      write!(f, " coverage")?;
+    }
+    if let Some(replays) = self.triage {
+      write!(f, " triage={replays}")?;
+    }
+    if self.nondeterminism {
+      write!(f, " detect_nondeterminism")?;
+    }
+    write!(
+      f,
+      " miri={} miri_iterations={}",
+      self.miri, self.miri_iterations
+    )
+  }
+}
+
+/// The text form.
+impl fmt::Debug for Config {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_tuple("Config").field(&self.to_string()).finish()
+  }
+}
+
+impl FromStr for Config {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    for word in s.split_whitespace() {
+      let (key, value) = match word.split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (word, None),
+      };
+      let invalid = || format!("invalid config option `{word}`");
+      let int = |value: Option<&str>| -> Result<u64, String> {
+        let value = value.ok_or_else(invalid)?;
+        let parsed = match value.strip_prefix("0x") {
+          Some(hex) => u64::from_str_radix(hex, 16),
+          None => value.parse(),
+        };
+        parsed.map_err(|_| invalid())
+      };
+      let int32 = |value: Option<&str>| -> Result<u32, String> {
+        u32::try_from(int(value)?).map_err(|_| invalid())
+      };
+      match key {
+        "iterations" => config.iterations = int32(value)?,
+        "seed" => config.seed = Some(int(value)?),
+        "exhaustive" if value.is_none() => {
+          config.exhaustive = true
+        }
+        "fail_if_interleavings_exceed" => {
+          config.max_interleavings = Some(int(value)?)
+        }
+        "hybrid" => config.hybrid = Some(int(value)?),
+        "pct" => config.pct_depth = Some(int32(value)?),
+        "replay" => {
+          let schedule = value.ok_or_else(invalid)?;
+          config.replay =
+            Some(schedule.parse().map_err(|_| invalid())?)
+        }
+        "miri" => {
+          config.miri = value
+            .ok_or_else(invalid)?
+            .parse()
+            .map_err(|_| invalid())?
+        }
+        "miri_iterations" => {
+          config.miri_iterations = int(value)?
+        }
+        "weak_memory" if value.is_none() => {
+          config.memory_model = MemoryModel::Weak
+        }
+        "tso" if value.is_none() => {
+          config.memory_model = MemoryModel::Tso
+        }
+        "reorder_window" => {
+          config.reorder_window = Some(
+            usize::try_from(int(value)?)
+              .map_err(|_| invalid())?,
+          )
+        }
+        "spurious_wakeups" if value.is_none() => {
+          config.spurious_wakeups = true
+        }
+        "spurious_cas_failures" if value.is_none() => {
+          config.spurious_cas_failures = true
+        }
+        "alloc_failures" if value.is_none() => {
+          config.alloc_failures = true
+        }
+        "io_errors" if value.is_none() => {
+          config.io_errors = true
+        }
+        "channel_disconnects" if value.is_none() => {
+          config.channel_disconnects = true
+        }
+        "thread_crashes" if value.is_none() => {
+          config.thread_crashes = true
+        }
+        "priorities" if value.is_none() => {
+          config.priorities = true
+        }
+        "inject_yields" if value.is_none() => {
+          config.inject_yields = true
+        }
+        "print_stats" if value.is_none() => {
+          config.print_stats = true
+        }
+        "coverage" if value.is_none() => config.coverage = true,
+        "triage" => match int32(value)? {
+          0 => return Err(invalid()),
+          replays => config.triage = Some(replays),
+        },
+        "detect_nondeterminism" if value.is_none() => {
+          config.nondeterminism = true
+        }
+        "time_budget_ms" => {
+          config.time_budget =
+            Some(Duration::from_millis(int(value)?))
+        }
+        _ => return Err(invalid()),
+      }
+    }
+    Ok(config)
+  }
+}
+
+fn run_iteration(
+  body: &mut impl FnMut(&mut Choices),
+  source: Source,
+  pct: Option<Pct>,
+  seed: Option<u64>,
+  config: Option<&Config>,
+) -> Choices {
+  let mut choices = Choices {
+    source,
+    pct,
+    schedule: Schedule::default(),
+    pause_points: 0,
+    paths: 1.0,
+    observed: Vec::new(),
+  };
+  let rr = config.and_then(|it| it.rr.as_deref());
+  let guard =
+    PrintScheduleOnPanic { choices: &mut choices, seed, rr };
+  if let Some(replays) = config.and_then(|it| it.triage) {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+      body(guard.choices)
+    }));
+    if let Err(payload) = result {
+      let failure = Failure {
+        message: panic_message(&*payload),
+        schedule: guard.choices.schedule.clone(),
+      };
+      eprintln!("\n{}", self::triage(&failure, replays, body));
+      panic::resume_unwind(payload);
+    }
+  } else {
+    body(guard.choices);
+  }
+  std::mem::forget(guard);
+  if config.is_some_and(|it| it.nondeterminism) {
+    assert_deterministic(&choices, body);
+  }
+  choices
+}
+
+/// See [`Config::detect_nondeterminism`].
+fn assert_deterministic(
+  choices: &Choices,
+  body: &mut impl FnMut(&mut Choices),
+) {
+  let schedule = &choices.schedule;
+  let mut replay = Choices {
+    source: Source::Replay {
+      schedule: schedule.clone(),
+      pos: 0,
+    },
+    pct: None,
+    schedule: Schedule::default(),
+    pause_points: 0,
+    paths: 1.0,
+    observed: Vec::new(),
+  };
+  let result =
+    panic::catch_unwind(AssertUnwindSafe(|| body(&mut replay)));
+  let difference = match result {
+    Err(payload) => {
+      format!("the replay failed: {}", panic_message(&*payload))
+    }
+    Ok(()) => match divergence(choices, &replay) {
+      Some(difference) => difference,
+      None => return,
+    },
+  };
+  panic!(
+    "the body is nondeterministic, replaying schedule \
+    \"{schedule}\" went differently: {difference}. Look for \
+    real time, real randomness, or synchronization the \
+    explorer doesn't see."
+  )
+}
+
+fn divergence(
+  run: &Choices,
+  replay: &Choices,
+) -> Option<String> {
+  let differ = |what: &str, i: usize, a: String, b: String| {
+    format!("{what} {i} was {a}, then {b}")
+  };
+  let (a, b) =
+    (&run.schedule.decisions, &replay.schedule.decisions);
+  if let Some(i) =
+    (0..a.len().max(b.len())).find(|&i| a.get(i) != b.get(i))
+  {
+    let show = |it: Option<&usize>| match it {
+      Some(value) => value.to_string(),
+      None => "not asked for".to_string(),
+    };
+    return Some(differ(
+      "decision",
+      i,
+      show(a.get(i)),
+      show(b.get(i)),
+    ));
+  }
+  if run.pause_points != replay.pause_points {
+    return Some(format!(
+      "{} pause points, then {}",
+      run.pause_points, replay.pause_points
+    ));
+  }
+  let (a, b) = (&run.observed, &replay.observed);
+  let i =
+    (0..a.len().max(b.len())).find(|&i| a.get(i) != b.get(i))?;
+  let show = |it: Option<&String>| match it {
+    Some(value) => value.clone(),
+    None => "not observed".to_string(),
+  };
+  Some(differ(
+    "observed value",
+    i,
+    show(a.get(i)),
+    show(b.get(i)),
+  ))
+}
+
+/// Runs `body` once following `schedule`, returning the failure
+/// instead of panicking.
+///
+/// This is the hook for external property-testing frameworks:
+/// generate a `Vec<usize>`, convert it into a [`Schedule`], and
+/// turn `Err` into the framework's failure type. Since replay
+/// clamps out-of-range decisions and pads with zeros, every
+/// vector is a valid schedule, so the framework's own shrinking
+/// applies as is. With proptest:
+///
+/// ```text
+/// proptest!(|(decisions in vec(any::<usize>(), 0..64))| {
+///   explore::check(&decisions.into(), body)
+///     .map_err(|it| TestCaseError::fail(it.to_string()))?;
+/// });
+/// ```
+pub fn check(
+  schedule: &Schedule,
+  body: impl FnMut(&mut Choices),
+) -> Result<(), Failure> {
+  check_taking(schedule, body).1
+}
+
+/// [`check`], along with the schedule taken, which differs from
+/// `schedule` where a decision was above its bound.
+fn check_taking(
+  schedule: &Schedule,
+  mut body: impl FnMut(&mut Choices),
+) -> (Schedule, Result<(), Failure>) {
+  let source =
+    Source::Replay { schedule: schedule.clone(), pos: 0 };
+  let mut choices = Choices {
+    source,
+    pct: None,
+    schedule: Schedule::default(),
+    pause_points: 0,
+    paths: 1.0,
+    observed: Vec::new(),
+  };
+  let result =
+    panic::catch_unwind(AssertUnwindSafe(|| body(&mut choices)));
+  let result = result.map_err(|payload| Failure {
+    message: panic_message(&*payload),
+    schedule: choices.schedule.clone(),
+  });
+  (choices.schedule, result)
+}
+
+/// Replays a failure `replays` times, and once along each
+/// schedule which takes a single decision of it one step either
+/// way, to tell whether it's deterministic and how particular
+/// the interleaving it needs is. A step past a decision's bound
+/// is clamped back to the failure's own schedule, so it doesn't
+/// count as a perturbation.
+pub fn triage(
+  failure: &Failure,
+  replays: u32,
+  mut body: impl FnMut(&mut Choices),
+) -> Triage {
+  let fails = |schedule: &Schedule, body: &mut _| {
+    check(schedule, body).is_err() as u32
+  };
+  let replay_failures = (0..replays)
+    .map(|_| fails(&failure.schedule, &mut body))
+    .sum();
+  let schedule = &failure.schedule;
+  let neighbours = (0..schedule.len()).flat_map(|i| {
+    let d = schedule.decisions[i];
+    [d.checked_sub(1), d.checked_add(1)]
+      .into_iter()
+      .flatten()
+      .map(move |d| schedule.clone().set(i, d))
+  });
+  let (mut perturbations, mut perturbation_failures) = (0, 0);
+  for schedule in neighbours {
+    let (taken, result) = check_taking(&schedule, &mut body);
+    if taken == failure.schedule {
+      continue;
+    }
+    perturbations += 1;
+    perturbation_failures += result.is_err() as u32;
+  }
+  Triage {
+    replays,
+    replay_failures,
+    perturbations,
+    perturbation_failures,
+  }
+}
+
+/// What [`triage`] found out about a failure.
+#[derive(Clone, Debug)]
+pub struct Triage {
+  pub replays: u32,
+  /// Replays which failed again.
+  pub replay_failures: u32,
+  pub perturbations: u32,
+  /// Perturbed schedules which failed too.
+  pub perturbation_failures: u32,
+}
+
+impl Triage {
+  /// Whether every replay failed.
+  pub fn deterministic(&self) -> bool {
+    self.replay_failures == self.replays
+  }
+}
+
+impl fmt::Display for Triage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let verdict = if self.deterministic() {
+      "deterministic under replay"
+    } else {
+      "depends on uninstrumented nondeterminism"
+    };
+    write!(
+      f,
+      "failure is {verdict}: failed {} of {} replays and {} of \
+      {} perturbed schedules",
+      self.replay_failures,
+      self.replays,
+      self.perturbation_failures,
+      self.perturbations
+    )
+  }
+}
+
+/// Runs `body` once under `config`, taking its decisions from
+/// what's left of `rng`, see [`Config::fuzz`]. Data generated
+/// from `rng` beforehand and the interleaving then come from the
+/// same bytes, so one arbtest seed or fuzzer input reproduces
+/// both:
+///
+/// ```text
+/// arbtest::arbtest(|rng| {
+///   let ops: Vec<Op> = rng.arbitrary()?;
+///   explore::interleave(rng, Config::new(), |g| run(&ops, g))?;
+///   Ok(())
+/// });
+/// ```
+///
+/// Data comes first, so long sequences of ops can leave next to
+/// nothing, and past the end every decision is zero, which runs
+/// the threads about one after the other. See [`split_schedule`]
+/// to give the interleaving bytes of its own.
+pub fn interleave(
+  rng: &mut Unstructured<'_>,
+  config: Config,
+  body: impl FnMut(&mut Choices),
+) -> arbitrary::Result<Stats> {
+  let rest = rng.bytes(rng.len())?;
+  Ok(config.fuzz(rest).run(body))
+}
+
+/// Takes `share` of what's left of `rng` for scheduling
+/// decisions, before any data is generated from the rest. As
+/// arbtest grows and shrinks its inputs, both budgets grow and
+/// shrink together:
+///
+/// ```text
+/// arbtest::arbtest(|rng| {
+///   let schedule = explore::split_schedule(rng, 0.5)?;
+///   let ops: Vec<Op> = rng.arbitrary()?;
+///   Config::new().fuzz(schedule).run(|g| run(&ops, g));
+///   Ok(())
+/// });
+/// ```
+pub fn split_schedule<'a>(
+  rng: &mut Unstructured<'a>,
+  share: f64,
+) -> arbitrary::Result<&'a [u8]> {
+  assert!((0.0..=1.0).contains(&share), "share of {share}");
+  let len = (rng.len() as f64 * share).round() as usize;
+  rng.bytes(len)
+}
+
+/// Returns a closure which runs `body` once along `schedule` per
+/// call.
+///
+/// Meant for benchmarks of concurrent code: every measured run
+/// takes exactly the same interleaving, so numbers are
+/// comparable run to run. With criterion,
+/// `b.iter(fixed_schedule(schedule, body))`.
+pub fn fixed_schedule(
+  schedule: Schedule,
+  mut body: impl FnMut(&mut Choices),
+) -> impl FnMut() {
+  move || {
+    let source =
+      Source::Replay { schedule: schedule.clone(), pos: 0 };
+    run_iteration(&mut body, source, None, None, None);
+  }
+}
+
+/// A panic caught by [`check`].
+#[derive(Clone, Debug)]
+pub struct Failure {
+  message: String,
+  schedule: Schedule,
+}
+
+impl Failure {
+  pub fn message(&self) -> &str {
+    &self.message
+  }
+
+  /// The decisions actually taken up to the panic.
+  pub fn schedule(&self) -> &Schedule {
+    &self.schedule
+  }
+}
+
+impl fmt::Display for Failure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} (schedule: \"{}\")",
+      self.message, self.schedule
+    )
+  }
+}
+
+pub(crate) fn panic_message(
+  payload: &(dyn Any + Send),
+) -> String {
+  if let Some(it) = payload.downcast_ref::<&str>() {
+    return it.to_string();
+  }
+  if let Some(it) = payload.downcast_ref::<String>() {
+    return it.clone();
+  }
+  "Box<dyn Any>".to_string()
+}
+
+struct PrintScheduleOnPanic<'a> {
+  choices: &'a mut Choices,
+  seed: Option<u64>,
+  /// See [`Config::rr`].
+  rr: Option<&'a Path>,
+}
+
+impl Drop for PrintScheduleOnPanic<'_> {
+  fn drop(&mut self) {
+    if std::thread::panicking() {
+      eprintln!("\nproperly-concurrent failed!");
+      if let Some(seed) = self.seed {
+        eprintln!("    Seed: {seed:#018x}");
+      }
+      eprintln!("    Schedule: \"{}\"\n", self.choices.schedule);
+      if let Some(dir) = self.rr {
+        match write_rr_driver(dir, &self.choices.schedule) {
+          Ok(script) => eprintln!(
+            "    Record under rr with {}\n",
+            script.display()
+          ),
+          Err(err) => {
+            eprintln!("can't write to {}: {err}", dir.display())
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Writes the schedule and a script which records the current
+/// test replaying it, returns the script's path.
+fn write_rr_driver(
+  dir: &Path,
+  schedule: &Schedule,
+) -> std::io::Result<PathBuf> {
+  let thread = std::thread::current();
+  let test = thread.name().unwrap_or("main");
+  let name = test.replace("::", "-");
+  let schedule_path = dir.join(format!("{name}.schedule"));
+  std::fs::create_dir_all(dir)?;
+  std::fs::write(&schedule_path, schedule.to_string())?;
+  let quote =
+    |it: &str| format!("'{}'", it.replace('\'', "'\\''"));
+  let exe = std::env::current_exe()?;
+  let script = format!(
+    "#!/bin/sh\n\
+    # Records {test} failing under rr, then `rr replay` to \
+    debug.\n\
+    PROPERLY_REPLAY_FILE={} exec rr record {} {} --exact \
+    --nocapture --test-threads=1\n",
+    quote(&schedule_path.to_string_lossy()),
+    quote(&exe.to_string_lossy()),
+    quote(test),
+  );
+  let script_path = dir.join(format!("{name}.rr.sh"));
+  std::fs::write(&script_path, script)?;
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let executable = std::fs::Permissions::from_mode(0o755);
+    std::fs::set_permissions(&script_path, executable)?;
+  }
+  Ok(script_path)
+}
+
+/// Source of scheduling decisions for a single iteration.
+pub struct Choices {
+  source: Source,
+  pct: Option<Pct>,
+  schedule: Schedule,
+  /// Calls to [`Choices::pick_thread`].
+  pause_points: u64,
+  /// The inverse of the probability of the decisions so far, see
+  /// [`Config::hybrid`].
+  paths: f64,
+  /// See [`Choices::observe`].
+  observed: Vec<String>,
+}
+
+struct Pct {
+  depth: u32,
+  /// Steps at which the running thread gets demoted.
+  change_points: Vec<usize>,
+  /// `(thread, priority)`, threads get one the first time
+  /// they're seen.
+  priorities: Vec<(usize, u64)>,
+  step: usize,
+}
+
+enum Source {
+  Random(Rng),
+  Exhaustive(Odometer),
+  Replay {
+    schedule: Schedule,
+    pos: usize,
+  },
+  /// See [`Config::fuzz`].
+  Bytes {
+    bytes: Vec<u8>,
+    pos: usize,
+  },
+  Debugger(Debugger),
+}
+
+/// A decision for a [`Config::debugger`] to make.
+pub struct Prompt<'a> {
+  /// The answer is in `0..=bound`.
+  pub bound: usize,
+  /// For a pick of the thread to go next, the threads to pick
+  /// from, by index.
+  pub threads: Option<&'a [usize]>,
+  /// The decisions made so far.
+  pub schedule: &'a Schedule,
+}
+
+impl fmt::Display for Prompt<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "after \"{}\", ", self.schedule)?;
+    match self.threads {
+      Some(threads) => {
+        write!(f, "pick a thread:")?;
+        for (i, thread) in threads.iter().enumerate() {
+          write!(f, " {i}=")?;
+          match thread {
+            &usize::MAX => write!(f, "clock")?,
+            thread => write!(f, "t{thread}")?,
+          }
+        }
+        Ok(())
+      }
+      None => write!(f, "pick a value in 0..={}", self.bound),
+    }
+  }
+}
+
+/// The source of decisions, and what was decided so far.
+impl fmt::Debug for Choices {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let source = match &self.source {
+      Source::Random(_) if self.pct.is_some() => "pct",
+      Source::Random(_) => "random",
+      Source::Exhaustive(_) => "exhaustive",
+      Source::Replay { .. } => "replay",
+      Source::Bytes { .. } => "fuzz",
+      Source::Debugger(_) => "debugger",
+    };
+    let mut f = f.debug_struct("Choices");
+    f.field("source", &source);
+    match &self.source {
+      Source::Exhaustive(g) => {
+        f.field("odometer", &g.to_string())
+      }
+      Source::Replay { pos, .. } | Source::Bytes { pos, .. } => {
+        f.field("pos", pos)
+      }
+      _ => &mut f,
+    };
+    f.field("schedule", &self.schedule.to_string())
+      .field("pause_points", &self.pause_points)
+      .finish()
+  }
+}
+
+impl Choices {
+  /// Returns a value between 0 and `bound` inclusive.
+  pub fn gen(&mut self, bound: usize) -> usize {
+    let value = match &mut self.source {
+      Source::Random(rng) => match (bound as u64).checked_add(1)
+      {
+        Some(n) => (rng.next() % n) as usize,
+        None => rng.next() as usize,
+      },
+      Source::Exhaustive(g) => g.gen(bound),
+      Source::Replay { schedule, pos } => {
+        let value =
+          schedule.decisions.get(*pos).copied().unwrap_or(0);
+        *pos += 1;
+        value.min(bound)
+      }
+      Source::Debugger(debugger) => {
+        let prompt = Prompt {
+          bound,
+          threads: None,
+          schedule: &self.schedule,
+        };
+        (debugger.lock().unwrap())(&prompt).min(bound)
+      }
+      Source::Bytes { bytes, pos } => {
+        let width = (usize::BITS - bound.leading_zeros())
+          .div_ceil(8) as usize;
+        let mut value = [0; 8];
+        for byte in &mut value[..width] {
+          *byte = bytes.get(*pos).copied().unwrap_or(0);
+          *pos += 1;
+        }
+        let value = u64::from_le_bytes(value);
+        match (bound as u64).checked_add(1) {
+          Some(n) => (value % n) as usize,
+          None => value as usize,
+        }
+      }
+    };
+    self.schedule.decisions.push(value);
+    self.paths *= bound as f64 + 1.0;
+    value
+  }
+
+  /// Returns false, then true.
+  pub fn flip(&mut self) -> bool {
+    self.gen(1) == 1
+  }
+
+  /// Returns an index into `weights`, with a probability in
+  /// proportion to its weight. Exhaustive exploration tries each
+  /// index with a non-zero weight once.
+  pub fn weighted(&mut self, weights: &[u32]) -> usize {
+    let starts: Vec<u64> = weights
+      .iter()
+      .scan(0, |start, &w| {
+        let this = *start;
+        *start += w as u64;
+        Some(this)
+      })
+      .collect();
+    let total: u64 = weights.iter().map(|&w| w as u64).sum();
+    assert!(total > 0, "all weights are zero");
+    if let Source::Exhaustive(g) = &mut self.source {
+      let live: Vec<usize> =
+        (0..weights.len()).filter(|&i| weights[i] > 0).collect();
+      let pruned = (weights.len() - live.len()) as u64;
+      let pick = live[g.gen_pruning(live.len() - 1, pruned)];
+      // Recorded like a random pick, so that replays agree.
+      self.schedule.decisions.push(starts[pick] as usize);
+      return pick;
+    }
+    let value = self.gen(total as usize - 1) as u64;
+    let pick = (0..weights.len())
+      .rev()
+      .find(|&i| weights[i] > 0 && starts[i] <= value)
+      .unwrap();
+    self.paths /= weights[pick] as f64;
+    pick
+  }
+
+  /// Selects an element from `input`.
+  pub fn pick<'a, T>(&mut self, input: &'a [T]) -> &'a T {
+    &input[self.gen(input.len() - 1)]
+  }
+
+  /// Picks which of the `runnable` threads goes next, returning
+  /// an index into `runnable`. Threads are identified by any id
+  /// which is stable within an iteration.
+  pub fn pick_thread(&mut self, runnable: &[usize]) -> usize {
+    self.pause_points += 1;
+    let bound = runnable.len() - 1;
+    if let Source::Debugger(debugger) = &self.source {
+      let prompt = Prompt {
+        bound,
+        threads: Some(runnable),
+        schedule: &self.schedule,
+      };
+      let pick = (debugger.lock().unwrap())(&prompt).min(bound);
+      self.schedule.decisions.push(pick);
+      return pick;
+    }
+    let (Some(pct), Source::Random(rng)) =
+      (&mut self.pct, &mut self.source)
+    else {
+      return self.gen(bound);
+    };
+    for &tid in runnable {
+      if !pct.priorities.iter().any(|&(t, _)| t == tid) {
+        // Initial priorities are all above the demoted ones.
+        let priority = (pct.depth as u64).max(rng.next());
+        pct.priorities.push((tid, priority));
+      }
+    }
+    let priority = |tid| {
+      pct.priorities.iter().find(|&&(t, _)| t == tid).unwrap().1
+    };
+    let pick = (0..runnable.len())
+      .max_by_key(|&i| priority(runnable[i]))
+      .unwrap();
+    let demoted = pct
+      .change_points
+      .iter()
+      .filter(|&&c| c <= pct.step)
+      .count();
+    if pct.change_points.contains(&pct.step) {
+      let (_, priority) = pct
+        .priorities
+        .iter_mut()
+        .find(|(t, _)| *t == runnable[pick])
+        .unwrap();
+      *priority = pct.depth as u64 - demoted as u64;
+    }
+    pct.step += 1;
+    self.schedule.decisions.push(pick);
+    pick
+  }
+
+  /// A seed for the body's own randomness, see [`crate::rand`]:
+  /// random in random mode, and always 0 in exhaustive mode,
+  /// where seeds aren't worth branching on. Recorded like any
+  /// decision, so replays agree.
+  pub fn seed(&mut self) -> u64 {
+    if let Source::Exhaustive(_) = self.source {
+      self.schedule.decisions.push(0);
+      return 0;
+    }
+    // Not a branch of the space, see `Config::hybrid`.
+    let paths = self.paths;
+    // 32 bits, as more would depend on the width of `usize`.
+    let seed = self.gen(u32::MAX as usize) as u64;
+    self.paths = paths;
+    seed
+  }
+
+  /// Records the `Debug` of some state, like what the threads
+  /// ended up with, for [`Config::detect_nondeterminism`] to
+  /// compare between a run and its replay. Nothing else looks at
+  /// it.
+  pub fn observe(&mut self, state: impl fmt::Debug) {
+    self.observed.push(format!("{state:?}"))
+  }
+
+  /// Decisions made so far in this iteration.
+  pub fn schedule(&self) -> &Schedule {
+    &self.schedule
+  }
+}
+
+/// A recorded sequence of decisions, displayed as `1.0.3`.
+///
+/// Replaying a schedule past its end yields zeros.
+#[derive(Default, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Schedule {
+  decisions: Vec<usize>,
+}
+
+impl Schedule {
+  pub fn decisions(&self) -> &[usize] {
+    &self.decisions
+  }
+
+  pub fn len(&self) -> usize {
+    self.decisions.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.decisions.is_empty()
+  }
+
+  /// The first `len` decisions.
+  pub fn truncate(mut self, len: usize) -> Schedule {
+    self.decisions.truncate(len);
+    self
+  }
+
+  /// `self`, then `other`, for replaying a known prefix and
+  /// exploring a suffix.
+  pub fn concat(mut self, other: &Schedule) -> Schedule {
+    self.decisions.extend_from_slice(&other.decisions);
+    self
+  }
+
+  /// Decision `i` replaced by `decision`, padding with zeros if
+  /// the schedule is shorter.
+  pub fn set(mut self, i: usize, decision: usize) -> Schedule {
+    if self.decisions.len() <= i {
+      self.decisions.resize(i + 1, 0);
+    }
+    self.decisions[i] = decision;
+    self
+  }
+
+  /// Decisions `i` and `i + 1` swapped, which for two thread
+  /// picks runs their steps in the other order. Panics if the
+  /// schedule has no decision `i + 1`.
+  pub fn swap(mut self, i: usize) -> Schedule {
+    self.decisions.swap(i, i + 1);
+    self
+  }
+
+  /// `decision` inserted before decision `i`, delaying the rest.
+  /// Panics if `i` is past the end.
+  pub fn insert(
+    mut self,
+    i: usize,
+    decision: usize,
+  ) -> Schedule {
+    self.decisions.insert(i, decision);
+    self
+  }
+
+  /// Decision `i` moved to the next option: at a thread pick, a
+  /// switch to another thread. Replays clamp to the last option,
+  /// so this changes nothing if decision `i` already was the
+  /// last one.
+  pub fn preempt(self, i: usize) -> Schedule {
+    let next = self.decisions.get(i).map_or(1, |it| it + 1);
+    self.set(i, next)
+  }
+
+  /// Strictly simpler schedules, most aggressive first: shorter
+  /// prefixes, then single decisions removed, then single
+  /// decisions moved towards zero. Each comes up once, even if
+  /// it can be reached in more than one of these ways.
+  ///
+  /// Shaped after quickcheck's `Arbitrary::shrink`, which can
+  /// delegate here directly.
+  pub fn shrink(&self) -> impl Iterator<Item = Schedule> + '_ {
+    let len = self.decisions.len();
+    let prefixes =
+      std::iter::successors(Some(len / 2), move |&n| {
+        let next = n + (len - n) / 2;
+        (next > n).then_some(next)
+      })
+      .filter(move |&n| n < len)
+      .map(move |n| self.decisions[..n].to_vec());
+    let removals = (0..len).map(move |i| {
+      let mut decisions = self.decisions.clone();
+      decisions.remove(i);
+      decisions
+    });
+    let reductions = (0..len).flat_map(move |i| {
+      let d = self.decisions[i];
+      let mut candidates = vec![0, d / 2, d.saturating_sub(1)];
+      candidates.dedup();
+      candidates.into_iter().filter(move |&c| c < d).map(
+        move |c| {
+          let mut decisions = self.decisions.clone();
+          decisions[i] = c;
+          decisions
+        },
+      )
+    });
+    let mut seen = HashSet::new();
+    prefixes
+      .chain(removals)
+      .chain(reductions)
+      .filter(move |it| seen.insert(it.clone()))
+      .map(Schedule::from)
+  }
+}
+
+impl From<Vec<usize>> for Schedule {
+  fn from(decisions: Vec<usize>) -> Schedule {
+    Schedule { decisions }
+  }
+}
+
+impl fmt::Display for Schedule {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (i, decision) in self.decisions.iter().enumerate() {
+      if i > 0 {
+        f.write_str(".")?;
+      }
+      write!(f, "{decision}")?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for Schedule {
+  type Err = std::num::ParseIntError;
+
+  fn from_str(s: &str) -> Result<Schedule, Self::Err> {
+    let s = s.trim();
+    if s.is_empty() {
+      return Ok(Schedule::default());
+    }
+    let decisions =
+      s.split('.').map(str::parse).collect::<Result<_, _>>()?;
+    Ok(Schedule { decisions })
+  }
+}
+
+/// exhaustigen's `Gen`, with a text form for [`Config::cache`].
+///
+/// Every decision is a digit `value` out of `0..=bound`, and
+/// `done` advances the innermost digit which isn't at its bound
+/// yet.
+#[derive(Default)]
+struct Odometer {
+  started: bool,
+  digits: Vec<(usize, usize)>,
+  pos: usize,
+  /// Digits from here on are decisions not reached before.
+  kept: usize,
+  tree: Tree,
+}
+
+impl Odometer {
+  /// Moves on to the next sequence of decisions, returns `true`
+  /// if there are none left.
+  fn done(&mut self) -> bool {
+    if !self.started {
+      self.started = true;
+      return false;
+    }
+    self.pos = 0;
+    while let Some((value, bound)) = self.digits.last_mut() {
+      if value < bound {
+        *value += 1;
+        self.kept = self.digits.len();
+        return false;
+      }
+      self.digits.pop();
+    }
+    true
+  }
+
+  fn gen(&mut self, bound: usize) -> usize {
+    self.gen_pruning(bound, 0)
+  }
+
+  /// [`Odometer::gen`] for a decision which left out `pruned`
+  /// options.
+  fn gen_pruning(&mut self, bound: usize, pruned: u64) -> usize {
+    if self.pos >= self.kept {
+      let levels = &mut self.tree.levels;
+      if levels.len() <= self.pos {
+        levels.resize(self.pos + 1, Level::default());
+      }
+      levels[self.pos].decisions += 1;
+      levels[self.pos].branches += bound as u64 + 1;
+      self.tree.pruned += pruned;
+    }
+    if self.pos == self.digits.len() {
+      self.digits.push((0, bound));
+    }
+    self.digits[self.pos].1 = bound;
+    self.pos += 1;
+    self.digits[self.pos - 1].0
+  }
+}
+
+/// `value/bound` digits, `1/2 0/1`.
+impl fmt::Display for Odometer {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (i, (value, bound)) in self.digits.iter().enumerate() {
+      let sep = if i == 0 { "" } else { " " };
+      write!(f, "{sep}{value}/{bound}")?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for Odometer {
+  type Err = std::num::ParseIntError;
+
+  fn from_str(s: &str) -> Result<Odometer, Self::Err> {
+    let digits = s
+      .split_whitespace()
+      .map(|digit| {
+        let (value, bound) =
+          digit.split_once('/').unwrap_or((digit, ""));
+        Ok((value.parse()?, bound.parse()?))
+      })
+      .collect::<Result<_, _>>()?;
+    Ok(Odometer { started: true, digits, ..Odometer::default() })
+  }
+}
+
+/// SplitMix64, good enough for picking interleavings.
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+  pub(crate) fn next(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+  }
+}
+
+#[test]
+fn trees_show_where_exploration_branches() {
+  let body = |g: &mut Choices| {
+    if g.flip() {
+      g.weighted(&[1, 0, 1]);
+    }
+    g.gen(2);
+  };
+  let tree = Config::new().exhaustive().run(body).tree.unwrap();
+  let levels: Vec<_> = tree
+    .levels
+    .iter()
+    .map(|it| (it.decisions, it.branches))
+    .collect();
+  assert_eq!(levels, [(1, 2), (2, 5), (2, 6)]);
+  assert_eq!(tree.levels[1].branch_factor(), 2.5);
+  assert_eq!((tree.pruned, tree.frontier), (1, 0));
+
+  let cut =
+    Config::new().exhaustive().miri(true).miri_iterations(2);
+  let tree = cut.run(body).tree.unwrap();
+  assert_eq!(tree.frontier, 1 + 1);
+  assert!(Config::new().run(body).tree.is_none());
+}
+
+#[test]
+fn interleaving_limits_fail_loudly() {
+  let body = |g: &mut Choices| {
+    g.gen(3);
+  };
+  let config = Config::new().exhaustive();
+  config.clone().fail_if_interleavings_exceed(4).run(body);
+  let result = panic::catch_unwind(AssertUnwindSafe(|| {
+    config.fail_if_interleavings_exceed(3).run(body)
+  }));
+  let payload = result.unwrap_err();
+  assert_eq!(
+    panic_message(&*payload),
+    "more than 3 interleavings, see \
+     `Config::fail_if_interleavings_exceed`"
+  );
+}
+
+#[test]
+fn seeds_fit_in_32_bits() {
+  Config::new().seed(92).run(|g| {
+    for _ in 0..100 {
+      assert!(g.seed() <= u32::MAX as u64);
+    }
+  });
+}
+
+#[test]
+fn fuzz_reads_decisions_from_bytes() {
+  let mut seen = Vec::new();
+  let stats = Config::new().fuzz(&[1, 0x2c, 0x01, 7]).run(|g| {
+    seen.push([g.gen(1), g.gen(400), g.gen(2), g.gen(3)])
+  });
+  assert_eq!(stats.iterations, 1);
+  assert_eq!(seen, [[1, 300, 1, 0]]);
+}
+
+#[test]
+fn interleave_shares_the_rng() {
+  let mut seen = Vec::new();
+  let data = [3, 1, 0, 1];
+  let mut rng = Unstructured::new(&data);
+  let ops: u8 = rng.arbitrary().unwrap();
+  interleave(&mut rng, Config::new(), |g| {
+    seen.push((0..ops).map(|_| g.flip()).collect::<Vec<_>>())
+  })
+  .unwrap();
+  assert!(rng.is_empty());
+  assert_eq!(seen, [[true, false, true]]);
+}
+
+#[test]
+fn split_schedules_are_not_starved() {
+  let data = [1, 1, 1, 1, 9, 0, 0, 0];
+  let mut rng = Unstructured::new(&data);
+  let schedule = split_schedule(&mut rng, 0.5).unwrap();
+  let ops: u8 = rng.arbitrary().unwrap();
+  let mut seen = Vec::new();
+  Config::new()
+    .fuzz(schedule)
+    .run(|g| seen.push((0..ops).filter(|_| g.flip()).count()));
+  assert_eq!(ops, 9);
+  assert_eq!(seen, [4]);
+}
+
+#[test]
+fn debugger_makes_every_decision() {
+  let prompts = Arc::new(Mutex::new(Vec::new()));
+  let config = Config::new().debugger({
+    let prompts = Arc::clone(&prompts);
+    move |prompt| {
+      prompts.lock().unwrap().push(prompt.to_string());
+      9
+    }
+  });
+  let mut seen = Vec::new();
+  config.run(|g| seen.push((g.gen(3), g.pick_thread(&[4, 7]))));
+  assert_eq!(seen, [(3, 1)]);
+  assert_eq!(
+    *prompts.lock().unwrap(),
+    [
+      "after \"\", pick a value in 0..=3",
+      "after \"3\", pick a thread: 0=t4 1=t7"
+    ]
+  );
+}
+
+#[test]
+fn rr_driver_replays_the_failure() {
+  let dir = std::env::temp_dir().join(format!(
+    "properly-concurrent-rr-{}",
+    std::process::id()
+  ));
+  let config = Config::new().exhaustive().rr(&dir);
+  let result = panic::catch_unwind(AssertUnwindSafe(|| {
+    config.run(|g| assert!(!g.flip()));
+  }));
+  assert!(result.is_err());
+  let name = "explore-rr_driver_replays_the_failure";
+  let schedule = std::fs::read_to_string(
+    dir.join(format!("{name}.schedule")),
+  );
+  let script =
+    std::fs::read_to_string(dir.join(format!("{name}.rr.sh")));
+  std::fs::remove_dir_all(&dir).unwrap();
+  assert_eq!(schedule.unwrap(), "1");
+  let script = script.unwrap();
+  assert!(script.contains("rr record '/"));
+  assert!(script.contains(
+    "'explore::rr_driver_replays_the_failure' --exact"
+  ));
+}
+
+#[test]
+fn schedule_roundtrip() {
+  for text in ["", "0", "1.0.3", "12.0.0.7"] {
+    let schedule: Schedule = text.parse().unwrap();
+    assert_eq!(schedule.to_string(), text);
+  }
+  assert!("1..2".parse::<Schedule>().is_err());
+}
+
+#[test]
+fn shrink_is_strictly_simpler() {
+  let schedule = Schedule::from(vec![4, 0, 2]);
+  let shrunk: Vec<String> =
+    schedule.shrink().map(|it| it.to_string()).collect();
+  assert_eq!(
+    shrunk,
+    [
+      "4", "4.0", "0.2", "4.2", "0.0.2", "2.0.2", "3.0.2",
+      "4.0.0", "4.0.1"
+    ]
+  );
+  assert_eq!(Schedule::default().shrink().count(), 0);
+}
+
+#[test]
+fn replay_follows_schedule() {
+  let mut seen = Vec::new();
+  Config::new().replay("2.1.5").run(|g| {
+    seen.extend([
+      g.gen(3),
+      g.flip() as usize,
+      g.gen(2),
+      g.gen(9),
+    ])
+  });
+  assert_eq!(seen, [2, 1, 2, 0]);
+}
+
+#[test]
+fn check_reports_failure() {
+  let schedule = Schedule::from(vec![3, 7]);
+  let failure = check(&schedule, |g| {
+    let a = g.gen(5);
+    let b = g.gen(5);
+    assert!(a + b < 8, "too big");
+  })
+  .unwrap_err();
+  assert_eq!(failure.message(), "too big");
+  assert_eq!(failure.schedule().decisions(), [3, 5]);
+}
+
+#[test]
+fn config_roundtrip() {
+  let text = "iterations=7 seed=0x2a exhaustive \
+    fail_if_interleavings_exceed=4 hybrid=9 pct=3 replay=1.0.2 \
+    weak_memory reorder_window=2 time_budget_ms=1500 \
+    spurious_wakeups spurious_cas_failures alloc_failures \
+    io_errors channel_disconnects thread_crashes priorities \
+    inject_yields print_stats coverage triage=3 \
+    detect_nondeterminism miri=true miri_iterations=5";
+  let config: Config = text.parse().unwrap();
+  assert_eq!(
+    config.to_string(),
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+  );
+  let tso: Config = "tso miri=false".parse().unwrap();
+  assert_eq!(
+    tso.to_string(),
+    "iterations=100 tso miri=false miri_iterations=1"
+  );
+  assert!("exhaustive=yes".parse::<Config>().is_err());
+  assert!("seed=0xzz".parse::<Config>().is_err());
+  assert!("iterations=4294967296".parse::<Config>().is_err());
+}
+
+#[test]
+fn env_overrides() {
+  let env = |vars: &'static [(&'static str, &'static str)]| {
+    move |name: &str| {
+      vars
+        .iter()
+        .find(|(it, _)| *it == name)
+        .map(|(_, v)| v.to_string())
+    }
+  };
+  let config = Config::new()
+    .exhaustive()
+    .env_with(env(&[
+      ("PROPERLY_ITERS", "7"),
+      ("PROPERLY_TIME_BUDGET_MS", "20"),
+      ("PROPERLY_SEED", "0x2a"),
+      ("PROPERLY_SCHEDULER", "pct:2"),
+      ("PROPERLY_REPLAY", "1.0"),
+    ]))
+    .unwrap();
+  assert_eq!(
+    config.to_string(),
+    "iterations=7 seed=0x2a pct=2 replay=1.0 time_budget_ms=20 \
+    miri=false miri_iterations=1"
+  );
+  assert!(Config::new()
+    .env_with(env(&[("PROPERLY_ITERS", "many")]))
+    .is_err());
+  assert!(Config::new()
+    .env_with(env(&[("PROPERLY_SCHEDULER", "pct:0")]))
+    .is_err());
+  let hybrid = Config::new()
+    .exhaustive()
+    .env_with(env(&[("PROPERLY_SCHEDULER", "hybrid:64")]))
+    .unwrap();
+  assert_eq!(
+    hybrid.to_string(),
+    "iterations=100 hybrid=64 miri=false miri_iterations=1"
+  );
+}
+
+#[test]
+fn miri_caps_iterations() {
+  let config = Config::new().miri(true).miri_iterations(3);
+  assert_eq!(
+    config
+      .clone()
+      .run(|g| {
+        g.gen(9);
+      })
+      .iterations,
+    3
+  );
+  assert_eq!(
+    config
+      .exhaustive()
+      .run(|g| {
+        g.gen(9);
+      })
+      .iterations,
+    3
+  );
+}
+
+#[test]
+fn time_budget_replaces_iterations() {
+  let config = Config::new().iterations(1_000_000);
+  let zero = config.clone().time_budget(Duration::ZERO);
+  assert_eq!(
+    zero
+      .run(|g| {
+        g.gen(9);
+      })
+      .iterations,
+    1
+  );
+  let short = config.time_budget(Duration::from_millis(50));
+  let count = short
+    .run(|_| {
+      std::thread::sleep(Duration::from_millis(10));
+    })
+    .iterations;
+  assert!((1..=5).contains(&count), "{count}");
+}
+
+#[test]
+fn hybrid_exhausts_small_spaces() {
+  let body = |g: &mut Choices| {
+    g.gen(2);
+    // Equal weights keep the estimate exact.
+    g.weighted(&[2, 0, 2]);
+  };
+  let config = Config::new().iterations(5);
+  let small = config.clone().hybrid(6).run(body);
+  assert_eq!(small.iterations, 5 + 6);
+  let large = config.hybrid(5).run(body);
+  assert_eq!(large.iterations, 5);
+}
+
+#[test]
+fn stats_count_distinct_schedules() {
+  let body = |g: &mut Choices| {
+    if g.flip() {
+      g.pick_thread(&[0, 1]);
+    }
+  };
+  let exhaustive = Config::new().exhaustive().run(body);
+  assert_eq!(exhaustive.iterations, 3);
+  assert_eq!(exhaustive.distinct_schedules, 3);
+  assert_eq!(exhaustive.duplicate_schedules, 0);
+  assert_eq!(exhaustive.max_depth, 2);
+  assert_eq!(exhaustive.pause_points, 2);
+  let random = Config::new().iterations(50).run(body);
+  assert_eq!(random.iterations, 50);
+  assert!(random.distinct_schedules <= 3);
+  assert!(random.duplicate_schedules >= 47);
+}
+
+#[test]
+#[should_panic = "expected at least 2 distinct schedules"]
+fn sequential_bodies_have_one_schedule() {
+  let stats = Config::new().exhaustive().run(|g| {
+    g.pick_thread(&[0]);
+  });
+  stats.assert_min_schedules(1);
+  stats.assert_min_schedules(2);
+}
+
+#[test]
+fn nondeterminism_is_detected() {
+  use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+
+  let config =
+    Config::new().iterations(5).detect_nondeterminism();
+  config.clone().run(|g| {
+    let flips = g.gen(3);
+    for _ in 0..flips {
+      g.flip();
+    }
+    g.observe(flips);
+  });
+
+  static RUNS: AtomicU32 = AtomicU32::new(0);
+  let message = |body: fn(&mut Choices)| {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+      config.clone().run(body)
+    }));
+    panic_message(&*result.unwrap_err())
+  };
+  let extra = message(|g| {
+    if RUNS.fetch_add(1, Relaxed) % 2 == 1 {
+      g.flip();
+    }
+  });
+  let expected = "decision 0 was not asked for, then 0";
+  assert!(extra.contains(expected));
+  let observed =
+    message(|g| g.observe(RUNS.fetch_add(1, Relaxed)));
+  assert!(observed.contains("observed value 0 was 2, then 3"));
+}
+
+#[test]
+fn schedules_compose() {
+  let schedule: Schedule = vec![1, 0, 3].into();
+  let other: Schedule = vec![2].into();
+  assert_eq!(schedule.clone().truncate(2).to_string(), "1.0");
+  assert_eq!(
+    schedule.clone().concat(&other).to_string(),
+    "1.0.3.2"
+  );
+  assert_eq!(schedule.clone().swap(0).to_string(), "0.1.3");
+  assert_eq!(
+    schedule.clone().insert(1, 4).to_string(),
+    "1.4.0.3"
+  );
+  assert_eq!(schedule.clone().preempt(1).to_string(), "1.1.3");
+  assert_eq!(
+    schedule.clone().set(4, 2).to_string(),
+    "1.0.3.0.2"
+  );
+
+  // Searching around a passing schedule for a failing one.
+  let body = |g: &mut Choices| {
+    let order = [g.gen(1), g.gen(1)];
+    assert_ne!(order, [0, 1]);
+  };
+  let passing: Schedule = vec![1, 0].into();
+  assert!(check(&passing, body).is_ok());
+  assert!(check(&passing.swap(0), body).is_err());
+}
+
+#[test]
+fn triage_tells_flaky_failures_apart() {
+  use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+  static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+  let failure = check(&vec![1].into(), |g| {
+    assert!(!g.flip(), "boom");
+  })
+  .unwrap_err();
+  let fixed = triage(&failure, 3, |g| {
+    assert!(!g.flip(), "boom");
+  });
+  assert!(fixed.deterministic());
+  assert_eq!(fixed.perturbations, 1, "{fixed}");
+  assert_eq!(fixed.perturbation_failures, 0, "{fixed}");
+  let flaky = triage(&failure, 4, |g| {
+    let odd = RUNS.fetch_add(1, Relaxed) % 2 == 1;
+    assert!(!(g.flip() && odd), "boom");
+  });
+  assert!(!flaky.deterministic());
+  assert_eq!(flaky.replay_failures, 2, "{flaky}");
+}
+
+#[test]
+fn progress_is_reported_until_a_failure() {
+  use std::sync::Mutex;
+
+  let seen = Arc::new(Mutex::new(Vec::new()));
+  let config = Config::new().on_progress({
+    let seen = Arc::clone(&seen);
+    move |progress| seen.lock().unwrap().push(progress.clone())
+  });
+  config.clone().iterations(4).run(|_| ());
+  let iterations: Vec<u64> = seen
+    .lock()
+    .unwrap()
+    .iter()
+    .map(|it| it.iterations)
+    .collect();
+  assert_eq!(iterations, [1, 2, 3, 4]);
+  assert_eq!(seen.lock().unwrap()[3].eta, Some(Duration::ZERO));
+
+  seen.lock().unwrap().clear();
+  let result = panic::catch_unwind(AssertUnwindSafe(|| {
+    config.exhaustive().run(|g| assert!(!g.flip()))
+  }));
+  assert!(result.is_err());
+  let seen = seen.lock().unwrap();
+  assert_eq!(seen.len(), 2);
+  assert_eq!((seen[1].iterations, seen[1].failures), (2, 1));
+  assert_eq!(seen[0].eta, None);
+}
+
+#[test]
+fn cache_resumes_exhaustive_runs() {
+  let path = std::env::temp_dir()
+    .join(format!("properly-concurrent-{}", std::process::id()));
+  let config = Config::new()
+    .exhaustive()
+    .miri(true)
+    .miri_iterations(2)
+    .cache(&path);
+  let mut seen = Vec::new();
+  let mut runs = Vec::new();
+  for _ in 0..4 {
+    let stats = config.clone().run(|g| seen.push(g.gen(4)));
+    runs.push(stats.iterations);
+  }
+  std::fs::remove_file(&path).unwrap();
+  assert_eq!(runs, [2, 2, 1, 0]);
+  assert_eq!(seen, [0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn time_budget_splits_exhaustive_runs() {
+  let path = std::env::temp_dir().join(format!(
+    "properly-concurrent-budget-{}",
+    std::process::id()
+  ));
+  let config = Config::new()
+    .exhaustive()
+    .time_budget(Duration::ZERO)
+    .cache(&path);
+  let mut seen = Vec::new();
+  for _ in 0..4 {
+    config.clone().run(|g| seen.push(g.gen(2)));
+  }
+  std::fs::remove_file(&path).unwrap();
+  assert_eq!(seen, [0, 1, 2]);
+}
+
+#[test]
+fn fixed_schedule_repeats() {
+  let mut seen = Vec::new();
+  let mut bench = fixed_schedule("1.3".parse().unwrap(), |g| {
+    seen.push(g.gen(5))
+  });
+  bench();
+  bench();
+  drop(bench);
+  assert_eq!(seen, [1, 1]);
+}
+
+#[crate::test(exhaustive)]
+fn exhaustive_fetch_add(g: &mut Choices) {
+  use crate::managed_thread::{self, AtomicU32, ThreadGroup};
+  use std::sync::atomic::Ordering::SeqCst;
+
+  let counter = AtomicU32::default();
+  std::thread::scope(|scope| {
+    let threads = ThreadGroup::new(
+      (0..2).map(|_| managed_thread::spawn(scope, &counter)),
+    );
+    threads.submit_all(g, |c| {
+      c.fetch_add(1, SeqCst);
+    });
+    threads.join();
+  });
+  assert_eq!(counter.load(SeqCst), 2);
+}
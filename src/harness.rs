@@ -0,0 +1,188 @@
+//! A test runner for concurrency tests, for `harness = false`
+//! targets.
+//!
+//! Instead of each test hard-coding its iteration count, tests
+//! are registered with a [`Harness`] which owns the exploration
+//! settings:
+//!
+//! ```text
+//! fn main() -> ExitCode {
+//!   Harness::new(Config::new().iterations(10_000))
+//!     .test("counter", counter)
+//!     .test("queue", queue)
+//!     .run()
+//! }
+//! ```
+//!
+//! The command line understands a subset of libtest's: `--list`,
+//! a name filter, and `--exact`. Other flags are ignored, along
+//! with the values of those which take one, like
+//! `--test-threads 1`.
+
+use std::{
+  panic::{self, AssertUnwindSafe},
+  process::ExitCode,
+};
+
+use crate::explore::{Choices, Config};
+
+type Test = (&'static str, fn(&mut Choices));
+
+/// The libtest flags whose value is the next argument.
+const FLAGS_WITH_VALUES: &[&str] = &[
+  "--test-threads",
+  "--logfile",
+  "--skip",
+  "--format",
+  "--color",
+  "--shuffle-seed",
+  "-Z",
+];
+
+pub struct Harness {
+  config: Config,
+  tests: Vec<Test>,
+}
+
+impl Harness {
+  pub fn new(config: Config) -> Harness {
+    Harness { config, tests: Vec::new() }
+  }
+
+  pub fn test(
+    mut self,
+    name: &'static str,
+    body: fn(&mut Choices),
+  ) -> Harness {
+    self.tests.push((name, body));
+    self
+  }
+
+  /// Parses `std::env::args` and runs the selected tests.
+  pub fn run(self) -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> =
+      args.iter().map(String::as_str).collect();
+    let mut out = String::new();
+    let failed = self.run_with_args(&args, &mut out);
+    print!("{out}");
+    if failed {
+      ExitCode::FAILURE
+    } else {
+      ExitCode::SUCCESS
+    }
+  }
+
+  /// Returns whether any test failed.
+  fn run_with_args(
+    self,
+    args: &[&str],
+    out: &mut String,
+  ) -> bool {
+    let list = args.contains(&"--list");
+    let exact = args.contains(&"--exact");
+    let mut filter = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+      if FLAGS_WITH_VALUES.contains(arg) {
+        rest.next();
+      } else if !arg.starts_with('-') && filter.is_none() {
+        filter = Some(arg);
+      }
+    }
+    let selected: Vec<_> = self
+      .tests
+      .iter()
+      .filter(|(name, _)| match filter {
+        None => true,
+        Some(filter) if exact => name == filter,
+        Some(filter) => name.contains(*filter),
+      })
+      .collect();
+
+    if list {
+      for (name, _) in &selected {
+        out.push_str(&format!("{name}: test\n"));
+      }
+      return false;
+    }
+
+    out.push_str(&format!(
+      "\nrunning {} tests\n",
+      selected.len()
+    ));
+    let mut failures = Vec::new();
+    for (name, body) in &selected {
+      let config = self.config.clone().env();
+      let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        config.run(body)
+      }));
+      match result {
+        Ok(stats) => out.push_str(&format!(
+          "test {name} ... ok ({} interleavings)\n",
+          stats.iterations
+        )),
+        Err(_) => {
+          out.push_str(&format!("test {name} ... FAILED\n"));
+          failures.push(name);
+        }
+      }
+    }
+    if !failures.is_empty() {
+      out.push_str("\nfailures:\n");
+      for name in &failures {
+        out.push_str(&format!("    {name}\n"));
+      }
+    }
+    let passed = selected.len() - failures.len();
+    let status =
+      if failures.is_empty() { "ok" } else { "FAILED" };
+    out.push_str(&format!(
+      "\ntest result: {status}. {passed} passed; {} failed; \
+      {} filtered out\n",
+      failures.len(),
+      self.tests.len() - selected.len(),
+    ));
+    !failures.is_empty()
+  }
+}
+
+#[test]
+fn filters_and_reports() {
+  let harness = || {
+    Harness::new(Config::new().exhaustive())
+      .test("coin", |g| {
+        g.flip();
+      })
+      .test("coin_pair", |g| {
+        g.flip();
+        g.flip();
+      })
+      .test("broken", |g| assert!(!g.flip()))
+  };
+
+  let mut out = String::new();
+  assert!(
+    !harness().run_with_args(&["--list", "coin"], &mut out)
+  );
+  assert_eq!(out, "coin: test\ncoin_pair: test\n");
+
+  let mut out = String::new();
+  assert!(
+    !harness().run_with_args(&["coin", "--exact"], &mut out)
+  );
+  assert!(out.contains("test coin ... ok (2 interleavings)"));
+  assert!(out.contains("1 passed; 0 failed; 2 filtered out"));
+
+  let mut out = String::new();
+  let args = ["--test-threads", "1", "-q", "pair"];
+  assert!(!harness().run_with_args(&args, &mut out));
+  assert!(out.contains("1 passed; 0 failed; 2 filtered out"));
+
+  let mut out = String::new();
+  assert!(harness().run_with_args(&[], &mut out));
+  assert!(
+    out.contains("test coin_pair ... ok (4 interleavings)")
+  );
+  assert!(out.contains("test broken ... FAILED"));
+}
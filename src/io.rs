@@ -0,0 +1,208 @@
+//! A simulated filesystem and network.
+//!
+//! Every operation is a scheduling point, so code which mixes
+//! locks with I/O is explored like code which only uses atomics.
+//! Messages in flight on a [`Net`] arrive in an order the
+//! explorer picks, and under [`Config::io_errors`] an operation
+//! may also fail with an error. Outside of exploration,
+//! operations succeed and messages arrive in order.
+//!
+//! [`Config::io_errors`]: crate::explore::Config::io_errors
+
+use std::{
+  collections::HashMap,
+  io,
+  sync::{Arc, Mutex},
+};
+
+use crate::{loom_compat, managed_thread};
+
+/// An in-memory filesystem. Writes are visible to reads right
+/// away, but only survive a [`Fs::crash`] once flushed.
+#[derive(Clone, Default)]
+pub struct Fs {
+  files: Arc<Mutex<HashMap<String, File>>>,
+}
+
+#[derive(Default)]
+struct File {
+  contents: Vec<u8>,
+  durable: Vec<u8>,
+}
+
+impl Fs {
+  pub fn new() -> Fs {
+    Fs::default()
+  }
+
+  /// Replaces the contents of the file, creating it if needed.
+  pub fn write(
+    &self,
+    path: &str,
+    data: &[u8],
+  ) -> io::Result<()> {
+    operation()?;
+    let mut files = self.files.lock().unwrap();
+    files.entry(path.to_string()).or_default().contents =
+      data.to_vec();
+    Ok(())
+  }
+
+  /// Appends to the file, creating it if needed.
+  pub fn append(
+    &self,
+    path: &str,
+    data: &[u8],
+  ) -> io::Result<()> {
+    operation()?;
+    let mut files = self.files.lock().unwrap();
+    let file = files.entry(path.to_string()).or_default();
+    file.contents.extend_from_slice(data);
+    Ok(())
+  }
+
+  pub fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+    operation()?;
+    let files = self.files.lock().unwrap();
+    match files.get(path) {
+      Some(file) => Ok(file.contents.clone()),
+      None => Err(io::ErrorKind::NotFound.into()),
+    }
+  }
+
+  /// Makes the current contents of the file durable.
+  pub fn flush(&self, path: &str) -> io::Result<()> {
+    operation()?;
+    let mut files = self.files.lock().unwrap();
+    match files.get_mut(path) {
+      Some(file) => {
+        file.durable = file.contents.clone();
+        Ok(())
+      }
+      None => Err(io::ErrorKind::NotFound.into()),
+    }
+  }
+
+  /// Loses everything which wasn't flushed, as if the machine
+  /// lost power. Not a scheduling point, call it once the
+  /// threads are done.
+  pub fn crash(&self) {
+    let mut files = self.files.lock().unwrap();
+    for file in files.values_mut() {
+      file.contents = file.durable.clone();
+    }
+  }
+}
+
+/// Identifies a receiver on a [`Net`].
+pub type Addr = usize;
+
+/// An in-memory network which delivers every message exactly
+/// once, in any order.
+#[derive(Clone, Default)]
+pub struct Net {
+  in_flight: Arc<Mutex<Vec<Message>>>,
+}
+
+/// A message and its receiver.
+type Message = (Addr, Vec<u8>);
+
+impl Net {
+  pub fn new() -> Net {
+    Net::default()
+  }
+
+  /// Fails without sending if the operation fails.
+  pub fn send(
+    &self,
+    to: Addr,
+    message: &[u8],
+  ) -> io::Result<()> {
+    operation()?;
+    self.in_flight.lock().unwrap().push((to, message.to_vec()));
+    Ok(())
+  }
+
+  /// Blocks until a message for `me` is in flight, and takes one
+  /// of those.
+  pub fn recv(&self, me: Addr) -> io::Result<Vec<u8>> {
+    operation()?;
+    loop {
+      let in_flight = Arc::clone(&self.in_flight);
+      managed_thread::block_until(Arc::new(move || {
+        in_flight.lock().unwrap().iter().any(|&(to, _)| to == me)
+      }));
+      let count = self.count(me);
+      let pick = match count {
+        0 => continue,
+        1 => 0,
+        _ => loom_compat::choose(count - 1),
+      };
+      let mut in_flight = self.in_flight.lock().unwrap();
+      let index = (0..in_flight.len())
+        .filter(|&i| in_flight[i].0 == me)
+        .nth(pick);
+      if let Some(index) = index {
+        return Ok(in_flight.remove(index).1);
+      }
+    }
+  }
+
+  fn count(&self, me: Addr) -> usize {
+    let in_flight = self.in_flight.lock().unwrap();
+    in_flight.iter().filter(|&&(to, _)| to == me).count()
+  }
+}
+
+/// The scheduling point of every operation, and whether it
+/// fails.
+fn operation() -> io::Result<()> {
+  managed_thread::pause();
+  if loom_compat::io_failure() {
+    return Err(io::Error::other("injected I/O error"));
+  }
+  Ok(())
+}
+
+#[test]
+fn unflushed_writes_are_lost() {
+  use std::collections::HashSet;
+
+  static SEEN: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+  let config = crate::explore::Config::new().exhaustive();
+  loom_compat::run(config.io_errors(), || {
+    let fs = Fs::new();
+    let _ = fs.write("log", b"a");
+    let _ = fs.flush("log");
+    let _ = fs.append("log", b"b");
+    fs.crash();
+    let contents = fs.read("log").unwrap_or_default();
+    SEEN.lock().unwrap().push(contents);
+  });
+  let seen: HashSet<Vec<u8>> =
+    SEEN.lock().unwrap().drain(..).collect();
+  assert_eq!(seen, HashSet::from([b"a".to_vec(), Vec::new()]));
+}
+
+#[test]
+fn messages_are_reordered() {
+  use std::collections::HashSet;
+
+  static SEEN: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+  let config = crate::explore::Config::new().exhaustive();
+  loom_compat::run(config, || {
+    let net = Net::new();
+    net.send(0, b"1").unwrap();
+    net.send(0, b"2").unwrap();
+    let first = net.recv(0).unwrap();
+    SEEN.lock().unwrap().push(first);
+  });
+  let seen: HashSet<Vec<u8>> =
+    SEEN.lock().unwrap().drain(..).collect();
+  assert_eq!(
+    seen,
+    HashSet::from([b"1".to_vec(), b"2".to_vec()])
+  );
+}
@@ -1,29 +1,119 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate self as properly_concurrent;
+
+#[cfg(feature = "std")]
+pub mod alloc;
+pub mod atomic;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "std")]
+pub mod epoch;
+#[cfg(feature = "std")]
+pub mod explore;
+#[cfg(feature = "std")]
+pub mod harness;
+#[cfg(feature = "std")]
+pub mod io;
+#[cfg(feature = "std")]
+pub mod loom_compat;
+#[cfg(feature = "std")]
 pub mod managed_thread;
+#[cfg(feature = "std")]
+pub mod memory;
+#[cfg(feature = "std")]
+pub mod model_test;
+#[cfg(feature = "std")]
+pub mod queue;
+#[cfg(feature = "std")]
+pub mod rand;
+#[cfg(feature = "std")]
+pub mod rcu;
+#[cfg(feature = "report")]
+pub mod report;
+#[cfg(feature = "std")]
+pub mod script;
+pub mod seqlock;
+#[cfg(feature = "std")]
+pub mod shuttle_compat;
+#[cfg(feature = "std")]
+pub mod spin;
+#[cfg(feature = "std")]
+pub mod sync;
+#[cfg(feature = "std")]
+pub mod thread;
+#[cfg(feature = "std")]
+pub mod time;
 
-use std::sync::atomic::Ordering::SeqCst;
+pub use atomic::checkpoint;
+pub use properly_concurrent_macros::{instrument_atomics, test};
 
-#[cfg(test)]
-use managed_thread::AtomicU32;
-#[cfg(not(test))]
-use std::sync::atomic::AtomicU32;
+/// Evaluates an expression between two [`checkpoint`]s, so a
+/// call which can't be instrumented, like into a third-party
+/// crate, is a step of its own. With a label first, the
+/// checkpoint before is a `managed_thread::label`:
+///
+/// ```text
+/// instrument!("flush", self.flush())
+/// ```
+#[macro_export]
+macro_rules! instrument {
+  ($label:literal, $body:expr) => {{
+    $crate::__label($label);
+    let value = $body;
+    $crate::checkpoint();
+    value
+  }};
+  ($body:expr) => {{
+    $crate::checkpoint();
+    let value = $body;
+    $crate::checkpoint();
+    value
+  }};
+}
 
-#[derive(Default)]
-pub struct Counter {
-  value: AtomicU32,
+#[doc(hidden)]
+#[track_caller]
+pub fn __label(name: &'static str) {
+  #[cfg(feature = "std")]
+  managed_thread::label(name);
+  #[cfg(not(feature = "std"))]
+  {
+    let _ = name;
+    checkpoint()
+  }
 }
 
-impl Counter {
-  pub fn increment(&self) {
-    // self.value.fetch_add(1, SeqCst);
-    let value = self.value.load(SeqCst);
-    self.value.store(value + 1, SeqCst);
+#[cfg(feature = "std")]
+pub use counter::Counter;
+
+#[cfg(feature = "std")]
+#[instrument_atomics(any(test, feature = "instrument"))]
+mod counter {
+  use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+
+  #[derive(Default)]
+  pub struct Counter {
+    value: AtomicU32,
   }
 
-  pub fn get(&self) -> u32 {
-    self.value.load(SeqCst)
+  impl Counter {
+    pub fn increment(&self) {
+      // self.value.fetch_add(1, SeqCst);
+      let value = self.value.load(SeqCst);
+      self.value.store(value + 1, SeqCst);
+    }
+
+    pub fn get(&self) -> u32 {
+      self.value.load(SeqCst)
+    }
   }
 }
 
+#[cfg(all(test, feature = "std"))]
+use sync::atomic::Ordering::SeqCst;
+
+#[cfg(feature = "std")]
 #[test]
 fn threaded_test() {
   let counter = Counter::default();
@@ -44,6 +134,7 @@ fn threaded_test() {
   assert_eq!(counter.get(), thread_count * increment_count);
 }
 
+#[cfg(feature = "std")]
 #[test]
 fn pbt() {
   arbtest::arbtest(|rng| {
@@ -82,6 +173,61 @@ fn pbt() {
   .seed(0x9c2a13a600000001);
 }
 
+#[cfg(feature = "std")]
+#[test]
+fn pbt_drive() {
+  arbtest::arbtest(|rng| {
+    let counter = managed_thread::AtomicU32::default();
+    let mut counter_model: u32 = 0;
+
+    std::thread::scope(|scope| {
+      let threads =
+        (0..3).map(|_| managed_thread::spawn(scope, &counter));
+      managed_thread::drive(rng, threads, |_, _, t| {
+        t.submit(|c| {
+          c.fetch_add(1, SeqCst);
+        });
+        counter_model += 1;
+        Ok(())
+      })
+    })?;
+    assert_eq!(counter_model, counter.load(SeqCst));
+
+    Ok(())
+  });
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn pbt_spawn_with() {
+  arbtest::arbtest(|rng| {
+    let counter = managed_thread::AtomicU32::default();
+    let mut counter_model: u32 = 0;
+
+    std::thread::scope(|scope| {
+      let threads = (0..2).map(|_| {
+        managed_thread::spawn_with(
+          scope,
+          &counter,
+          |c, n: u32| {
+            c.fetch_add(n, SeqCst);
+          },
+        )
+      });
+      managed_thread::drive(rng, threads, |rng, _, t| {
+        let n = rng.int_in_range(0..=9)?;
+        t.send(n);
+        counter_model += n;
+        Ok(())
+      })
+    })?;
+    assert_eq!(counter_model, counter.load(SeqCst));
+
+    Ok(())
+  });
+}
+
+#[cfg(feature = "std")]
 #[test]
 fn exhaustytest() {
   let mut g = exhaustigen::Gen::new();
@@ -124,3 +270,126 @@ fn exhaustytest() {
   }
   eprintln!("all {interleavings_count} interleavings are fine!");
 }
+
+#[cfg(feature = "std")]
+#[properly_concurrent::test(exhaustive)]
+#[should_panic]
+fn exhaustive_macro(g: &mut explore::Choices) {
+  let counter = Counter::default();
+  let increment_count = g.gen(5) as u32;
+  let mut counter_model: u32 = 0;
+
+  std::thread::scope(|scope| {
+    let threads = [
+      managed_thread::spawn(scope, &counter),
+      managed_thread::spawn(scope, &counter),
+    ];
+
+    'outer: loop {
+      for t in &threads {
+        if g.flip() {
+          if t.is_paused() {
+            t.unpause();
+            continue 'outer;
+          }
+          if counter_model < increment_count {
+            t.submit(|c| c.increment());
+            counter_model += 1;
+            continue 'outer;
+          }
+        }
+      }
+      break;
+    }
+    for t in threads {
+      t.join()
+    }
+  });
+
+  assert_eq!(counter_model, counter.get());
+}
+
+#[cfg(feature = "std")]
+#[properly_concurrent::test(exhaustive)]
+#[should_panic]
+fn thread_group_loses_an_update(g: &mut explore::Choices) {
+  let counter = Counter::default();
+  std::thread::scope(|scope| {
+    let threads = managed_thread::ThreadGroup::new(
+      (0..2).map(|_| managed_thread::spawn(scope, &counter)),
+    );
+    threads.submit_all(g, |c| c.increment());
+    threads.join();
+  });
+  assert_eq!(counter.get(), 2);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn instrumented_calls_are_steps() {
+  use std::sync::Mutex;
+
+  let log = Mutex::new(Vec::new());
+  std::thread::scope(|scope| {
+    let threads = [1, 2].map(|id| {
+      let t = managed_thread::spawn(scope, &log);
+      t.submit(move |log| {
+        instrument!("push", log.lock().unwrap().push(id));
+        instrument!(log.lock().unwrap().push(id * 10));
+      });
+      t
+    });
+    let [t1, t2] = &threads;
+    script::Script::new()
+      .to(1, "push")
+      .step(1)
+      .step(0)
+      .finish(1)
+      .finish(0)
+      .run(&[t1, t2]);
+    for t in threads {
+      t.join()
+    }
+  });
+  assert_eq!(*log.lock().unwrap(), [2, 1, 20, 10]);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn instrument_atomics_rewrites_paths() {
+  #[instrument_atomics]
+  mod queue {
+    use std::sync::atomic::AtomicUsize;
+
+    pub fn head() -> &'static str {
+      std::any::type_name::<AtomicUsize>()
+    }
+
+    pub fn tail() -> &'static str {
+      std::any::type_name::<::std::sync::atomic::AtomicU8>()
+    }
+  }
+
+  assert!(queue::head().starts_with("properly_concurrent::"));
+  assert!(queue::tail().starts_with("properly_concurrent::"));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn instrument_atomics_rewrites_module_imports() {
+  #[instrument_atomics]
+  mod stack {
+    use std::sync::atomic;
+
+    pub fn top() -> &'static str {
+      std::any::type_name::<atomic::AtomicU32>()
+    }
+
+    pub fn bottom() -> &'static str {
+      std::any::type_name::<core::sync::atomic::AtomicU16>()
+    }
+  }
+
+  assert!(stack::top().starts_with("properly_concurrent::"));
+  assert!(stack::bottom().starts_with("properly_concurrent::"));
+}
@@ -0,0 +1,2484 @@
+//! A subset of [loom]'s API on top of this crate's engine.
+//!
+//! Swapping `use loom::...` for
+//! `use properly_concurrent::loom_compat::...` runs an existing
+//! loom test under our exhaustive explorer, which is handy for
+//! comparing the two and for migrating a suite piecemeal.
+//!
+//! Threads spawned here are `'static` OS threads, reused across
+//! iterations, each paused at every instrumented operation.
+//! Locks are built from the instrumented atomics: a
+//! `std::sync::Mutex` contended across managed threads would
+//! block the explorer.
+//!
+//! [loom]: https://docs.rs/loom
+
+use std::{
+  cell::RefCell,
+  collections::BTreeMap,
+  fmt,
+  panic::{self, AssertUnwindSafe, Location},
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+    Arc, Mutex,
+  },
+};
+
+use crate::{
+  explore,
+  managed_thread::{self, SharedContext},
+  memory::{Memory, MemoryModel},
+  time::Clock,
+};
+
+/// Runs `f` under every interleaving of the threads it spawns.
+/// Like loom, models weak memory, see
+/// [`explore::Config::weak_memory`].
+pub fn model<F>(f: F)
+where
+  F: Fn() + Sync + Send + 'static,
+{
+  run(explore::Config::new().exhaustive().weak_memory(), f)
+}
+
+/// [`model`] under `config` instead, for the options loom
+/// doesn't have, like [`explore::Config::thread_crashes`] or
+/// [`explore::Config::tso`]:
+///
+/// ```text
+/// let config = Config::new().exhaustive().spurious_wakeups();
+/// loom_compat::model_with(config, || { ... });
+/// ```
+pub fn model_with<F>(config: explore::Config, f: F)
+where
+  F: Fn() + Sync + Send + 'static,
+{
+  run(config, f)
+}
+
+/// Runs `f` as the main managed thread of each iteration of
+/// `config`, letting the explorer pick which thread goes next,
+/// with the overrides of [`explore::Config::env`].
+pub(crate) fn run<F>(config: explore::Config, f: F)
+where
+  F: Fn() + Sync + Send + 'static,
+{
+  let config = config.env();
+  let lints = Arc::default();
+  let memory = {
+    let config = config.clone();
+    move || {
+      Arc::new(Memory::new(&config).lints(Arc::clone(&lints)))
+    }
+  };
+  run_in(config, memory, f)
+}
+
+/// Runs `f` like [`model_with`] under `config`, and returns
+/// which instrumented operations its threads reached, and how
+/// often.
+pub fn coverage<F>(config: explore::Config, f: F) -> Coverage
+where
+  F: Fn() + Sync + Send + 'static,
+{
+  let config = config.coverage().env();
+  let memory = {
+    let config = config.clone();
+    move || Arc::new(Memory::new(&config))
+  };
+  explore(config, memory, None, f).unwrap()
+}
+
+/// Replays `schedule`, printed by a failure of `f` under
+/// `config`, then again without each of its preemptions in turn,
+/// latest first, to find one the failure can't do without: the
+/// switch away from a thread which could have gone on. `None` if
+/// the failure doesn't reproduce, or survives the removal of
+/// every single preemption.
+///
+/// The rest of the schedule is replayed as is, so a removal may
+/// also change what later decisions pick.
+pub fn critical_race<F>(
+  config: explore::Config,
+  schedule: &str,
+  f: F,
+) -> Option<CriticalRace>
+where
+  F: Fn() + Sync + Send + 'static,
+{
+  let f = Arc::new(f);
+  let lints = Arc::new(Mutex::default());
+  let replay = |schedule: &explore::Schedule| {
+    let config = config.clone().replay(&schedule.to_string());
+    let memory = {
+      let (config, lints) = (config.clone(), Arc::clone(&lints));
+      move || {
+        Arc::new(Memory::new(&config).lints(Arc::clone(&lints)))
+      }
+    };
+    let preemptions = Arc::new(Mutex::default());
+    let f = Arc::clone(&f);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+      explore(
+        config,
+        memory,
+        Some(Arc::clone(&preemptions)),
+        move || f(),
+      )
+    }));
+    let preemptions =
+      std::mem::take(&mut *preemptions.lock().unwrap());
+    (result.is_err(), preemptions)
+  };
+  let schedule: explore::Schedule =
+    schedule.parse().expect("invalid schedule");
+  let (failed, preemptions) = replay(&schedule);
+  if !failed {
+    return None;
+  }
+  preemptions.into_iter().rev().find_map(|preemption| {
+    let passing =
+      schedule.clone().set(preemption.decision, preemption.stay);
+    let (failed, _) = replay(&passing);
+    (!failed).then_some(CriticalRace { preemption, passing })
+  })
+}
+
+/// The preemption a failure needs, see [`critical_race`].
+#[derive(Clone, Debug)]
+pub struct CriticalRace {
+  preemption: Preemption,
+  passing: explore::Schedule,
+}
+
+/// A switch from a thread which could have gone on.
+#[derive(Clone, Copy, Debug)]
+struct Preemption {
+  /// The index of the pick in the schedule.
+  decision: usize,
+  /// The pick which would have let the thread go on.
+  stay: usize,
+  preempted: (usize, Option<Site>),
+  by: (usize, Option<Site>),
+}
+
+impl CriticalRace {
+  /// The thread switched away from, numbered in spawn order with
+  /// the closure of the model as 0.
+  pub fn preempted(&self) -> usize {
+    self.preemption.preempted.0
+  }
+
+  /// The operation the preempted thread was about to do, `None`
+  /// if it hadn't started yet.
+  pub fn preempted_at(
+    &self,
+  ) -> Option<&'static Location<'static>> {
+    self.preemption.preempted.1
+  }
+
+  /// The thread switched to.
+  pub fn by(&self) -> usize {
+    self.preemption.by.0
+  }
+
+  /// The operation the thread switched to went on with.
+  pub fn by_at(&self) -> Option<&'static Location<'static>> {
+    self.preemption.by.1
+  }
+
+  /// The index of the preempting pick in the failing schedule.
+  pub fn decision(&self) -> usize {
+    self.preemption.decision
+  }
+
+  /// The failing schedule without the preemption, which passes.
+  pub fn passing(&self) -> &explore::Schedule {
+    &self.passing
+  }
+}
+
+impl fmt::Display for CriticalRace {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let site = |(thread, site): (usize, Option<Site>)| match site
+    {
+      Some(site) => site.to_string(),
+      None => format!("the start of thread {thread}"),
+    };
+    let Preemption { preempted, by, decision, .. } =
+      self.preemption;
+    writeln!(
+      f,
+      "the critical race is between {} and {}",
+      site(preempted),
+      site(by)
+    )?;
+    write!(
+      f,
+      "thread {} was about to go on when decision {decision} \
+      switched to thread {}, letting it go on passes: {}",
+      preempted.0, by.0, self.passing
+    )
+  }
+}
+
+/// [`run`], with each weak memory execution starting from
+/// `memory()`.
+pub(crate) fn run_in<F>(
+  config: explore::Config,
+  memory: impl Fn() -> Arc<Memory>,
+  f: F,
+) where
+  F: Fn() + Sync + Send + 'static,
+{
+  if let Some(coverage) = explore(config, memory, None, f) {
+    eprint!("{coverage}");
+  }
+}
+
+/// [`run_in`], returning the coverage if `config` asks for it,
+/// and recording the preemptions of the last iteration into
+/// `preemptions`.
+fn explore<F>(
+  config: explore::Config,
+  memory: impl Fn() -> Arc<Memory>,
+  preemptions: Option<Arc<Mutex<Vec<Preemption>>>>,
+  f: F,
+) -> Option<Coverage>
+where
+  F: Fn() + Sync + Send + 'static,
+{
+  let f = Arc::new(f);
+  let coverage = config.get_coverage().then(Arc::default);
+  let weak_memory = config.memory_model() != MemoryModel::SeqCst;
+  let spurious_wakeups = config.get_spurious_wakeups();
+  let spurious_cas_failures = config.get_spurious_cas_failures();
+  let alloc_failures = config.get_alloc_failures();
+  let io_errors = config.get_io_errors();
+  let channel_disconnects = config.get_channel_disconnects();
+  let thread_crashes = config.get_thread_crashes();
+  let priorities = config.get_priorities();
+  let inject_yields = config.get_inject_yields();
+  config.run(|g| {
+    let registry = Arc::new(Registry {
+      memory: weak_memory.then(&memory),
+      spurious_wakeups,
+      spurious_cas_failures,
+      alloc_failures,
+      io_errors,
+      channel_disconnects,
+      crash: AtomicBool::new(thread_crashes),
+      inject_yields,
+      iteration: ITERATIONS.fetch_add(1, SeqCst),
+      coverage: coverage.clone(),
+      preemptions: preemptions.clone(),
+      ..Default::default()
+    });
+    if let Some(preemptions) = &preemptions {
+      preemptions.lock().unwrap().clear();
+    }
+    if let Some(coverage) = &coverage {
+      let mut coverage = coverage.lock().unwrap();
+      coverage.iterations += 1;
+      coverage.at.clear();
+    }
+    let _guard = DetachOnDrop(Arc::clone(&registry));
+    Registry::set(Some(Arc::clone(&registry)));
+    let main = thread::spawn({
+      let f = Arc::clone(&f);
+      move || f()
+    });
+    Registry::set(None);
+
+    let mut previous = None;
+    for step in 0.. {
+      assert!(
+        !priorities || step < STARVATION_STEPS,
+        "starvation: no end after {STARVATION_STEPS} steps"
+      );
+      let threads = registry.threads.lock().unwrap().clone();
+      let mut runnable: Vec<usize> = (0..threads.len())
+        .filter(|&t| threads[t].is_runnable())
+        .collect();
+      if priorities {
+        let levels = registry.priorities.lock().unwrap();
+        let top = runnable.iter().map(|&t| levels[t]).max();
+        runnable.retain(|&t| Some(levels[t]) == top);
+      }
+      if runnable.is_empty() {
+        if registry.clock.advance() {
+          continue;
+        }
+        assert!(
+          threads.iter().all(|t| t.is_finished()),
+          "deadlock: every live thread is blocked"
+        );
+        break;
+      }
+      // Any thread may be slow, so a pending timeout may fire
+      // before the runnable threads get to go, as if it were one
+      // more thread.
+      if registry.clock.pending() {
+        runnable.push(CLOCK);
+      }
+      if let (Some(coverage), true) =
+        (&registry.coverage, runnable.len() > 1)
+      {
+        coverage.lock().unwrap().choice(&runnable);
+      }
+      let index = g.schedule().len();
+      let pick = g.pick_thread(&runnable);
+      if runnable[pick] == CLOCK {
+        if let Some(site) = registry.clock.next_sleep() {
+          registry.early_sleeps.lock().unwrap().push(site);
+        }
+        registry.clock.advance();
+        continue;
+      }
+      if let Some(preemptions) = &registry.preemptions {
+        let stay =
+          runnable.iter().position(|&it| Some(it) == previous);
+        if let Some(stay) = stay.filter(|&it| it != pick) {
+          let at = registry.at.lock().unwrap();
+          let site =
+            |t: usize| (t, at.get(t).copied().flatten());
+          preemptions.lock().unwrap().push(Preemption {
+            decision: index,
+            stay,
+            preempted: site(runnable[stay]),
+            by: site(runnable[pick]),
+          });
+        }
+      }
+      previous = Some(runnable[pick]);
+      let thread = &threads[runnable[pick]];
+      if runnable[pick] != MAIN
+        && registry.crash.load(SeqCst)
+        && g.flip()
+      {
+        registry.crash.store(false, SeqCst);
+        thread.crash();
+        continue;
+      }
+      registry.steps.fetch_add(1, SeqCst);
+      registry.spinning.lock().unwrap()[runnable[pick]]
+        .scheduled += 1;
+      let alone =
+        threads.iter().filter(|t| !t.is_finished()).count() == 1;
+      let unpause = || {
+        if alone {
+          thread.unpause_solo()
+        } else {
+          thread.unpause()
+        }
+      };
+      unpause();
+      loop {
+        let mut decision = registry.decision.lock().unwrap();
+        let Some(bound) = decision.bound.take() else { break };
+        decision.answer = Some(match decision.seed {
+          true => g.seed() as usize,
+          false => g.gen(bound),
+        });
+        drop(decision);
+        unpause();
+      }
+      // A thread which just started paused at its first
+      // operation.
+      if let Some(preemptions) = &registry.preemptions {
+        let mut preemptions = preemptions.lock().unwrap();
+        let last = preemptions.last_mut();
+        if let Some(p) = last.filter(|it| it.decision == index) {
+          let at = registry.at.lock().unwrap();
+          if p.by.1.is_none() {
+            p.by.1 = at.get(p.by.0).copied().flatten();
+          }
+        }
+      }
+    }
+    main.join().unwrap();
+  });
+  coverage.map(|it| std::mem::take(&mut *it.lock().unwrap()))
+}
+
+/// Numbers the iterations of every run, see [`sync::LazyLock`].
+static ITERATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The thread running the closure passed to [`model`].
+const MAIN: usize = 0;
+
+/// How long an iteration may run under
+/// [`explore::Config::priorities`].
+const STARVATION_STEPS: usize = 10_000;
+
+/// Stands for letting time pass in [`Choices::pick_thread`].
+///
+/// [`Choices::pick_thread`]: explore::Choices::pick_thread
+const CLOCK: usize = usize::MAX;
+
+#[derive(Default)]
+struct Registry {
+  threads: Mutex<Vec<Arc<SharedContext>>>,
+  memory: Option<Arc<Memory>>,
+  clock: Arc<Clock>,
+  decision: Mutex<Decision>,
+  spurious_wakeups: bool,
+  spurious_cas_failures: bool,
+  alloc_failures: bool,
+  io_errors: bool,
+  channel_disconnects: bool,
+  /// Whether a thread may still crash in this iteration, see
+  /// [`explore::Config::thread_crashes`].
+  crash: AtomicBool,
+  /// Indexed like `threads`, see [`thread::set_priority`].
+  priorities: Mutex<Vec<u8>>,
+  /// Threads scheduled so far, see [`hint::spin_loop`].
+  steps: AtomicUsize,
+  /// Indexed like `threads`.
+  spinning: Mutex<Vec<Spinning>>,
+  inject_yields: bool,
+  iteration: usize,
+  coverage: Option<Arc<Mutex<Coverage>>>,
+  /// Sleeps which ended while other threads could run, see
+  /// [`crate::time::suspicious_sleeps`].
+  early_sleeps: Mutex<Vec<Site>>,
+  /// See [`critical_race`].
+  preemptions: Option<Arc<Mutex<Vec<Preemption>>>>,
+  /// Indexed like `threads`, where each last paused, kept for
+  /// `preemptions`.
+  at: Mutex<Vec<Option<Site>>>,
+}
+
+type Site = &'static Location<'static>;
+
+/// The instrumented operations reached by a [`coverage`] run,
+/// listed by source location.
+///
+/// A site only becomes known once reached, so code which no
+/// iteration got to doesn't show up: compare against the report
+/// of a run which did, or look for the sites that are expected.
+#[derive(Default)]
+pub struct Coverage {
+  iterations: u64,
+  /// By file, line and column.
+  sites: BTreeMap<(&'static str, u32, u32), Reached>,
+  /// Where each thread of the current iteration last paused.
+  at: Vec<Option<Site>>,
+}
+
+/// How one site of a [`Coverage`] was reached.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Reached {
+  pub hits: u64,
+  pub iterations: u64,
+  /// Times the explorer picked which thread goes next while a
+  /// thread was paused here. Zero means the site never
+  /// interleaved with anything, so its pause points are dead
+  /// weight.
+  pub choices: u64,
+  /// The last iteration counted in `iterations`.
+  last: u64,
+}
+
+impl Coverage {
+  pub fn iterations(&self) -> u64 {
+    self.iterations
+  }
+
+  /// How `site`, like `src/lib.rs:12:5`, was reached, if at all.
+  pub fn get(&self, site: &str) -> Option<Reached> {
+    self
+      .sites
+      .iter()
+      .find(|((file, line, column), _)| {
+        format!("{file}:{line}:{column}") == site
+      })
+      .map(|(_, reached)| *reached)
+  }
+
+  /// Every site reached, like `src/lib.rs:12:5`, in source
+  /// order.
+  pub fn sites(
+    &self,
+  ) -> impl Iterator<Item = (String, Reached)> + '_ {
+    self.sites.iter().map(|((file, line, column), reached)| {
+      (format!("{file}:{line}:{column}"), *reached)
+    })
+  }
+
+  /// Sites which were reached, but never interleaved with
+  /// anything.
+  pub fn never_interleaved(&self) -> Vec<String> {
+    self
+      .sites
+      .iter()
+      .filter(|(_, reached)| reached.choices == 0)
+      .map(|((file, line, column), _)| {
+        format!("{file}:{line}:{column}")
+      })
+      .collect()
+  }
+
+  fn reach(&mut self, thread: usize, site: Site) {
+    let key = (site.file(), site.line(), site.column());
+    let reached = self.sites.entry(key).or_default();
+    reached.hits += 1;
+    if reached.last != self.iterations {
+      reached.last = self.iterations;
+      reached.iterations += 1;
+    }
+    if self.at.len() <= thread {
+      self.at.resize(thread + 1, None);
+    }
+    self.at[thread] = Some(site);
+  }
+
+  fn choice(&mut self, runnable: &[usize]) {
+    for &thread in runnable {
+      let Some(Some(site)) = self.at.get(thread).copied() else {
+        continue;
+      };
+      let key = (site.file(), site.line(), site.column());
+      self.sites.get_mut(&key).unwrap().choices += 1;
+    }
+  }
+}
+
+impl fmt::Display for Coverage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "coverage over {} iterations:",
+      self.iterations
+    )?;
+    for ((file, line, column), reached) in &self.sites {
+      let Reached { hits, iterations, choices, .. } = reached;
+      write!(
+        f,
+        "  {file}:{line}:{column}: {hits} hits in {iterations} \
+        iterations, {choices} choices"
+      )?;
+      if *choices == 0 {
+        write!(f, ", never interleaved")?;
+      }
+      writeln!(f)?;
+    }
+    Ok(())
+  }
+}
+
+/// Records an instrumented operation at `site`, if its thread is
+/// under [`coverage`] or [`critical_race`].
+pub(crate) fn reached(site: Site) {
+  let Some(registry) = Registry::get() else { return };
+  if registry.coverage.is_none()
+    && registry.preemptions.is_none()
+  {
+    return;
+  }
+  let thread = thread::CURRENT
+    .with(|it| it.borrow().as_ref().map(|it| it.id));
+  let Some(thread) = thread else { return };
+  if let Some(coverage) = &registry.coverage {
+    coverage.lock().unwrap().reach(thread, site)
+  }
+  if registry.preemptions.is_some() {
+    let mut at = registry.at.lock().unwrap();
+    if at.len() <= thread {
+      at.resize(thread + 1, None);
+    }
+    at[thread] = Some(site);
+  }
+}
+
+/// How many of the steps so far were someone else's, as far as a
+/// thread's [`hint::spin_loop`] is concerned.
+#[derive(Clone, Copy, Default)]
+struct Spinning {
+  scheduled: usize,
+  /// Steps by other threads before its last spin ended.
+  others_seen: usize,
+}
+
+/// The faults a thread may still suffer in this iteration, one
+/// of each kind.
+pub(crate) struct Faults {
+  /// See [`explore::Config::spurious_wakeups`], for `park`.
+  park: AtomicBool,
+  /// Likewise, for `Condvar` waits.
+  condvar: AtomicBool,
+  /// See [`explore::Config::spurious_cas_failures`].
+  cas: AtomicBool,
+  /// See [`explore::Config::alloc_failures`].
+  alloc: AtomicBool,
+  /// See [`explore::Config::io_errors`].
+  io: AtomicBool,
+  /// See [`explore::Config::channel_disconnects`].
+  disconnect: AtomicBool,
+}
+
+impl Registry {
+  fn faults(&self) -> Faults {
+    Faults {
+      park: AtomicBool::new(self.spurious_wakeups),
+      condvar: AtomicBool::new(self.spurious_wakeups),
+      cas: AtomicBool::new(self.spurious_cas_failures),
+      alloc: AtomicBool::new(self.alloc_failures),
+      io: AtomicBool::new(self.io_errors),
+      disconnect: AtomicBool::new(self.channel_disconnects),
+    }
+  }
+}
+
+/// A question from a thread paused in the middle of an
+/// operation, see [`choose`].
+#[derive(Default)]
+struct Decision {
+  bound: Option<usize>,
+  /// Asks for [`explore::Choices::seed`] rather than a value up
+  /// to `bound`.
+  seed: bool,
+  answer: Option<usize>,
+}
+
+/// Asks the explorer for a value in `0..=bound` on behalf of the
+/// current thread, like which store a weak load reads. Returns 0
+/// outside of `model`, or if the controller lets go of the
+/// thread.
+pub(crate) fn choose(bound: usize) -> usize {
+  ask(bound, false)
+}
+
+/// The next number of the current thread's generator, which the
+/// explorer seeds on first use. `None` outside of `model`.
+pub(crate) fn random() -> Option<u64> {
+  let rng = thread::CURRENT.with(|it| {
+    it.borrow().as_ref().map(|it| Arc::clone(&it.rng))
+  })?;
+  let state = *rng.lock().unwrap();
+  let mut state = explore::Rng(match state {
+    Some(state) => state,
+    None => ask(usize::MAX, true) as u64,
+  });
+  let value = state.next();
+  *rng.lock().unwrap() = Some(state.0);
+  Some(value)
+}
+
+fn ask(bound: usize, seed: bool) -> usize {
+  let Some(registry) = Registry::get() else { return 0 };
+  *registry.decision.lock().unwrap() =
+    Decision { bound: Some(bound), seed, answer: None };
+  managed_thread::end_solo();
+  managed_thread::pause();
+  let mut decision = registry.decision.lock().unwrap();
+  decision.bound = None;
+  decision.answer.take().unwrap_or(0).min(bound)
+}
+
+/// A scheduling point if [`explore::Config::inject_yields`] is
+/// on.
+pub(crate) fn injected_yield() {
+  if Registry::get().is_some_and(|it| it.inject_yields) {
+    managed_thread::pause()
+  }
+}
+
+/// Whether the current `compare_exchange_weak` fails regardless
+/// of the value.
+pub(crate) fn spurious_cas_failure() -> bool {
+  fault(|it| &it.cas)
+}
+
+/// Whether the current allocation fails, see [`crate::alloc`].
+pub(crate) fn alloc_failure() -> bool {
+  fault(|it| &it.alloc)
+}
+
+/// Whether the current [`crate::io`] operation fails.
+pub(crate) fn io_failure() -> bool {
+  fault(|it| &it.io)
+}
+
+/// Whether the current [`sync::mpsc`] operation sees the other
+/// side hung up regardless.
+fn channel_disconnect() -> bool {
+  fault(|it| &it.disconnect)
+}
+
+/// Lets the explorer decide whether the current thread suffers
+/// the fault `kind` now, if it still may.
+fn fault(kind: fn(&Faults) -> &AtomicBool) -> bool {
+  let faults = thread::CURRENT.with(|it| {
+    it.borrow().as_ref().map(|it| Arc::clone(&it.faults))
+  });
+  match faults {
+    Some(faults) if kind(&faults).load(SeqCst) => {
+      let fail = choose(1) == 1;
+      kind(&faults).store(!fail, SeqCst);
+      fail
+    }
+    _ => false,
+  }
+}
+
+thread_local! {
+  static REGISTRY: RefCell<Option<Arc<Registry>>> =
+    const { RefCell::new(None) };
+}
+
+impl Registry {
+  fn set(registry: Option<Arc<Registry>>) {
+    REGISTRY.with(|it| *it.borrow_mut() = registry);
+  }
+
+  fn get() -> Option<Arc<Registry>> {
+    REGISTRY.with(|it| it.borrow().clone())
+  }
+}
+
+/// Whether the current thread is one of a [`model`], see
+/// [`crate::thread`].
+pub(crate) fn active() -> bool {
+  Registry::get().is_some()
+}
+
+/// The virtual clock of the current iteration, see
+/// [`crate::time`].
+pub(crate) fn clock() -> Option<Arc<Clock>> {
+  Registry::get().map(|it| Arc::clone(&it.clock))
+}
+
+/// If the controller panics, lets the remaining threads run to
+/// completion rather than leaving them paused forever.
+struct DetachOnDrop(Arc<Registry>);
+
+impl Drop for DetachOnDrop {
+  fn drop(&mut self) {
+    if std::thread::panicking() {
+      for &site in self.0.early_sleeps.lock().unwrap().iter() {
+        crate::time::suspect(site)
+      }
+    }
+    for t in self.0.threads.lock().unwrap().iter() {
+      t.detach()
+    }
+  }
+}
+
+pub mod thread {
+  use std::{
+    cell::RefCell,
+    fmt,
+    sync::{
+      atomic::{AtomicBool, Ordering::SeqCst},
+      Arc, Mutex,
+    },
+    time::Duration,
+  };
+
+  use super::{Faults, Registry, Spinning};
+  use crate::{
+    managed_thread::{self, SharedContext},
+    memory,
+  };
+
+  pub struct JoinHandle<T> {
+    inner: managed_thread::UnscopedHandle<T>,
+    ctx: Arc<SharedContext>,
+    memory: Option<memory::Thread>,
+    thread: Thread,
+  }
+
+  /// A handle to [`Thread::unpark`] a managed thread.
+  #[derive(Clone)]
+  pub struct Thread {
+    pub(super) id: usize,
+    token: Arc<AtomicBool>,
+    pub(super) faults: Arc<Faults>,
+    /// The state of [`crate::rand`], once seeded.
+    pub(super) rng: Arc<Mutex<Option<u64>>>,
+  }
+
+  thread_local! {
+    pub(super) static CURRENT: RefCell<Option<Thread>> =
+      const { RefCell::new(None) };
+  }
+
+  pub fn current() -> Thread {
+    CURRENT
+      .with(|it| it.borrow().clone())
+      .expect("current called outside of `model`")
+  }
+
+  impl Thread {
+    pub fn unpark(&self) {
+      managed_thread::pause();
+      self.token.store(true, SeqCst)
+    }
+  }
+
+  /// Blocks until the token from [`Thread::unpark`] is
+  /// available, and consumes it.
+  pub fn park() {
+    let Thread { token, .. } = current();
+    if super::fault(|it| &it.park) {
+      managed_thread::pause();
+      token.store(false, SeqCst);
+      return;
+    }
+    managed_thread::block_until(Arc::new({
+      let token = Arc::clone(&token);
+      move || token.load(SeqCst)
+    }));
+    token.store(false, SeqCst)
+  }
+
+  /// [`park`], or until `timeout` passes on the virtual clock of
+  /// [`crate::time`]. Both outcomes are explored when the unpark
+  /// and the timeout race.
+  pub fn park_timeout(timeout: Duration) {
+    let Thread { token, .. } = current();
+    if super::fault(|it| &it.park) {
+      managed_thread::pause();
+      token.store(false, SeqCst);
+      return;
+    }
+    let clock = super::clock().unwrap();
+    clock.wait(timeout, {
+      let token = Arc::clone(&token);
+      move || token.load(SeqCst)
+    });
+    token.store(false, SeqCst)
+  }
+
+  pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+  where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+  {
+    let registry =
+      Registry::get().expect("spawn called outside of `model`");
+    let memory = registry
+      .memory
+      .as_ref()
+      .map(|it| it.spawn(memory::Thread::current().as_ref()));
+    // The parent is no longer alone.
+    managed_thread::end_solo();
+    let thread = Thread {
+      id: registry.threads.lock().unwrap().len(),
+      token: Arc::default(),
+      faults: Arc::new(registry.faults()),
+      rng: Arc::default(),
+    };
+    let (ctx, inner) = managed_thread::spawn_unscoped({
+      let registry = Arc::clone(&registry);
+      let memory = memory.clone();
+      let thread = thread.clone();
+      move || {
+        Registry::set(Some(registry));
+        CURRENT.with(|it| *it.borrow_mut() = Some(thread));
+        let _reset = Reset;
+        if let Some(memory) = memory {
+          memory.enter();
+        }
+        f()
+      }
+    });
+    registry.threads.lock().unwrap().push(Arc::clone(&ctx));
+    registry.priorities.lock().unwrap().push(0);
+    registry.spinning.lock().unwrap().push(Spinning::default());
+    JoinHandle { inner, ctx, memory, thread }
+  }
+
+  /// Clears the thread-locals of a finished thread, as its OS
+  /// thread is reused.
+  struct Reset;
+
+  impl Drop for Reset {
+    fn drop(&mut self) {
+      Registry::set(None);
+      CURRENT.with(|it| *it.borrow_mut() = None);
+      memory::Thread::exit();
+    }
+  }
+
+  impl fmt::Debug for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("Thread")
+        .field("id", &self.id)
+        .field("unparked", &self.token.load(SeqCst))
+        .finish_non_exhaustive()
+    }
+  }
+
+  impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("JoinHandle")
+        .field("thread", &self.thread)
+        .field("finished", &self.ctx.is_finished())
+        .finish_non_exhaustive()
+    }
+  }
+
+  impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Thread {
+      &self.thread
+    }
+
+    pub fn join(self) -> std::thread::Result<T> {
+      let ctx = Arc::clone(&self.ctx);
+      managed_thread::block_until(Arc::new(move || {
+        ctx.is_finished()
+      }));
+      if let (Some(me), Some(child)) =
+        (memory::Thread::current(), &self.memory)
+      {
+        me.join(child);
+      }
+      self.inner.join()
+    }
+  }
+
+  /// Sets the priority of the current thread, 0 by default and
+  /// for spawned threads. Only matters under
+  /// [`crate::explore::Config::priorities`], higher runs first.
+  pub fn set_priority(priority: u8) {
+    let registry = Registry::get()
+      .expect("set_priority called outside of `model`");
+    managed_thread::pause();
+    registry.priorities.lock().unwrap()[current().id] = priority;
+  }
+
+  pub fn yield_now() {
+    managed_thread::pause()
+  }
+}
+
+pub mod hint {
+  use std::sync::{atomic::Ordering::SeqCst, Arc};
+
+  use super::{Registry, Spinning};
+  use crate::{managed_thread, memory};
+
+  /// Blocks until some other thread has taken a step since the
+  /// last spin, as spinning any sooner can't see anything new. A
+  /// spin loop thus can't starve the thread it waits for, and
+  /// one waiting for nobody is a deadlock.
+  ///
+  /// Under weak memory, the thread first gets to see every store
+  /// made so far, like it would by spinning long enough, and
+  /// retries right away if there were any it couldn't.
+  pub fn spin_loop() {
+    let Some(registry) = Registry::get() else {
+      return std::hint::spin_loop();
+    };
+    if memory::Thread::current().is_some_and(|it| it.catch_up())
+    {
+      return managed_thread::pause();
+    }
+    let me = super::thread::current().id;
+    let Spinning { scheduled, others_seen } =
+      registry.spinning.lock().unwrap()[me];
+    // Blocked, the thread isn't scheduled, so only others step.
+    managed_thread::block_until(Arc::new({
+      let registry = Arc::clone(&registry);
+      move || {
+        registry.steps.load(SeqCst) > scheduled + others_seen
+      }
+    }));
+    let mut spinning = registry.spinning.lock().unwrap();
+    let steps = registry.steps.load(SeqCst);
+    spinning[me].others_seen = steps - spinning[me].scheduled;
+  }
+}
+
+pub mod sync {
+  use std::{
+    cell::{Cell, UnsafeCell},
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{
+      atomic::{
+        AtomicBool,
+        Ordering::{Acquire, Relaxed, Release, SeqCst},
+      },
+      LockResult,
+    },
+    time::Duration,
+  };
+
+  use super::{hint, thread, Registry};
+  use crate::{
+    managed_thread::{self, AtomicUsize},
+    memory,
+  };
+
+  /// `std::sync::Arc`, whose weak pointers are scheduling
+  /// points: so are [`Arc::downgrade`] and [`Weak::upgrade`],
+  /// and giving up the last strong pointer while weak ones are
+  /// around. Other uses run as they do in std, not to grow the
+  /// state space of every test.
+  ///
+  /// Not being std's, it doesn't coerce to `Arc<dyn Trait>`:
+  /// make one of a `Box<dyn Trait>` with [`From`] instead.
+  #[derive(Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+  pub struct Arc<T: ?Sized>(std::sync::Arc<T>);
+
+  impl<T> Arc<T> {
+    pub fn new(value: T) -> Arc<T> {
+      Arc(std::sync::Arc::new(value))
+    }
+
+    pub fn try_unwrap(this: Arc<T>) -> Result<T, Arc<T>> {
+      this.pause_if_last();
+      std::sync::Arc::try_unwrap(this.into_std()).map_err(Arc)
+    }
+
+    pub fn into_inner(this: Arc<T>) -> Option<T> {
+      this.pause_if_last();
+      std::sync::Arc::into_inner(this.into_std())
+    }
+  }
+
+  impl<T: ?Sized> Arc<T> {
+    pub fn downgrade(this: &Arc<T>) -> Weak<T> {
+      managed_thread::pause();
+      Weak(std::sync::Arc::downgrade(&this.0))
+    }
+
+    pub fn strong_count(this: &Arc<T>) -> usize {
+      std::sync::Arc::strong_count(&this.0)
+    }
+
+    pub fn weak_count(this: &Arc<T>) -> usize {
+      std::sync::Arc::weak_count(&this.0)
+    }
+
+    pub fn ptr_eq(this: &Arc<T>, other: &Arc<T>) -> bool {
+      std::sync::Arc::ptr_eq(&this.0, &other.0)
+    }
+
+    pub fn get_mut(this: &mut Arc<T>) -> Option<&mut T> {
+      std::sync::Arc::get_mut(&mut this.0)
+    }
+
+    pub fn as_ptr(this: &Arc<T>) -> *const T {
+      std::sync::Arc::as_ptr(&this.0)
+    }
+
+    /// Which of the last strong drop and an upgrade goes first.
+    fn pause_if_last(&self) {
+      if std::sync::Arc::strong_count(&self.0) == 1
+        && std::sync::Arc::weak_count(&self.0) > 0
+      {
+        managed_thread::pause()
+      }
+    }
+
+    /// The std `Arc`, without going through [`Drop`].
+    fn into_std(self) -> std::sync::Arc<T> {
+      let this = std::mem::ManuallyDrop::new(self);
+      // SAFETY: `this` is never used or dropped again.
+      unsafe { std::ptr::read(&this.0) }
+    }
+  }
+
+  impl<T: Clone> Arc<T> {
+    pub fn make_mut(this: &mut Arc<T>) -> &mut T {
+      std::sync::Arc::make_mut(&mut this.0)
+    }
+  }
+
+  impl<T> From<T> for Arc<T> {
+    fn from(value: T) -> Arc<T> {
+      Arc::new(value)
+    }
+  }
+
+  impl<T: ?Sized> From<Box<T>> for Arc<T> {
+    fn from(value: Box<T>) -> Arc<T> {
+      Arc(value.into())
+    }
+  }
+
+  impl<T: ?Sized> From<std::sync::Arc<T>> for Arc<T> {
+    fn from(arc: std::sync::Arc<T>) -> Arc<T> {
+      Arc(arc)
+    }
+  }
+
+  impl<T: ?Sized> AsRef<T> for Arc<T> {
+    fn as_ref(&self) -> &T {
+      &self.0
+    }
+  }
+
+  impl<T: ?Sized> std::borrow::Borrow<T> for Arc<T> {
+    fn borrow(&self) -> &T {
+      &self.0
+    }
+  }
+
+  impl<T: ?Sized> Clone for Arc<T> {
+    fn clone(&self) -> Arc<T> {
+      Arc(std::sync::Arc::clone(&self.0))
+    }
+  }
+
+  impl<T: ?Sized> Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+      &self.0
+    }
+  }
+
+  impl<T: ?Sized> Drop for Arc<T> {
+    fn drop(&mut self) {
+      self.pause_if_last()
+    }
+  }
+
+  impl<T: ?Sized + fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      fmt::Debug::fmt(&self.0, f)
+    }
+  }
+
+  impl<T: ?Sized + fmt::Display> fmt::Display for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      fmt::Display::fmt(&self.0, f)
+    }
+  }
+
+  impl<T: ?Sized> fmt::Pointer for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      fmt::Pointer::fmt(&self.0, f)
+    }
+  }
+
+  /// `std::sync::Weak`, see [`Arc`].
+  pub struct Weak<T: ?Sized>(std::sync::Weak<T>);
+
+  impl<T> Weak<T> {
+    pub fn new() -> Weak<T> {
+      Weak(std::sync::Weak::new())
+    }
+  }
+
+  impl<T> Default for Weak<T> {
+    fn default() -> Weak<T> {
+      Weak::new()
+    }
+  }
+
+  impl<T: ?Sized> Weak<T> {
+    pub fn upgrade(&self) -> Option<Arc<T>> {
+      managed_thread::pause();
+      self.0.upgrade().map(Arc)
+    }
+
+    pub fn strong_count(&self) -> usize {
+      self.0.strong_count()
+    }
+
+    pub fn weak_count(&self) -> usize {
+      self.0.weak_count()
+    }
+  }
+
+  impl<T: ?Sized> Clone for Weak<T> {
+    fn clone(&self) -> Weak<T> {
+      Weak(self.0.clone())
+    }
+  }
+
+  impl<T: ?Sized> fmt::Debug for Weak<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.write_str("(Weak)")
+    }
+  }
+
+  /// The owner of a lock, as a thread id plus one, or zero.
+  struct RawLock {
+    owner: AtomicUsize,
+  }
+
+  impl RawLock {
+    fn new() -> RawLock {
+      RawLock { owner: AtomicUsize::new(0) }
+    }
+
+    fn me() -> usize {
+      thread::current().id + 1
+    }
+
+    fn is_held_by_me(&self) -> bool {
+      // A thread always sees its own latest store, stale or not.
+      self.owner.load(Relaxed) == RawLock::me()
+    }
+
+    fn lock(self: &std::sync::Arc<RawLock>) {
+      let me = RawLock::me();
+      // A thread crashing between taking the lock and making the
+      // guard would hold the lock forever.
+      while let Err(owner) =
+        managed_thread::deferring_crashes(|| {
+          self.owner.compare_exchange(0, me, Acquire, Relaxed)
+        })
+      {
+        assert!(
+          owner != me,
+          "deadlock: thread {} locks a mutex it holds",
+          me - 1
+        );
+        managed_thread::block_until(std::sync::Arc::new({
+          let raw = std::sync::Arc::clone(self);
+          move || raw.owner.latest() == 0
+        }));
+        // Or a stale owner could keep it waiting forever.
+        if let Some(thread) = memory::Thread::current() {
+          thread.catch_up();
+        }
+      }
+    }
+
+    fn unlock(&self) {
+      // Likewise, see `lock`.
+      managed_thread::deferring_crashes(|| {
+        self.owner.store(0, Release)
+      })
+    }
+  }
+
+  /// `std::sync::Mutex`, for managed threads only. Waiting for a
+  /// lock is blocking, so a cycle of waiting threads is reported
+  /// as a deadlock, as is a thread locking a mutex twice. Never
+  /// poisoned.
+  pub struct Mutex<T> {
+    raw: std::sync::Arc<RawLock>,
+    data: UnsafeCell<T>,
+  }
+
+  // SAFETY: like `std::sync::Mutex`, the lock hands out one
+  // `&mut T` at a time.
+  unsafe impl<T: Send> Send for Mutex<T> {}
+  unsafe impl<T: Send> Sync for Mutex<T> {}
+
+  impl<T> Mutex<T> {
+    pub fn new(value: T) -> Mutex<T> {
+      Mutex {
+        raw: std::sync::Arc::new(RawLock::new()),
+        data: UnsafeCell::new(value),
+      }
+    }
+
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+      self.raw.lock();
+      Ok(MutexGuard { mutex: self })
+    }
+  }
+
+  pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+  }
+
+  impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+      // SAFETY: the guard holds the lock.
+      unsafe { &*self.mutex.data.get() }
+    }
+  }
+
+  impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+      // SAFETY: the guard holds the lock.
+      unsafe { &mut *self.mutex.data.get() }
+    }
+  }
+
+  impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+      self.mutex.raw.unlock()
+    }
+  }
+
+  /// `std::sync::Condvar`, for managed threads only. Waiting
+  /// threads are blocked, so a notification nobody sends is a
+  /// deadlock.
+  ///
+  /// A timeout is on the virtual clock of [`crate::time`], and
+  /// whether it expires before a notification is up to the
+  /// explorer, as for [`thread::park_timeout`]: both are
+  /// explored when they race. Under
+  /// [`crate::explore::Config::spurious_wakeups`], one of a
+  /// thread's waits may also return without either.
+  #[derive(Default)]
+  pub struct Condvar {
+    /// A token per waiting thread, in the order they started
+    /// waiting.
+    waiters: std::sync::Mutex<Vec<std::sync::Arc<AtomicBool>>>,
+  }
+
+  /// `std::sync::WaitTimeoutResult`, which can't be made outside
+  /// of std.
+  #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+  pub struct WaitTimeoutResult(bool);
+
+  impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+      self.0
+    }
+  }
+
+  impl Condvar {
+    pub fn new() -> Condvar {
+      Condvar::default()
+    }
+
+    pub fn wait<'a, T>(
+      &self,
+      guard: MutexGuard<'a, T>,
+    ) -> LockResult<MutexGuard<'a, T>> {
+      let (guard, _) = self.wait_for(guard, None);
+      Ok(guard)
+    }
+
+    pub fn wait_timeout<'a, T>(
+      &self,
+      guard: MutexGuard<'a, T>,
+      timeout: Duration,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)> {
+      let (guard, timed_out) =
+        self.wait_for(guard, Some(timeout));
+      Ok((guard, WaitTimeoutResult(timed_out)))
+    }
+
+    pub fn notify_one(&self) {
+      managed_thread::pause();
+      let mut waiters = self.waiters.lock().unwrap();
+      if !waiters.is_empty() {
+        waiters.remove(0).store(true, SeqCst);
+      }
+    }
+
+    pub fn notify_all(&self) {
+      managed_thread::pause();
+      for token in self.waiters.lock().unwrap().drain(..) {
+        token.store(true, SeqCst);
+      }
+    }
+
+    /// Returns whether it timed out.
+    fn wait_for<'a, T>(
+      &self,
+      guard: MutexGuard<'a, T>,
+      timeout: Option<Duration>,
+    ) -> (MutexGuard<'a, T>, bool) {
+      let mutex = guard.mutex;
+      // Unlocked by hand, and relocked by a new guard.
+      std::mem::forget(guard);
+      if super::fault(|it| &it.condvar) {
+        mutex.raw.unlock();
+        managed_thread::pause();
+        mutex.raw.lock();
+        return (MutexGuard { mutex }, false);
+      }
+      let token = std::sync::Arc::new(AtomicBool::new(false));
+      self
+        .waiters
+        .lock()
+        .unwrap()
+        .push(std::sync::Arc::clone(&token));
+      mutex.raw.unlock();
+      let notified = {
+        let token = std::sync::Arc::clone(&token);
+        move || token.load(SeqCst)
+      };
+      match timeout {
+        Some(timeout) => {
+          super::clock().unwrap().wait(timeout, notified)
+        }
+        None => managed_thread::block_until(
+          std::sync::Arc::new(notified),
+        ),
+      }
+      // Under the lock, so a notification either woke this
+      // thread or goes to another one.
+      let mut waiters = self.waiters.lock().unwrap();
+      let timed_out = !token.load(SeqCst);
+      waiters.retain(|it| !std::sync::Arc::ptr_eq(it, &token));
+      drop(waiters);
+      mutex.raw.lock();
+      (MutexGuard { mutex }, timed_out)
+    }
+  }
+
+  /// A [`Mutex`] which the thread holding it may lock again,
+  /// handing out shared references only.
+  pub struct ReentrantMutex<T> {
+    raw: std::sync::Arc<RawLock>,
+    /// Only touched by the owner.
+    depth: Cell<usize>,
+    data: T,
+  }
+
+  // SAFETY: `depth` is only touched by the thread holding the
+  // lock, and `data` is only shared between the guards of that
+  // thread.
+  unsafe impl<T: Send> Send for ReentrantMutex<T> {}
+  unsafe impl<T: Send> Sync for ReentrantMutex<T> {}
+
+  impl<T> ReentrantMutex<T> {
+    pub fn new(value: T) -> ReentrantMutex<T> {
+      ReentrantMutex {
+        raw: std::sync::Arc::new(RawLock::new()),
+        depth: Cell::new(0),
+        data: value,
+      }
+    }
+
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+      if !self.raw.is_held_by_me() {
+        self.raw.lock();
+      }
+      self.depth.set(self.depth.get() + 1);
+      ReentrantMutexGuard { mutex: self }
+    }
+  }
+
+  pub struct ReentrantMutexGuard<'a, T> {
+    mutex: &'a ReentrantMutex<T>,
+  }
+
+  impl<T> Deref for ReentrantMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+      &self.mutex.data
+    }
+  }
+
+  impl<T> Drop for ReentrantMutexGuard<'_, T> {
+    fn drop(&mut self) {
+      let depth = self.mutex.depth.get() - 1;
+      self.mutex.depth.set(depth);
+      if depth == 0 {
+        self.mutex.raw.unlock()
+      }
+    }
+  }
+
+  /// `std::sync::LazyLock`, initialized afresh in every
+  /// iteration.
+  ///
+  /// Threads which find the value missing race to initialize it,
+  /// the first one runs `init` and the others spin until it's
+  /// done, all of which is explored. Like loom's `lazy_static!`,
+  /// the value is never dropped: it is leaked, as a reference to
+  /// a static may outlive its iteration. Outside of `model`, it
+  /// is `std`'s.
+  pub struct LazyLock<T: 'static, F = fn() -> T> {
+    init: F,
+    /// The iteration it was initialized in, `None` while running
+    /// `init`.
+    value: std::sync::Mutex<Option<(usize, Option<&'static T>)>>,
+    unmanaged: std::sync::OnceLock<T>,
+  }
+
+  impl<T, F: Fn() -> T> LazyLock<T, F> {
+    pub const fn new(init: F) -> LazyLock<T, F> {
+      LazyLock {
+        init,
+        value: std::sync::Mutex::new(None),
+        unmanaged: std::sync::OnceLock::new(),
+      }
+    }
+
+    pub fn force(this: &LazyLock<T, F>) -> &T {
+      let Some(registry) = Registry::get() else {
+        return this.unmanaged.get_or_init(&this.init);
+      };
+      managed_thread::pause();
+      loop {
+        let mut value = this.value.lock().unwrap();
+        match *value {
+          Some((it, Some(value)))
+            if it == registry.iteration =>
+          {
+            return value
+          }
+          Some((it, None)) if it == registry.iteration => {
+            drop(value);
+            hint::spin_loop()
+          }
+          _ => {
+            *value = Some((registry.iteration, None));
+            drop(value);
+            let init = Box::leak(Box::new((this.init)()));
+            *this.value.lock().unwrap() =
+              Some((registry.iteration, Some(init)));
+            return init;
+          }
+        }
+      }
+    }
+  }
+
+  impl<T, F: Fn() -> T> Deref for LazyLock<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+      LazyLock::force(self)
+    }
+  }
+
+  /// `std::sync::mpsc`'s unbounded channel. Every operation is a
+  /// scheduling point, so whether a message is there yet when
+  /// [`mpsc::Receiver::try_recv`] looks is explored.
+  /// [`mpsc::Receiver::recv_timeout`] waits on the virtual clock
+  /// of [`crate::time`], so both the timeout and the message
+  /// winning are explored too. A message carries what its sender
+  /// did before it to the receiver, as with std.
+  /// [`crate::explore::Config::channel_disconnects`] makes a
+  /// peer seem gone early.
+  pub mod mpsc {
+    use std::{
+      collections::VecDeque,
+      sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+        Arc, Mutex,
+      },
+      time::Duration,
+    };
+
+    pub use std::sync::mpsc::{
+      RecvError, RecvTimeoutError, SendError, TryRecvError,
+    };
+
+    use crate::{managed_thread, memory};
+
+    struct Channel<T> {
+      queue: Mutex<VecDeque<(T, Option<memory::Released>)>>,
+      state: Arc<State>,
+    }
+
+    /// What a blocked receiver waits on, apart from the
+    /// messages.
+    struct State {
+      len: AtomicUsize,
+      senders: AtomicUsize,
+      receiver: AtomicBool,
+    }
+
+    impl State {
+      fn ready(&self) -> bool {
+        self.len.load(SeqCst) > 0
+          || self.senders.load(SeqCst) == 0
+      }
+    }
+
+    pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+      let state = State {
+        len: AtomicUsize::new(0),
+        senders: AtomicUsize::new(1),
+        receiver: AtomicBool::new(true),
+      };
+      let channel = Arc::new(Channel {
+        queue: Mutex::new(VecDeque::new()),
+        state: Arc::new(state),
+      });
+      (
+        Sender { channel: Arc::clone(&channel) },
+        Receiver { channel },
+      )
+    }
+
+    pub struct Sender<T> {
+      channel: Arc<Channel<T>>,
+    }
+
+    impl<T> Sender<T> {
+      pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        managed_thread::pause();
+        let state = &self.channel.state;
+        if !state.receiver.load(SeqCst)
+          || super::super::channel_disconnect()
+        {
+          return Err(SendError(value));
+        }
+        let released =
+          memory::Thread::current().map(|it| it.release());
+        let mut queue = self.channel.queue.lock().unwrap();
+        queue.push_back((value, released));
+        state.len.store(queue.len(), SeqCst);
+        Ok(())
+      }
+    }
+
+    impl<T> Clone for Sender<T> {
+      fn clone(&self) -> Sender<T> {
+        self.channel.state.senders.fetch_add(1, SeqCst);
+        Sender { channel: Arc::clone(&self.channel) }
+      }
+    }
+
+    /// Hanging up is a scheduling point, as a receiver can tell.
+    impl<T> Drop for Sender<T> {
+      fn drop(&mut self) {
+        managed_thread::pause();
+        self.channel.state.senders.fetch_sub(1, SeqCst);
+      }
+    }
+
+    pub struct Receiver<T> {
+      channel: Arc<Channel<T>>,
+    }
+
+    impl<T> Receiver<T> {
+      pub fn recv(&self) -> Result<T, RecvError> {
+        managed_thread::pause();
+        let state = Arc::clone(&self.channel.state);
+        managed_thread::block_until(Arc::new(move || {
+          state.ready()
+        }));
+        self.take().ok_or(RecvError)
+      }
+
+      pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        managed_thread::pause();
+        if super::super::channel_disconnect() {
+          return Err(TryRecvError::Disconnected);
+        }
+        match self.take() {
+          Some(value) => Ok(value),
+          None if self.disconnected() => {
+            Err(TryRecvError::Disconnected)
+          }
+          None => Err(TryRecvError::Empty),
+        }
+      }
+
+      pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+      ) -> Result<T, RecvTimeoutError> {
+        managed_thread::pause();
+        let state = Arc::clone(&self.channel.state);
+        super::super::clock()
+          .unwrap()
+          .wait(timeout, move || state.ready());
+        if super::super::channel_disconnect() {
+          return Err(RecvTimeoutError::Disconnected);
+        }
+        match self.take() {
+          Some(value) => Ok(value),
+          None if self.disconnected() => {
+            Err(RecvTimeoutError::Disconnected)
+          }
+          None => Err(RecvTimeoutError::Timeout),
+        }
+      }
+
+      fn disconnected(&self) -> bool {
+        self.channel.state.senders.load(SeqCst) == 0
+      }
+
+      fn take(&self) -> Option<T> {
+        let mut queue = self.channel.queue.lock().unwrap();
+        let (value, released) = queue.pop_front()?;
+        self.channel.state.len.store(queue.len(), SeqCst);
+        if let (Some(thread), Some(released)) =
+          (memory::Thread::current(), released)
+        {
+          thread.acquire(&released)
+        }
+        Some(value)
+      }
+    }
+
+    impl<T> Drop for Receiver<T> {
+      fn drop(&mut self) {
+        managed_thread::pause();
+        self.channel.state.receiver.store(false, SeqCst);
+      }
+    }
+  }
+
+  pub mod atomic {
+    pub use std::sync::atomic::Ordering;
+
+    pub use crate::atomic::fence;
+
+    pub use crate::managed_thread::{
+      AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize,
+      AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize,
+    };
+  }
+}
+
+pub mod cell {
+  /// loom's closure-based `UnsafeCell`. Accesses are not checked
+  /// for races, they just run, see also
+  /// [`crate::explore::Config::inject_yields`].
+  #[derive(Default, Debug)]
+  pub struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+  impl<T> UnsafeCell<T> {
+    pub fn new(value: T) -> UnsafeCell<T> {
+      UnsafeCell(std::cell::UnsafeCell::new(value))
+    }
+
+    pub fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+      super::injected_yield();
+      f(self.0.get())
+    }
+
+    pub fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+      super::injected_yield();
+      f(self.0.get())
+    }
+  }
+}
+
+#[test]
+fn fetch_add_is_atomic() {
+  use sync::{
+    atomic::AtomicUsize, atomic::Ordering::SeqCst, Arc,
+  };
+
+  model(|| {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..2)
+      .map(|_| {
+        let counter = Arc::clone(&counter);
+        thread::spawn(move || counter.fetch_add(1, SeqCst))
+      })
+      .collect();
+    for t in threads {
+      t.join().unwrap();
+    }
+    assert_eq!(counter.load(SeqCst), 2);
+  });
+}
+
+#[test]
+#[should_panic]
+fn load_store_is_not() {
+  use sync::{
+    atomic::AtomicUsize, atomic::Ordering::SeqCst, Arc,
+  };
+
+  model(|| {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let counter = Arc::clone(&counter);
+      move || counter.store(counter.load(SeqCst) + 1, SeqCst)
+    });
+    counter.store(counter.load(SeqCst) + 1, SeqCst);
+    t.join().unwrap();
+    assert_eq!(counter.load(SeqCst), 2);
+  });
+}
+
+#[test]
+fn coverage_finds_sites_which_never_interleave() {
+  use std::sync::atomic::AtomicU32;
+  use sync::atomic::AtomicUsize;
+
+  static ALONE: AtomicU32 = AtomicU32::new(0);
+
+  let config = explore::Config::new().exhaustive();
+  let coverage = coverage(config, || {
+    let flag = Arc::new(AtomicUsize::new(0));
+    let ((), line) = (flag.store(1, SeqCst), line!());
+    ALONE.store(line, SeqCst);
+    let t = thread::spawn({
+      let flag = Arc::clone(&flag);
+      move || flag.store(2, SeqCst)
+    });
+    flag.load(SeqCst);
+    t.join().unwrap();
+  });
+  let n = coverage.iterations();
+  let alone = coverage.never_interleaved();
+  let line = ALONE.load(SeqCst);
+  assert_eq!(alone.len(), 1, "{coverage}");
+  assert!(alone[0].contains(&format!(":{line}:")), "{coverage}");
+  let site = &alone[0];
+  let reached = coverage.get(site).unwrap();
+  assert_eq!((reached.hits, reached.iterations), (n, n));
+}
+
+#[test]
+fn checkpoints_split_plain_code() {
+  use std::collections::HashSet;
+
+  static ORDERS: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+  model(|| {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let steps = |log: Arc<Mutex<Vec<u8>>>, first| {
+      log.lock().unwrap().push(first);
+      crate::checkpoint();
+      log.lock().unwrap().push(first + 1);
+    };
+    let t = thread::spawn({
+      let log = Arc::clone(&log);
+      move || steps(log, 1)
+    });
+    steps(Arc::clone(&log), 3);
+    t.join().unwrap();
+    ORDERS.lock().unwrap().push(log.lock().unwrap().clone());
+  });
+  let orders: HashSet<Vec<u8>> =
+    ORDERS.lock().unwrap().drain(..).collect();
+  assert!(orders.contains(&vec![3, 1, 4, 2]), "{orders:?}");
+}
+
+#[test]
+fn lazy_locks_reset_between_iterations() {
+  use std::sync::atomic::AtomicUsize as StdAtomicUsize;
+  use sync::{atomic::AtomicUsize, LazyLock};
+
+  static RUNS: StdAtomicUsize = StdAtomicUsize::new(0);
+  static INITS: StdAtomicUsize = StdAtomicUsize::new(0);
+  static COUNTER: LazyLock<AtomicUsize> = LazyLock::new(|| {
+    INITS.fetch_add(1, SeqCst);
+    AtomicUsize::new(0)
+  });
+
+  model(|| {
+    RUNS.fetch_add(1, SeqCst);
+    let t = thread::spawn(|| COUNTER.fetch_add(1, SeqCst));
+    COUNTER.fetch_add(1, SeqCst);
+    t.join().unwrap();
+    assert_eq!(COUNTER.load(SeqCst), 2);
+  });
+  assert_eq!(INITS.load(SeqCst), RUNS.load(SeqCst));
+}
+
+#[test]
+fn upgrades_race_the_last_drop() {
+  use std::collections::HashSet;
+
+  static UPGRADED: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+  model(|| {
+    let strong = sync::Arc::new(AtomicUsize::new(92));
+    let weak = sync::Arc::downgrade(&strong);
+    let t = thread::spawn(move || {
+      let upgraded = weak.upgrade();
+      if let Some(value) = &upgraded {
+        assert_eq!(value.load(SeqCst), 92);
+      }
+      UPGRADED.lock().unwrap().push(upgraded.is_some());
+    });
+    drop(strong);
+    t.join().unwrap();
+  });
+  let seen: HashSet<bool> =
+    UPGRADED.lock().unwrap().drain(..).collect();
+  assert_eq!(seen, HashSet::from([true, false]));
+}
+
+#[test]
+fn unwraps_race_upgrades() {
+  use std::collections::BTreeSet;
+
+  static SEEN: Mutex<BTreeSet<(bool, bool)>> =
+    Mutex::new(BTreeSet::new());
+
+  let f: sync::Arc<dyn Fn() -> u8> =
+    sync::Arc::from(Box::new(|| 92u8) as Box<dyn Fn() -> u8>);
+  assert_eq!(f(), 92);
+  assert_eq!(sync::Arc::new(92).to_string(), "92");
+
+  model(|| {
+    let strong = sync::Arc::new(92);
+    let weak = sync::Arc::downgrade(&strong);
+    let t = thread::spawn(move || {
+      let upgraded = weak.upgrade();
+      thread::yield_now();
+      upgraded.is_some()
+    });
+    let unwrapped = sync::Arc::try_unwrap(strong).is_ok();
+    let upgraded = t.join().unwrap();
+    SEEN.lock().unwrap().insert((unwrapped, upgraded));
+  });
+  // An upgrade which got in first holds on until it's dropped.
+  let seen = SEEN.lock().unwrap().clone();
+  assert_eq!(
+    seen,
+    [(false, true), (true, false), (true, true)].into()
+  );
+}
+
+#[test]
+fn mutexes_exclude() {
+  model(|| {
+    let counter = Arc::new(sync::Mutex::new(0));
+    let t = thread::spawn({
+      let counter = Arc::clone(&counter);
+      move || *counter.lock().unwrap() += 1
+    });
+    *counter.lock().unwrap() += 1;
+    t.join().unwrap();
+    assert_eq!(*counter.lock().unwrap(), 2);
+  });
+}
+
+#[test]
+#[should_panic(
+  expected = "deadlock: thread 0 locks a mutex it holds"
+)]
+fn relocking_a_mutex_deadlocks() {
+  model(|| {
+    let mutex = sync::Mutex::new(());
+    let _guard = mutex.lock().unwrap();
+    let _again = mutex.lock().unwrap();
+  });
+}
+
+#[test]
+fn reentrant_mutexes_nest() {
+  model(|| {
+    let mutex = Arc::new(sync::ReentrantMutex::new(()));
+    let t = thread::spawn({
+      let mutex = Arc::clone(&mutex);
+      move || drop(mutex.lock())
+    });
+    let outer = mutex.lock();
+    drop(mutex.lock());
+    drop(outer);
+    t.join().unwrap();
+  });
+}
+
+#[test]
+#[should_panic(
+  expected = "deadlock: every live thread is blocked"
+)]
+fn lock_order_inversion_deadlocks() {
+  model(|| {
+    let a = Arc::new(sync::Mutex::new(()));
+    let b = Arc::new(sync::Mutex::new(()));
+    let t = thread::spawn({
+      let (a, b) = (Arc::clone(&a), Arc::clone(&b));
+      move || {
+        let _b = b.lock().unwrap();
+        let _a = a.lock().unwrap();
+      }
+    });
+    {
+      let _a = a.lock().unwrap();
+      let _b = b.lock().unwrap();
+    }
+    t.join().unwrap();
+  });
+}
+
+#[test]
+fn iterations_reuse_os_threads() {
+  use std::{collections::HashSet, thread::ThreadId};
+  use sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+  static SPAWNED: Mutex<Vec<ThreadId>> = Mutex::new(Vec::new());
+
+  model(|| {
+    let flag = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let flag = Arc::clone(&flag);
+      move || {
+        SPAWNED
+          .lock()
+          .unwrap()
+          .push(std::thread::current().id());
+        flag.store(1, SeqCst)
+      }
+    });
+    flag.load(SeqCst);
+    t.join().unwrap();
+  });
+  let spawned = SPAWNED.lock().unwrap();
+  let distinct: HashSet<_> = spawned.iter().collect();
+  assert!(distinct.len() < spawned.len());
+}
+
+#[test]
+fn park_waits_for_unpark() {
+  use sync::{
+    atomic::AtomicUsize, atomic::Ordering::SeqCst, Arc,
+  };
+
+  let config =
+    explore::Config::new().exhaustive().spurious_wakeups();
+  run(config, || {
+    let flag = Arc::new(AtomicUsize::new(0));
+    let main = thread::current();
+    let t = thread::spawn({
+      let flag = Arc::clone(&flag);
+      move || {
+        flag.store(1, SeqCst);
+        main.unpark()
+      }
+    });
+    while flag.load(SeqCst) == 0 {
+      thread::park();
+    }
+    t.join().unwrap();
+  })
+}
+
+#[test]
+fn condvar_timeouts_race_notifications() {
+  use std::{collections::BTreeSet, time::Duration};
+
+  use sync::{Arc, Condvar, Mutex};
+
+  static TIMED_OUT: std::sync::Mutex<BTreeSet<bool>> =
+    std::sync::Mutex::new(BTreeSet::new());
+
+  model(|| {
+    let state = Arc::new((Mutex::new(()), Condvar::new()));
+    let guard = state.0.lock().unwrap();
+    let t = thread::spawn({
+      let state = Arc::clone(&state);
+      move || state.1.notify_one()
+    });
+    let timeout = Duration::from_secs(1);
+    let (_guard, result) =
+      state.1.wait_timeout(guard, timeout).unwrap();
+    TIMED_OUT.lock().unwrap().insert(result.timed_out());
+    t.join().unwrap();
+  });
+  let seen = TIMED_OUT.lock().unwrap();
+  assert_eq!(*seen, BTreeSet::from([false, true]));
+}
+
+#[test]
+#[should_panic(expected = "woke up before the unpark")]
+fn spurious_wakeups_need_a_loop() {
+  use sync::{
+    atomic::AtomicUsize, atomic::Ordering::SeqCst, Arc,
+  };
+
+  let config =
+    explore::Config::new().exhaustive().spurious_wakeups();
+  run(config, || {
+    let flag = Arc::new(AtomicUsize::new(0));
+    let main = thread::current();
+    let t = thread::spawn({
+      let flag = Arc::clone(&flag);
+      move || {
+        flag.store(1, SeqCst);
+        main.unpark()
+      }
+    });
+    if flag.load(SeqCst) == 0 {
+      thread::park();
+    }
+    assert_eq!(
+      flag.load(SeqCst),
+      1,
+      "woke up before the unpark"
+    );
+    t.join().unwrap();
+  })
+}
+
+#[test]
+fn any_wait_may_wake_spuriously() {
+  use std::{collections::BTreeSet, time::Duration};
+
+  use sync::{Condvar, Mutex};
+
+  static TIMED_OUT: std::sync::Mutex<BTreeSet<(bool, bool)>> =
+    std::sync::Mutex::new(BTreeSet::new());
+
+  let config =
+    explore::Config::new().exhaustive().spurious_wakeups();
+  run(config, || {
+    let timeout = Duration::from_secs(1);
+    // Doesn't use up the budget of the condvar.
+    thread::park_timeout(timeout);
+    let (mutex, condvar) = (Mutex::new(()), Condvar::new());
+    let guard = mutex.lock().unwrap();
+    let (guard, first) =
+      condvar.wait_timeout(guard, timeout).unwrap();
+    let (_guard, second) =
+      condvar.wait_timeout(guard, timeout).unwrap();
+    TIMED_OUT
+      .lock()
+      .unwrap()
+      .insert((first.timed_out(), second.timed_out()));
+  });
+  let seen = TIMED_OUT.lock().unwrap();
+  assert_eq!(
+    *seen,
+    BTreeSet::from([(false, true), (true, false), (true, true)])
+  );
+}
+
+#[test]
+fn compare_exchange_weak_retries() {
+  use sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+  let config =
+    explore::Config::new().exhaustive().spurious_cas_failures();
+  run(config, || {
+    let lock = AtomicUsize::new(0);
+    while lock
+      .compare_exchange_weak(0, 1, SeqCst, SeqCst)
+      .is_err()
+    {}
+    assert_eq!(lock.load(SeqCst), 1);
+  })
+}
+
+/// Takes a failed `compare_exchange_weak` to mean somebody else
+/// won.
+#[test]
+#[should_panic(expected = "somebody else holds the lock")]
+fn compare_exchange_weak_fails_spuriously() {
+  use sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+  let config =
+    explore::Config::new().exhaustive().spurious_cas_failures();
+  run(config, || {
+    let lock = AtomicUsize::new(0);
+    if let Err(actual) =
+      lock.compare_exchange_weak(0, 1, SeqCst, SeqCst)
+    {
+      assert_eq!(actual, 1, "somebody else holds the lock");
+    }
+  })
+}
+
+#[test]
+fn cells_run_uninterrupted() {
+  use cell::UnsafeCell;
+
+  struct Counter(UnsafeCell<usize>);
+  // SAFETY: managed threads never run at the same time.
+  unsafe impl Sync for Counter {}
+
+  model(|| {
+    let counter = Arc::new(Counter(UnsafeCell::new(0)));
+    let t = thread::spawn({
+      let counter = Arc::clone(&counter);
+      move || {
+        let value = counter.0.with(|it| unsafe { *it });
+        counter.0.with_mut(|it| unsafe { *it = value + 1 });
+      }
+    });
+    let value = counter.0.with(|it| unsafe { *it });
+    counter.0.with_mut(|it| unsafe { *it = value + 1 });
+    t.join().unwrap();
+    assert_eq!(counter.0.with(|it| unsafe { *it }), 2);
+  })
+}
+
+#[test]
+#[should_panic(expected = "lost an increment")]
+fn injected_yields_interrupt_cells() {
+  use cell::UnsafeCell;
+
+  struct Counter(UnsafeCell<usize>);
+  // SAFETY: managed threads never run at the same time.
+  unsafe impl Sync for Counter {}
+
+  let config =
+    explore::Config::new().exhaustive().inject_yields();
+  run(config, || {
+    let counter = Arc::new(Counter(UnsafeCell::new(0)));
+    let t = thread::spawn({
+      let counter = Arc::clone(&counter);
+      move || {
+        let value = counter.0.with(|it| unsafe { *it });
+        counter.0.with_mut(|it| unsafe { *it = value + 1 });
+      }
+    });
+    let value = counter.0.with(|it| unsafe { *it });
+    counter.0.with_mut(|it| unsafe { *it = value + 1 });
+    t.join().unwrap();
+    let value = counter.0.with(|it| unsafe { *it });
+    assert_eq!(value, 2, "lost an increment");
+  })
+}
+
+#[test]
+#[should_panic(expected = "crashed halfway through the update")]
+fn crashes_interrupt_updates() {
+  use sync::{
+    atomic::AtomicUsize, atomic::Ordering::SeqCst, Arc,
+  };
+
+  let config =
+    explore::Config::new().exhaustive().thread_crashes();
+  run(config, || {
+    let a = Arc::new(AtomicUsize::new(0));
+    let b = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let (a, b) = (Arc::clone(&a), Arc::clone(&b));
+      move || {
+        a.store(1, SeqCst);
+        b.store(1, SeqCst);
+      }
+    });
+    let _ = t.join();
+    assert_eq!(
+      a.load(SeqCst),
+      b.load(SeqCst),
+      "crashed halfway through the update"
+    );
+  })
+}
+
+#[test]
+fn crashes_unwind_through_guards() {
+  use std::{collections::BTreeSet, sync::Mutex};
+  use sync::atomic::{AtomicU8, Ordering::SeqCst};
+
+  static SEEN: Mutex<BTreeSet<(bool, u32, u8)>> =
+    Mutex::new(BTreeSet::new());
+
+  /// Pauses in its destructor, while a crash unwinds.
+  struct Done(Arc<AtomicU8>);
+
+  impl Drop for Done {
+    fn drop(&mut self) {
+      self.0.store(1, SeqCst)
+    }
+  }
+
+  let config =
+    explore::Config::new().exhaustive().thread_crashes();
+  run(config, || {
+    let counter = Arc::new(sync::Mutex::new(0));
+    let done = Arc::new(AtomicU8::new(0));
+    let t = thread::spawn({
+      let counter = Arc::clone(&counter);
+      let done = Done(Arc::clone(&done));
+      move || {
+        let _done = done;
+        let mut guard = counter.lock().unwrap();
+        *guard += 1;
+        thread::yield_now();
+      }
+    });
+    let crashed = t.join().is_err();
+    let value = *counter.lock().unwrap();
+    SEEN.lock().unwrap().insert((
+      crashed,
+      value,
+      done.load(SeqCst),
+    ));
+  });
+  // The crash never comes between the lock and the guard, nor
+  // halfway through the unlock.
+  let seen = SEEN.lock().unwrap().clone();
+  let expected =
+    [(false, 1, 1), (true, 0, 1), (true, 1, 0), (true, 1, 1)];
+  assert_eq!(seen, expected.into());
+}
+
+/// The main thread, at a higher priority, spins on a lock which
+/// a spawned thread can't take first, as it's raised before the
+/// spawn.
+#[test]
+fn higher_priorities_run_first() {
+  use sync::{atomic::AtomicU8, atomic::Ordering::SeqCst, Arc};
+
+  let config = explore::Config::new().exhaustive().priorities();
+  run(config, || {
+    let lock = Arc::new(AtomicU8::new(0));
+    thread::set_priority(1);
+    let t = thread::spawn({
+      let lock = Arc::clone(&lock);
+      move || {
+        while lock
+          .compare_exchange(0, 1, SeqCst, SeqCst)
+          .is_err()
+        {}
+        lock.store(0, SeqCst);
+      }
+    });
+    while lock.compare_exchange(0, 1, SeqCst, SeqCst).is_err() {}
+    lock.store(0, SeqCst);
+    t.join().unwrap();
+  })
+}
+
+/// Raised only after the spawn, the main thread may spin on a
+/// lock the spawned thread holds and never gets to release
+/// again.
+#[test]
+#[should_panic(expected = "starvation")]
+fn priority_inversion_starves() {
+  use sync::{atomic::AtomicU8, atomic::Ordering::SeqCst, Arc};
+
+  let config = explore::Config::new().exhaustive().priorities();
+  run(config, || {
+    let lock = Arc::new(AtomicU8::new(0));
+    let t = thread::spawn({
+      let lock = Arc::clone(&lock);
+      move || {
+        while lock
+          .compare_exchange(0, 1, SeqCst, SeqCst)
+          .is_err()
+        {}
+        lock.store(0, SeqCst);
+      }
+    });
+    thread::set_priority(1);
+    while lock.compare_exchange(0, 1, SeqCst, SeqCst).is_err() {}
+    lock.store(0, SeqCst);
+    t.join().unwrap();
+  })
+}
+
+#[test]
+fn polling_receivers_see_every_outcome() {
+  use std::{collections::BTreeSet, time::Duration};
+
+  use sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+
+  static SEEN: Mutex<BTreeSet<&str>> =
+    Mutex::new(BTreeSet::new());
+
+  model(|| {
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn(move || tx.send(92).unwrap());
+    let seen = match rx.try_recv() {
+      Ok(_) => "value",
+      Err(TryRecvError::Empty) => "empty",
+      Err(TryRecvError::Disconnected) => unreachable!(),
+    };
+    SEEN.lock().unwrap().insert(seen);
+    t.join().unwrap();
+  });
+  assert_eq!(*SEEN.lock().unwrap(), ["empty", "value"].into());
+
+  SEEN.lock().unwrap().clear();
+  model(|| {
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn(move || tx.send(92).unwrap());
+    let seen = match rx.recv_timeout(Duration::from_secs(1)) {
+      Ok(_) => "value",
+      Err(RecvTimeoutError::Timeout) => "timeout",
+      Err(RecvTimeoutError::Disconnected) => unreachable!(),
+    };
+    SEEN.lock().unwrap().insert(seen);
+    t.join().unwrap();
+    if seen == "timeout" {
+      assert_eq!(rx.recv(), Ok(92));
+    }
+    assert!(rx.recv().is_err());
+  });
+  assert_eq!(*SEEN.lock().unwrap(), ["timeout", "value"].into());
+}
+
+#[test]
+fn channels_hang_up_early() {
+  use std::collections::BTreeSet;
+
+  use sync::mpsc::{self, TryRecvError};
+
+  static SEEN: Mutex<BTreeSet<(bool, &str)>> =
+    Mutex::new(BTreeSet::new());
+
+  let config =
+    explore::Config::new().exhaustive().channel_disconnects();
+  run(config, || {
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn(move || tx.send(92).is_ok());
+    let seen = match rx.try_recv() {
+      Ok(_) => "value",
+      Err(TryRecvError::Empty) => "empty",
+      Err(TryRecvError::Disconnected) => "disconnected",
+    };
+    let sent = t.join().unwrap();
+    SEEN.lock().unwrap().insert((sent, seen));
+  });
+  assert_eq!(
+    *SEEN.lock().unwrap(),
+    [
+      (false, "disconnected"),
+      (false, "empty"),
+      (true, "disconnected"),
+      (true, "empty"),
+      (true, "value"),
+    ]
+    .into()
+  );
+}
+
+#[test]
+fn messages_synchronize() {
+  use sync::{
+    atomic::{AtomicU32, Ordering::Relaxed},
+    mpsc,
+  };
+
+  model(|| {
+    let data = Arc::new(AtomicU32::new(0));
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn({
+      let data = Arc::clone(&data);
+      move || {
+        data.store(1, Relaxed);
+        tx.send(()).unwrap();
+      }
+    });
+    rx.recv().unwrap();
+    assert_eq!(data.load(Relaxed), 1);
+    t.join().unwrap();
+  });
+}
+
+#[test]
+fn critical_races_name_both_sides() {
+  use std::sync::atomic::AtomicU32 as Line;
+  use sync::atomic::{AtomicU32, Ordering::SeqCst};
+
+  static LOAD: Line = Line::new(0);
+  static STORE: Line = Line::new(0);
+
+  // The spawned thread loads between main's load and store.
+  let schedule = "0.0.0.1.1.0.0.0.0.0.0";
+  let race =
+    critical_race(explore::Config::new(), schedule, || {
+      let count = Arc::new(AtomicU32::new(0));
+      let t = thread::spawn({
+        let count = Arc::clone(&count);
+        move || {
+          let (value, line) = (count.load(SeqCst), line!());
+          LOAD.store(line, SeqCst);
+          count.store(value + 1, SeqCst);
+        }
+      });
+      let value = count.load(SeqCst);
+      let ((), line) = (count.store(value + 1, SeqCst), line!());
+      STORE.store(line, SeqCst);
+      t.join().unwrap();
+      assert_eq!(count.load(SeqCst), 2);
+    })
+    .unwrap();
+  assert_eq!((race.preempted(), race.by()), (0, 1));
+  assert_eq!(
+    race.preempted_at().unwrap().line(),
+    STORE.load(SeqCst)
+  );
+  assert_eq!(race.by_at().unwrap().line(), LOAD.load(SeqCst));
+  assert!(race.to_string().starts_with(
+    "the critical race is between src/loom_compat.rs"
+  ));
+  assert_eq!(
+    race.passing().to_string(),
+    "0.0.0.0.1.0.0.0.0.0.0"
+  );
+}
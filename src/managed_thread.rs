@@ -1,66 +1,237 @@
+use arbtest::arbitrary::{self, Unstructured};
 use std::{
   cell::RefCell,
-  sync::{atomic::Ordering, mpsc, Arc, Condvar, Mutex},
-  thread::Scope,
+  fmt,
+  marker::PhantomData,
+  panic::{self, AssertUnwindSafe, Location},
+  sync::{
+    atomic::{self, Ordering::SeqCst},
+    mpsc, Arc, Mutex, OnceLock,
+  },
+  thread::{Scope, Thread, ThreadId},
+  time::{Duration, Instant},
 };
 
-#[derive(Default)]
-pub struct AtomicU32 {
-  inner: std::sync::atomic::AtomicU32,
+use crate::explore::{self, Choices};
+
+pub use crate::atomic::{
+  AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize,
+  AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize,
+};
+
+pub(crate) fn pause() {
+  if let Some(ctx) = SharedContext::get() {
+    ctx.pause()
+  }
 }
 
-impl AtomicU32 {
-  pub fn load(&self, ordering: Ordering) -> u32 {
-    pause();
-    let result = self.inner.load(ordering);
-    pause();
-    result
+/// Warns, once per site, about an instrumented operation at
+/// `site` on a thread which isn't managed while some managed
+/// thread is in the middle of a step. That's usually a
+/// `std::thread::spawn` in the code under test, which runs
+/// outside the exploration and voids what it promises, see
+/// [`escapes`].
+///
+/// A heuristic: with tests running side by side, a plain thread
+/// of another test trips it too. Plain `std` atomics can't be
+/// seen at all; [`crate::instrument_atomics`] rewrites them.
+pub(crate) fn check_managed(site: &'static Location<'static>) {
+  // The fast path for code running outside of any test.
+  if registry::is_empty() || SharedContext::get().is_some() {
+    return;
+  }
+  if !registry::running_outside(std::thread::current().id()) {
+    return;
   }
+  let mut escapes = ESCAPES.lock().unwrap();
+  if !escapes.contains(&site) {
+    eprintln!(
+      "warning: an instrumented operation at {site} ran on a \
+      thread which isn't managed, while a managed one was \
+      running; spawn it with `managed_thread` so the explorer \
+      can interleave it"
+    );
+    escapes.push(site);
+  }
+}
+
+static ESCAPES: Mutex<Vec<&'static Location<'static>>> =
+  Mutex::new(Vec::new());
+
+/// The sites of instrumented operations which ran on a thread
+/// that isn't managed, as warned about so far in the process,
+/// for a test to fail on rather than to only print.
+pub fn escapes() -> Vec<&'static Location<'static>> {
+  ESCAPES.lock().unwrap().clone()
+}
 
-  pub fn store(&self, value: u32, ordering: Ordering) {
-    pause();
-    self.inner.store(value, ordering);
-    pause();
+/// A [`crate::checkpoint`] named `name`, for [`crate::script`]s
+/// to stop at. Outside of a managed thread, does nothing.
+#[track_caller]
+pub fn label(name: &'static str) {
+  crate::loom_compat::reached(std::panic::Location::caller());
+  if let Some(ctx) = SharedContext::get() {
+    *ctx.label.lock().unwrap() = Some(name);
+    ctx.pause();
+    *ctx.label.lock().unwrap() = None;
   }
+}
 
-  pub fn fetch_add(
-    &self,
-    value: u32,
-    ordering: Ordering,
-  ) -> u32 {
-    pause();
-    let result = self.inner.fetch_add(value, ordering);
-    pause();
-    result
+/// Runs `f` as a single step: one scheduling point before it,
+/// none inside, for code which does real blocking work, like
+/// waiting on a socket or a child process. The controller
+/// doesn't warn about a hang while the thread is in here.
+///
+/// `f` must not wait for another managed thread, which can't run
+/// until it returns.
+pub fn blocking<R>(f: impl FnOnce() -> R) -> R {
+  let Some(ctx) = SharedContext::get() else { return f() };
+  ctx.pause();
+  struct Leave(Arc<SharedContext>);
+  impl Drop for Leave {
+    fn drop(&mut self) {
+      self.0.blocking.fetch_sub(1, SeqCst);
+    }
+  }
+  ctx.blocking.fetch_add(1, SeqCst);
+  let _leave = Leave(ctx);
+  f()
+}
+
+/// Runs `f` with any [`Crash`] held back until it returns, for
+/// releases which `std` never fails halfway through, like
+/// unlocking a mutex.
+pub(crate) fn deferring_crashes<R>(f: impl FnOnce() -> R) -> R {
+  let Some(ctx) = SharedContext::get() else { return f() };
+  struct Leave(Arc<SharedContext>);
+  impl Drop for Leave {
+    fn drop(&mut self) {
+      self.0.deferring_crashes.fetch_sub(1, SeqCst);
+    }
   }
+  ctx.deferring_crashes.fetch_add(1, SeqCst);
+  let _leave = Leave(ctx);
+  f()
 }
 
-fn pause() {
+/// How long a step runs before the controller suspects the
+/// thread is blocked on something it can't see.
+const HANG: Duration = Duration::from_secs(10);
+
+/// Ends a [`SharedContext::unpause_solo`] run of the current
+/// thread, as the controller has a decision to make at its next
+/// pause.
+pub(crate) fn end_solo() {
   if let Some(ctx) = SharedContext::get() {
-    ctx.pause()
+    ctx.solo.store(false, SeqCst)
   }
 }
 
+/// Pauses until `until` holds. The controller is expected not to
+/// resume a blocked thread before that, but re-checking makes it
+/// safe if it does.
+pub(crate) fn block_until(
+  until: Arc<dyn Fn() -> bool + Send + Sync>,
+) {
+  while !until() {
+    match SharedContext::get() {
+      // Left to run to completion by a failed controller. If the
+      // thread is deadlocked, that's never, and it shouldn't
+      // burn a core until the process exits.
+      Some(ctx) if ctx.state() == State::Detached => {
+        std::thread::sleep(Duration::from_millis(1))
+      }
+      Some(ctx) => {
+        // Only the controller can unblock us.
+        ctx.solo.store(false, SeqCst);
+        *ctx.blocker.lock().unwrap() = Some(Arc::clone(&until));
+        ctx.pause();
+        *ctx.blocker.lock().unwrap() = None;
+      }
+      None => std::thread::yield_now(),
+    }
+  }
+}
+
+type Blocker = Arc<dyn Fn() -> bool + Send + Sync>;
+
+/// The handshake between a managed thread and its controller.
+///
+/// Every step of an exploration is a round-trip through here, so
+/// it is an atomic state plus `park`/`unpark` rather than a
+/// mutex and a condvar: each side stores the new state and wakes
+/// the other one. Both wait in a loop, which makes stray wakeups
+/// harmless.
 #[derive(Default)]
-struct SharedContext {
-  state: Mutex<State>,
-  cv: Condvar,
+pub(crate) struct SharedContext {
+  state: atomic::AtomicU8,
+  /// Pauses are no-ops, see [`SharedContext::unpause_solo`].
+  solo: atomic::AtomicBool,
+  /// The next resume is a [`Crash`].
+  crash: atomic::AtomicBool,
+  /// Nesting depth of [`blocking`] sections, where pauses are
+  /// no-ops.
+  blocking: atomic::AtomicU32,
+  /// Nesting depth of [`deferring_crashes`] sections.
+  deferring_crashes: atomic::AtomicU32,
+  /// The managed thread, set before it first pauses.
+  worker: OnceLock<Thread>,
+  /// Whichever thread last resumed the managed one.
+  controller: Mutex<Option<Thread>>,
+  blocker: Mutex<Option<Blocker>>,
+  /// The [`label`] the thread is paused at.
+  label: Mutex<Option<&'static str>>,
+  /// What the thread panicked with, for the controller to
+  /// repeat.
+  panic: Mutex<Option<String>>,
 }
 
-#[derive(Default, PartialEq, Eq, Debug)]
+#[derive(Default, PartialEq, Eq, Debug, Clone, Copy)]
+#[repr(u8)]
 enum State {
   #[default]
   Ready,
   Running,
   Paused,
+  /// An unscoped thread returned from its closure.
+  Finished,
+  /// The submitted op panicked, the thread is gone.
+  Panicked,
+  /// The thread was stopped with a [`Crash`].
+  Crashed,
+  /// The handle was dropped without a join (usually because the
+  /// controller panicked), the thread runs unmanaged until its
+  /// queue drains.
+  Detached,
+}
+
+impl State {
+  const ALL: [State; 7] = [
+    State::Ready,
+    State::Running,
+    State::Paused,
+    State::Finished,
+    State::Panicked,
+    State::Crashed,
+    State::Detached,
+  ];
 }
 
 thread_local! {
-  static INSTANCE: RefCell<Option<Arc<SharedContext>>> = RefCell::new(None);
+  static INSTANCE: RefCell<Option<Arc<SharedContext>>> =
+    const { RefCell::new(None) };
 }
 
 impl SharedContext {
+  fn new(state: State) -> SharedContext {
+    SharedContext {
+      state: atomic::AtomicU8::new(state as u8),
+      ..Default::default()
+    }
+  }
+
   fn set(ctx: Arc<SharedContext>) {
+    let _ = ctx.worker.set(std::thread::current());
     INSTANCE.with(|it| *it.borrow_mut() = Some(ctx));
   }
 
@@ -68,83 +239,905 @@ impl SharedContext {
     INSTANCE.with(|it| it.borrow().clone())
   }
 
+  fn state(&self) -> State {
+    State::ALL[self.state.load(SeqCst) as usize]
+  }
+
+  /// Moves from `from` to `to`, or returns the actual state.
+  fn transition(
+    &self,
+    from: State,
+    to: State,
+  ) -> Result<(), State> {
+    self
+      .state
+      .compare_exchange(from as u8, to as u8, SeqCst, SeqCst)
+      .map(drop)
+      .map_err(|it| State::ALL[it as usize])
+  }
+
+  /// Called by the managed thread once it's done with a step.
+  fn wake_controller(&self) {
+    if let Some(controller) = &*self.controller.lock().unwrap() {
+      controller.unpark()
+    }
+  }
+
+  pub(crate) fn is_paused(&self) -> bool {
+    self.state() == State::Paused
+  }
+
+  /// Returned, or crashed, so it will never run again.
+  pub(crate) fn is_finished(&self) -> bool {
+    matches!(self.state(), State::Finished | State::Crashed)
+  }
+
+  /// Paused, and not waiting on something that hasn't happened
+  /// yet.
+  pub(crate) fn is_runnable(&self) -> bool {
+    if !self.is_paused() {
+      return false;
+    }
+    // Only a paused thread touches its blocker, and only around
+    // the pause, so this doesn't contend with a running one.
+    let blocker = self.blocker.lock().unwrap().clone();
+    blocker.is_none_or(|it| it())
+  }
+
+  pub(crate) fn unpause(&self) {
+    *self.controller.lock().unwrap() =
+      Some(std::thread::current());
+    let result = self.transition(State::Paused, State::Running);
+    assert_eq!(result, Ok(()));
+    self.worker.get().unwrap().unpark();
+    self.wait_while_running();
+  }
+
+  /// Like [`SharedContext::unpause`], but skips the following
+  /// pauses too, until [`end_solo`].
+  ///
+  /// For when no other thread can run: there is no interleaving
+  /// to choose, so round-trips through the controller are
+  /// wasted.
+  pub(crate) fn unpause_solo(&self) {
+    self.solo.store(true, SeqCst);
+    self.unpause()
+  }
+
+  /// Stops a paused thread for good, see [`Crash`].
+  pub(crate) fn crash(&self) {
+    self.crash.store(true, SeqCst);
+    self.unpause()
+  }
+
+  /// Lets the thread run free, see [`State::Detached`].
+  pub(crate) fn detach(&self) {
+    let mut state = self.state();
+    while !matches!(
+      state,
+      State::Finished | State::Panicked | State::Crashed
+    ) {
+      match self.transition(state, State::Detached) {
+        Ok(()) => break,
+        Err(actual) => state = actual,
+      }
+    }
+    if let Some(worker) = self.worker.get() {
+      worker.unpark()
+    }
+  }
+
   fn pause(&self) {
-    let mut guard = self.state.lock().unwrap();
-    assert_eq!(*guard, State::Running);
-    *guard = State::Paused;
-    self.cv.notify_all();
-    guard = self
-      .cv
-      .wait_while(guard, |state| *state == State::Paused)
-      .unwrap();
-    assert_eq!(*guard, State::Running)
+    if self.solo.load(SeqCst) || self.blocking.load(SeqCst) > 0 {
+      return;
+    }
+    match self.transition(State::Running, State::Paused) {
+      Ok(()) => (),
+      Err(State::Detached) => return,
+      Err(state) => panic!("pausing a {state:?} thread"),
+    }
+    self.wake_controller();
+    while self.state() == State::Paused {
+      std::thread::park();
+    }
+    assert!(matches!(
+      self.state(),
+      State::Running | State::Detached
+    ));
+    // Destructors pausing while the crash unwinds must not crash
+    // the thread again, which would abort the process.
+    if self.deferring_crashes.load(SeqCst) == 0
+      && !std::thread::panicking()
+      && self.crash.swap(false, SeqCst)
+    {
+      panic::resume_unwind(Box::new(Crash))
+    }
+  }
+
+  /// Blocks the controller until the managed thread pauses or
+  /// finishes its op.
+  fn wait_while_running(&self) {
+    let start = Instant::now();
+    let mut warned = false;
+    while self.state() == State::Running {
+      std::thread::park_timeout(HANG);
+      if !warned
+        && start.elapsed() >= HANG
+        && self.blocking.load(SeqCst) == 0
+      {
+        warned = true;
+        eprintln!(
+          "warning: a managed thread has run for {HANG:?} \
+          without pausing, it may be blocked on something \
+          uninstrumented; wrap real blocking in \
+          `managed_thread::blocking`"
+        );
+      }
+    }
+    if self.state() == State::Panicked {
+      let message = self.panic.lock().unwrap().take();
+      panic!(
+        "managed thread panicked: {}",
+        message.as_deref().unwrap_or("Box<dyn Any>")
+      )
+    }
   }
 }
 
-pub struct ManagedHandle<'scope, T> {
-  inner: std::thread::ScopedJoinHandle<'scope, ()>,
-  sender: mpsc::Sender<Box<dyn FnOnce(&mut T) + 'scope + Send>>,
+/// The panic payload which stops a crashed thread, see
+/// [`ManagedHandle::crash`].
+///
+/// A crash unwinds from the pause the thread is at, so nothing
+/// more of its op runs, but destructors do, as with a panic.
+#[derive(Debug)]
+pub struct Crash;
+
+type Op<'scope, T> = Box<dyn FnOnce(&mut T) + 'scope + Send>;
+
+/// A managed thread owning a `T`, which takes messages of type
+/// `M` from the controller one at a time.
+///
+/// By default messages are boxed closures, see [`submit`]. Tests
+/// sending many tiny ops can [`spawn_with`] an enum of them
+/// instead, which saves an allocation per op.
+///
+/// [`submit`]: ManagedHandle::submit
+pub struct ManagedHandle<'scope, T, M = Op<'scope, T>> {
+  inner: Option<std::thread::ScopedJoinHandle<'scope, ()>>,
+  mailbox: Arc<Mutex<Mailbox<M>>>,
   ctx: Arc<SharedContext>,
+  state: PhantomData<fn(&mut T)>,
+}
+
+/// The next message, handed over by parking rather than a
+/// channel.
+struct Mailbox<M> {
+  message: Option<M>,
+  closed: bool,
 }
 
 pub fn spawn<'scope, T: 'scope + Send>(
   scope: &'scope Scope<'scope, '_>,
-  mut state: T,
+  state: T,
 ) -> ManagedHandle<'scope, T> {
+  spawn_with(scope, state, |state, op: Op<'scope, T>| op(state))
+}
+
+/// Spawns a managed thread which handles each message with
+/// `apply`.
+pub fn spawn_with<'scope, T, M>(
+  scope: &'scope Scope<'scope, '_>,
+  mut state: T,
+  mut apply: impl FnMut(&mut T, M) + Send + 'scope,
+) -> ManagedHandle<'scope, T, M>
+where
+  T: 'scope + Send,
+  M: 'scope + Send,
+{
   let ctx: Arc<SharedContext> = Default::default();
-  let (sender, receiver) =
-    mpsc::channel::<Box<dyn FnOnce(&mut T) + 'scope + Send>>();
+  let mailbox = Arc::new(Mutex::new(Mailbox {
+    message: None,
+    closed: false,
+  }));
   let inner = scope.spawn({
     let ctx = Arc::clone(&ctx);
+    let mailbox = Arc::clone(&mailbox);
     move || {
       SharedContext::set(Arc::clone(&ctx));
-      for f in receiver {
-        f(&mut state);
-        let mut guard = ctx.state.lock().unwrap();
-        assert_eq!(*guard, State::Running);
-        *guard = State::Ready;
-        ctx.cv.notify_all()
+      while let Some(message) = receive(&mailbox) {
+        let result =
+          panic::catch_unwind(AssertUnwindSafe(|| {
+            apply(&mut state, message)
+          }));
+        if let Err(payload) = result {
+          let crashed = payload.is::<Crash>();
+          let state = if crashed {
+            State::Crashed
+          } else {
+            *ctx.panic.lock().unwrap() =
+              Some(explore::panic_message(&*payload));
+            State::Panicked
+          };
+          ctx.state.store(state as u8, SeqCst);
+          ctx.wake_controller();
+          if crashed {
+            return;
+          }
+          panic::resume_unwind(payload)
+        }
+        match ctx.transition(State::Running, State::Ready) {
+          Ok(()) => ctx.wake_controller(),
+          Err(State::Detached) => (),
+          Err(state) => panic!("finishing a {state:?} op"),
+        }
       }
     }
   });
-  ManagedHandle { inner, ctx, sender }
+  let _ = ctx.worker.set(inner.thread().clone());
+  registry::add(&ctx);
+  ManagedHandle {
+    inner: Some(inner),
+    mailbox,
+    ctx,
+    state: PhantomData,
+  }
+}
+
+/// Waits for the next message, `None` once the handle is gone.
+fn receive<M>(mailbox: &Mutex<Mailbox<M>>) -> Option<M> {
+  loop {
+    let mut mailbox = mailbox.lock().unwrap();
+    if let Some(message) = mailbox.message.take() {
+      return Some(message);
+    }
+    if mailbox.closed {
+      return None;
+    }
+    drop(mailbox);
+    std::thread::park();
+  }
+}
+
+/// Spawns a `'static` managed thread which runs `f` once.
+///
+/// The thread starts out paused, before running any of `f`, so
+/// that the controller decides when it begins. The OS thread
+/// comes from a pool and goes back to it once `f` returns, as
+/// explorations spawn the same threads over and over.
+pub(crate) fn spawn_unscoped<F, R>(
+  f: F,
+) -> (Arc<SharedContext>, UnscopedHandle<R>)
+where
+  F: FnOnce() -> R + Send + 'static,
+  R: Send + 'static,
+{
+  let ctx = Arc::new(SharedContext::new(State::Running));
+  *ctx.controller.lock().unwrap() = Some(std::thread::current());
+  let (sender, result) = mpsc::channel();
+  pool::run({
+    let ctx = Arc::clone(&ctx);
+    move |idle| {
+      SharedContext::set(Arc::clone(&ctx));
+      // A crash may come as soon as the first pause.
+      let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        ctx.pause();
+        f()
+      }));
+      INSTANCE.with(|it| *it.borrow_mut() = None);
+      let state = match &result {
+        Ok(_) => State::Finished,
+        Err(payload) if payload.is::<Crash>() => State::Crashed,
+        Err(payload) => {
+          *ctx.panic.lock().unwrap() =
+            Some(explore::panic_message(&**payload));
+          State::Panicked
+        }
+      };
+      ctx.state.store(state as u8, SeqCst);
+      ctx.wake_controller();
+      idle.release();
+      let _ = sender.send(result);
+    }
+  });
+  ctx.wait_while_running();
+  (ctx, UnscopedHandle { result })
+}
+
+/// Like [`std::thread::JoinHandle`], for [`spawn_unscoped`].
+pub(crate) struct UnscopedHandle<R> {
+  result: mpsc::Receiver<std::thread::Result<R>>,
+}
+
+impl<R> UnscopedHandle<R> {
+  pub(crate) fn join(self) -> std::thread::Result<R> {
+    self.result.recv().unwrap()
+  }
+}
+
+mod pool {
+  use std::sync::{mpsc, Mutex};
+
+  type Job = Box<dyn FnOnce() + Send>;
+
+  /// Caps the threads kept around between explorations.
+  const MAX_IDLE: usize = 64;
+
+  static IDLE: Mutex<Vec<mpsc::Sender<Job>>> =
+    Mutex::new(Vec::new());
+
+  /// The calling OS thread, to be handed back to the pool.
+  pub(super) struct Idle(mpsc::Sender<Job>);
+
+  impl Idle {
+    /// Makes the thread available to the next [`run`] once the
+    /// current job returns. Dropping `Idle` instead retires it.
+    pub(super) fn release(self) {
+      let mut idle = IDLE.lock().unwrap();
+      if idle.len() < MAX_IDLE {
+        idle.push(self.0)
+      }
+    }
+  }
+
+  /// Runs `job` on an idle pooled thread, or on a new one.
+  pub(super) fn run(job: impl FnOnce(Idle) + Send + 'static) {
+    let pooled = IDLE.lock().unwrap().pop();
+    let sender = pooled.unwrap_or_else(|| {
+      let (sender, receiver) = mpsc::channel::<Job>();
+      std::thread::spawn(move || {
+        for job in receiver {
+          job()
+        }
+      });
+      sender
+    });
+    let idle = Idle(sender.clone());
+    sender.send(Box::new(move || job(idle))).unwrap();
+  }
 }
 
 impl<'scope, T> ManagedHandle<'scope, T> {
+  pub fn submit<F: FnOnce(&mut T) + Send + 'scope>(&self, f: F) {
+    self.send(Box::new(f))
+  }
+}
+
+impl<'scope, T, M> ManagedHandle<'scope, T, M> {
   pub fn is_paused(&self) -> bool {
-    let guard = self.ctx.state.lock().unwrap();
-    *guard == State::Paused
+    self.ctx.is_paused()
   }
 
   pub fn unpause(&self) {
-    let mut guard = self.ctx.state.lock().unwrap();
-    assert_eq!(*guard, State::Paused);
-    *guard = State::Running;
-    self.ctx.cv.notify_all();
-    guard = self
-      .ctx
-      .cv
-      .wait_while(guard, |state| *state == State::Running)
-      .unwrap();
+    self.ctx.unpause()
   }
 
-  pub fn submit<F: FnOnce(&mut T) + Send + 'scope>(&self, f: F) {
-    let mut guard = self.ctx.state.lock().unwrap();
-    assert_eq!(*guard, State::Ready);
-    *guard = State::Running;
-    self.sender.send(Box::new(f)).unwrap();
-    guard = self
-      .ctx
-      .cv
-      .wait_while(guard, |state| *state == State::Running)
-      .unwrap();
+  /// The [`label`] the thread is paused at, if any.
+  pub fn label(&self) -> Option<&'static str> {
+    *self.ctx.label.lock().unwrap()
   }
 
-  pub fn join(self) {
+  /// An untyped reference to the thread, as in [`live_threads`].
+  pub fn thread_ref(&self) -> ThreadRef {
+    ThreadRef { ctx: Arc::clone(&self.ctx) }
+  }
+
+  /// Kills a paused thread in the middle of its op, to test
+  /// recovery from a thread or process dying at that point. The
+  /// thread takes no further messages.
+  pub fn crash(&self) {
+    self.ctx.crash()
+  }
+
+  /// Hands `message` to the thread and waits until it is done
+  /// with it or pauses.
+  pub fn send(&self, message: M) {
+    *self.ctx.controller.lock().unwrap() =
+      Some(std::thread::current());
+    let result =
+      self.ctx.transition(State::Ready, State::Running);
+    assert_eq!(result, Ok(()));
+    self.mailbox.lock().unwrap().message = Some(message);
+    self.ctx.worker.get().unwrap().unpark();
+    self.ctx.wait_while_running();
+  }
+
+  pub fn join(mut self) {
     while self.is_paused() {
       self.unpause();
     }
-    drop(self.sender);
-    self.inner.join().unwrap();
+    self.close();
+    if let Err(payload) = self.inner.take().unwrap().join() {
+      panic::resume_unwind(payload)
+    }
   }
+
+  fn close(&self) {
+    self.mailbox.lock().unwrap().closed = true;
+    self.ctx.worker.get().unwrap().unpark();
+  }
+}
+
+/// The thread, what it's up to, and whether a message waits for
+/// it.
+impl<T, M> fmt::Debug for ManagedHandle<'_, T, M> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let worker = self.ctx.worker.get();
+    let mailbox = self.mailbox.lock().unwrap();
+    f.debug_struct("ManagedHandle")
+      .field("thread", &worker.map(|it| it.id()))
+      .field("name", &worker.and_then(|it| it.name()))
+      .field("state", &self.ctx.state())
+      .field("label", &self.label())
+      .field("queued", &mailbox.message.is_some())
+      .field("closed", &mailbox.closed)
+      .finish()
+  }
+}
+
+impl<'scope, T, M> Drop for ManagedHandle<'scope, T, M> {
+  fn drop(&mut self) {
+    registry::remove(&self.ctx);
+    self.ctx.detach();
+    self.close();
+  }
+}
+
+/// A managed thread found through [`live_threads`], for helpers
+/// which don't have its handle.
+#[derive(Clone)]
+pub struct ThreadRef {
+  ctx: Arc<SharedContext>,
+}
+
+impl ThreadRef {
+  pub fn thread(&self) -> &Thread {
+    self.ctx.worker.get().unwrap()
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.ctx.is_paused()
+  }
+
+  pub fn unpause(&self) {
+    self.ctx.unpause()
+  }
+
+  pub fn label(&self) -> Option<&'static str> {
+    *self.ctx.label.lock().unwrap()
+  }
+}
+
+impl fmt::Debug for ThreadRef {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ThreadRef")
+      .field("thread", &self.thread().id())
+      .field("state", &self.ctx.state())
+      .field("label", &self.label())
+      .finish()
+  }
+}
+
+/// Where [`wait_all_paused`] found the threads, as indices into
+/// the slice it was given.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Quiescence {
+  pub paused: Vec<usize>,
+  /// Done with their op, gone, or detached, so not coming back
+  /// to the controller by themselves.
+  pub finished: Vec<usize>,
+}
+
+/// Blocks until none of `threads` is running, for a known
+/// quiescent point before a scheduling decision.
+///
+/// The handle methods already wait for the step they start, so
+/// this only matters for a thread which was resumed elsewhere,
+/// like by a helper thread.
+pub fn wait_all_paused(threads: &[ThreadRef]) -> Quiescence {
+  let mut result = Quiescence::default();
+  for (i, t) in threads.iter().enumerate() {
+    // Whoever resumed the thread is the one it wakes, so poll.
+    while t.ctx.state() == State::Running {
+      std::thread::sleep(Duration::from_millis(1));
+    }
+    if t.is_paused() {
+      result.paused.push(i)
+    } else {
+      result.finished.push(i)
+    }
+  }
+  result
+}
+
+/// The managed threads spawned by the current thread whose
+/// handles are still around, in the order they were spawned.
+pub fn live_threads() -> Vec<ThreadRef> {
+  registry::owned_by(std::thread::current().id())
+}
+
+/// The [`live_threads`] which are paused.
+pub fn paused_threads() -> Vec<ThreadRef> {
+  let mut threads = live_threads();
+  threads.retain(|it| it.is_paused());
+  threads
+}
+
+/// Every live handle, keyed by the thread which spawned it, so
+/// that tests running side by side don't see each other's
+/// threads. Global rather than thread-local, as a handle may be
+/// dropped elsewhere.
+mod registry {
+  use super::*;
+
+  static LIVE: Mutex<Vec<(ThreadId, Arc<SharedContext>)>> =
+    Mutex::new(Vec::new());
+
+  /// `LIVE.len()`, without the lock.
+  static COUNT: atomic::AtomicUsize =
+    atomic::AtomicUsize::new(0);
+
+  pub(super) fn add(ctx: &Arc<SharedContext>) {
+    let owner = std::thread::current().id();
+    let mut live = LIVE.lock().unwrap();
+    live.push((owner, Arc::clone(ctx)));
+    COUNT.store(live.len(), SeqCst);
+  }
+
+  pub(super) fn remove(ctx: &Arc<SharedContext>) {
+    let mut live = LIVE.lock().unwrap();
+    live.retain(|(_, it)| !Arc::ptr_eq(it, ctx));
+    COUNT.store(live.len(), SeqCst);
+  }
+
+  pub(super) fn is_empty() -> bool {
+    COUNT.load(SeqCst) == 0
+  }
+
+  /// Whether a managed thread which `me` doesn't control is
+  /// mid-step.
+  pub(super) fn running_outside(me: ThreadId) -> bool {
+    let live = LIVE.lock().unwrap();
+    live.iter().any(|(owner, ctx)| {
+      *owner != me && ctx.state() == State::Running
+    })
+  }
+
+  pub(super) fn owned_by(owner: ThreadId) -> Vec<ThreadRef> {
+    let live = LIVE.lock().unwrap();
+    live
+      .iter()
+      .filter(|(it, _)| *it == owner)
+      .map(|(_, ctx)| ThreadRef { ctx: Arc::clone(ctx) })
+      .collect()
+  }
+}
+
+/// The `pbt` loop, packaged: until `rng` runs out of entropy,
+/// picks a thread and either unpauses it or, if it's idle, lets
+/// `op` submit something to it. Then joins every thread.
+///
+/// Running out of entropy is the normal way for this to end, so
+/// it is not reported as an error, and threads are joined on
+/// every exit path.
+pub fn drive<'scope, T, M>(
+  rng: &mut Unstructured<'_>,
+  threads: impl IntoIterator<Item = ManagedHandle<'scope, T, M>>,
+  mut op: impl FnMut(
+    &mut Unstructured<'_>,
+    usize,
+    &ManagedHandle<'scope, T, M>,
+  ) -> arbitrary::Result<()>,
+) -> arbitrary::Result<()> {
+  let threads: Vec<_> = threads.into_iter().collect();
+  let mut result = Ok(());
+  while !rng.is_empty() {
+    let step = rng.choose_index(threads.len()).and_then(|tid| {
+      let t = &threads[tid];
+      if t.is_paused() {
+        t.unpause();
+        Ok(())
+      } else {
+        op(rng, tid, t)
+      }
+    });
+    if let Err(err) = step {
+      if err != arbitrary::Error::NotEnoughData {
+        result = Err(err);
+      }
+      break;
+    }
+  }
+  for t in threads {
+    t.join();
+  }
+  result
+}
+
+/// Unpauses a paused one of `threads` of `g`'s choosing,
+/// returning its index, or `None` if none is paused.
+fn unpause_any(
+  g: &mut Choices,
+  threads: &[ThreadRef],
+) -> Option<usize> {
+  let paused: Vec<usize> = (0..threads.len())
+    .filter(|&i| threads[i].is_paused())
+    .collect();
+  if paused.is_empty() {
+    return None;
+  }
+  let i = paused[g.pick_thread(&paused)];
+  threads[i].unpause();
+  Some(i)
+}
+
+/// Managed threads which take the same messages, for phase-style
+/// tests: every thread gets the message, then the explorer
+/// interleaves them until all are done with it.
+#[derive(Debug)]
+pub struct ThreadGroup<'scope, T, M = Op<'scope, T>> {
+  threads: Vec<ManagedHandle<'scope, T, M>>,
+}
+
+impl<'scope, T, M> ThreadGroup<'scope, T, M> {
+  pub fn new<I>(threads: I) -> ThreadGroup<'scope, T, M>
+  where
+    I: IntoIterator<Item = ManagedHandle<'scope, T, M>>,
+  {
+    ThreadGroup { threads: threads.into_iter().collect() }
+  }
+
+  pub fn threads(&self) -> &[ManagedHandle<'scope, T, M>] {
+    &self.threads
+  }
+
+  /// Sends `message` to every thread, then lets `g` pick which
+  /// paused thread goes next until none is paused. Each thread
+  /// only runs up to its first pause point on delivery, so the
+  /// explorer decides the order of everything shared.
+  pub fn send_all(&self, g: &mut Choices, message: M)
+  where
+    M: Clone,
+  {
+    for t in &self.threads {
+      t.send(message.clone());
+    }
+    self.settle(g);
+  }
+
+  /// Unpauses threads in the order `g` picks until none is
+  /// paused.
+  pub fn settle(&self, g: &mut Choices) {
+    let threads: Vec<ThreadRef> = self
+      .threads
+      .iter()
+      .map(ManagedHandle::thread_ref)
+      .collect();
+    while unpause_any(g, &threads).is_some() {}
+  }
+
+  pub fn join(self) {
+    for t in self.threads {
+      t.join();
+    }
+  }
+}
+
+impl<'scope, T> ThreadGroup<'scope, T> {
+  /// [`ThreadGroup::send_all`] for a closure.
+  pub fn submit_all(
+    &self,
+    g: &mut Choices,
+    op: impl FnOnce(&mut T) + Clone + Send + 'scope,
+  ) {
+    for t in &self.threads {
+      t.submit(op.clone());
+    }
+    self.settle(g);
+  }
+}
+
+/// Named managed threads of any types, for tests with several
+/// roles. The set owns the handles, so submit each thread's op
+/// before adding it. Indexing by name gives its [`ThreadRef`].
+#[derive(Default)]
+pub struct ThreadSet<'scope> {
+  threads: Vec<ThreadRef>,
+  /// Indexed like `threads`.
+  members: Vec<Member<'scope>>,
+}
+
+struct Member<'scope> {
+  name: &'static str,
+  join: Box<dyn FnOnce() + 'scope>,
+}
+
+impl<'scope> ThreadSet<'scope> {
+  pub fn new() -> ThreadSet<'scope> {
+    ThreadSet::default()
+  }
+
+  pub fn insert<T: 'scope, M: 'scope>(
+    &mut self,
+    name: &'static str,
+    handle: ManagedHandle<'scope, T, M>,
+  ) -> &mut ThreadSet<'scope> {
+    assert!(
+      self.members.iter().all(|it| it.name != name),
+      "duplicate thread name {name:?}"
+    );
+    self.threads.push(handle.thread_ref());
+    self.members.push(Member {
+      name,
+      join: Box::new(move || handle.join()),
+    });
+    self
+  }
+
+  /// Unpauses a paused thread of `g`'s choosing, returning its
+  /// name, or `None` if no thread is paused.
+  pub fn unpause_any(
+    &self,
+    g: &mut Choices,
+  ) -> Option<&'static str> {
+    let i = unpause_any(g, &self.threads)?;
+    Some(self.members[i].name)
+  }
+
+  pub fn all_paused(&self) -> bool {
+    self.threads.iter().all(ThreadRef::is_paused)
+  }
+
+  /// Joins the threads in the order they were added.
+  pub fn join_all(self) {
+    for member in self.members {
+      (member.join)()
+    }
+  }
+}
+
+impl std::ops::Index<&str> for ThreadSet<'_> {
+  type Output = ThreadRef;
+
+  fn index(&self, name: &str) -> &ThreadRef {
+    match self.members.iter().position(|it| it.name == name) {
+      Some(i) => &self.threads[i],
+      None => panic!("no thread named {name:?}"),
+    }
+  }
+}
+
+impl fmt::Debug for ThreadSet<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_map()
+      .entries(
+        self.members.iter().map(|it| it.name).zip(&self.threads),
+      )
+      .finish()
+  }
+}
+
+#[test]
+fn thread_sets_mix_thread_types() {
+  let reads = Mutex::new(Vec::new());
+  crate::explore::Config::new().exhaustive().run(|g| {
+    let value = AtomicU32::new(0);
+    std::thread::scope(|scope| {
+      let writer = spawn(scope, &value);
+      writer.submit(|value| value.store(1, SeqCst));
+      let reader =
+        spawn_with(scope, &reads, |reads, value: &AtomicU32| {
+          let read = value.load(SeqCst);
+          reads.lock().unwrap().push(read)
+        });
+      reader.send(&value);
+
+      let mut threads = ThreadSet::new();
+      threads.insert("writer", writer).insert("reader", reader);
+      assert!(threads.all_paused());
+      assert_eq!(threads["reader"].label(), None);
+      while threads.unpause_any(g).is_some() {}
+      threads.join_all();
+    });
+  });
+  let mut reads = reads.into_inner().unwrap();
+  reads.sort();
+  reads.dedup();
+  assert_eq!(reads, [0, 1]);
+}
+
+#[test]
+fn raw_threads_are_escapes() {
+  let counter = AtomicU32::new(0);
+  let line = atomic::AtomicU32::new(0);
+  std::thread::scope(|scope| {
+    let t = spawn(scope, (&counter, &line));
+    t.submit(|&mut (counter, line)| {
+      std::thread::scope(|raw| {
+        raw.spawn(|| {
+          let (_, here) =
+            (counter.fetch_add(1, SeqCst), line!());
+          line.store(here, SeqCst);
+        });
+      });
+    });
+    t.join();
+  });
+  assert_eq!(counter.load(SeqCst), 1);
+  let line = line.load(SeqCst);
+  assert!(escapes()
+    .iter()
+    .any(|it| it.file() == file!() && it.line() == line));
+}
+
+#[test]
+fn registry_tracks_live_threads() {
+  let log = Mutex::new(Vec::new());
+  std::thread::scope(|scope| {
+    let mut threads: Vec<_> = (0..3)
+      .map(|id| {
+        let t = spawn(scope, &log);
+        t.submit(move |log| {
+          crate::checkpoint();
+          log.lock().unwrap().push(id);
+        });
+        t
+      })
+      .collect();
+    assert_eq!(live_threads().len(), 3);
+    while let Some(t) = paused_threads().pop() {
+      t.unpause();
+    }
+    drop(threads.remove(0));
+    assert_eq!(live_threads().len(), 2);
+  });
+  assert!(live_threads().is_empty());
+  assert_eq!(*log.lock().unwrap(), [2, 1, 0]);
+}
+
+#[test]
+fn quiescence_waits_for_helpers() {
+  std::thread::scope(|scope| {
+    let threads: Vec<_> = (0..3)
+      .map(|_| {
+        let t = spawn(scope, ());
+        t.submit(|()| {
+          crate::checkpoint();
+          std::thread::sleep(Duration::from_millis(10));
+        });
+        t
+      })
+      .collect();
+    threads[1].unpause();
+    let refs: Vec<_> =
+      threads.iter().map(|it| it.thread_ref()).collect();
+    let helper = refs[2].clone();
+    scope.spawn(move || helper.unpause());
+    while refs[2].is_paused() {
+      std::thread::yield_now();
+    }
+    let quiescence = wait_all_paused(&refs);
+    assert_eq!(quiescence.paused, [0]);
+    assert_eq!(quiescence.finished, [1, 2]);
+  });
+}
+
+#[test]
+fn blocking_sections_are_one_step() {
+  std::thread::scope(|scope| {
+    let t = spawn(scope, ());
+    t.submit(|()| {
+      blocking(|| {
+        crate::checkpoint();
+        crate::checkpoint();
+      });
+      crate::checkpoint();
+    });
+    let mut steps = 0;
+    while t.is_paused() {
+      t.unpause();
+      steps += 1;
+    }
+    assert_eq!(steps, 2);
+    t.join();
+  });
 }
@@ -0,0 +1,1723 @@
+//! A weak memory model for the instrumented atomics.
+//!
+//! Without it, every atomic operation acts on the one current
+//! value, as if it were `SeqCst`, which hides bugs where an
+//! ordering is too weak. With [`Config::weak_memory`], each
+//! location instead keeps the history of its stores in
+//! modification order, and each thread a vector clock. A load
+//! may return any store which is not older than what the thread
+//! has already observed or what happens-before it:
+//!
+//! - a thread never reads a store older than the last one it
+//!   read or wrote at the same location,
+//! - a store that happens-before the load hides every older
+//!   store,
+//! - an `Acquire` load reading a `Release` store (or a
+//!   read-modify-write continuing its release sequence) joins
+//!   the storing thread's clock,
+//! - read-modify-writes read the latest store, and `SeqCst`
+//!   loads nothing older than the latest `SeqCst` store,
+//! - a `Release` fence makes the thread's later stores release
+//!   what came before the fence, and an `Acquire` fence acquires
+//!   what the thread's earlier relaxed loads read,
+//! - `SeqCst` fences are ordered among themselves: each one
+//!   acquires everything before every earlier one. This is
+//!   stronger than C++ requires.
+//!
+//! Which of the allowed stores a load returns is a decision of
+//! the controller, like picking a thread. The first option is
+//! always the latest store, so schedules padded with zeros read
+//! fresh values.
+//!
+//! The model also lints for message passing without
+//! synchronization, see [`MissingSync`].
+//!
+//! [`minimal_orderings`] uses the model the other way around, to
+//! find out which orderings could be weaker than they are, and
+//! [`trace`] shows which store each load read and which release
+//! each acquire synchronized with, and what that made
+//! happen-before what: as a graph, or by the vector clock of
+//! each event.
+//!
+//! [`Config::weak_memory`]: crate::explore::Config::weak_memory
+
+use std::{
+  cell::RefCell,
+  collections::HashMap,
+  fmt,
+  panic::{self, AssertUnwindSafe},
+  sync::{atomic::Ordering, Arc, Mutex},
+};
+
+use crate::explore::Config;
+
+/// Where in the source an atomic operation is.
+type Site = &'static std::panic::Location<'static>;
+
+/// Which memory model [`Config`] explores under.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum MemoryModel {
+  /// No model, atomics act on their current value.
+  SeqCst,
+  /// The model with x86 semantics substituted for the orderings,
+  /// see [`Config::tso`].
+  Tso,
+  Weak,
+}
+
+/// The memory of one execution.
+#[derive(Default)]
+pub(crate) struct Memory {
+  /// Under TSO, a store stays in the storing thread's buffer,
+  /// readable only by that thread, until some other thread reads
+  /// it or the storing thread executes a barrier. In memory,
+  /// that "commits" it and the thread's earlier stores: every
+  /// load from then on reads them or something newer.
+  tso: bool,
+  /// How many stores back a load may read, see
+  /// [`Config::reorder_window`].
+  window: Option<usize>,
+  state: Mutex<State>,
+  /// Orderings to use instead of the ones written at these
+  /// sites.
+  overrides: HashMap<Site, Ordering>,
+  /// Every operation seen, shared between executions.
+  ops: Arc<Mutex<Vec<Op>>>,
+  /// Lints reported so far, shared between executions.
+  lints: Arc<Mutex<Vec<MissingSync>>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Op {
+  site: Site,
+  access: Access,
+  ordering: Ordering,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Access {
+  Load,
+  Store,
+  Rmw,
+  Fence,
+}
+
+#[derive(Default)]
+struct State {
+  clocks: Vec<VectorClock>,
+  fences: Vec<Fences>,
+  /// The clock of the last `SeqCst` fence.
+  seq_cst: VectorClock,
+  locations: HashMap<usize, Location>,
+  events: Vec<Event>,
+  /// Per reader and writer, the newest store the reader loaded
+  /// from the writer without synchronizing with it.
+  unsynchronized: Vec<HashMap<usize, Unsynchronized>>,
+  /// Indexed like `events`, the clock of the thread right after
+  /// each, kept only for a [`trace`].
+  event_clocks: Option<Vec<VectorClock>>,
+}
+
+impl State {
+  /// Keeps the clock of the event `tid` just made, if tracing.
+  fn record(&mut self, tid: usize) {
+    if let Some(event_clocks) = &mut self.event_clocks {
+      event_clocks.push(self.clocks[tid].clone());
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+struct Unsynchronized {
+  time: u32,
+  store: Site,
+  load: Site,
+}
+
+/// What a thread's fences carry over to its later operations.
+#[derive(Default)]
+struct Fences {
+  /// The clock and event of the last `Release` fence.
+  release: Option<(VectorClock, usize)>,
+  /// What relaxed loads have read since, for the next `Acquire`
+  /// fence.
+  acquire: VectorClock,
+  acquire_head: Option<usize>,
+}
+
+impl Fences {
+  /// Synchronizes with `store`, now if `ordering` acquires,
+  /// otherwise at the next `Acquire` fence. Returns the release
+  /// it synchronized with.
+  fn read(
+    &mut self,
+    ordering: Ordering,
+    store: &Store,
+    clock: &mut VectorClock,
+  ) -> Option<usize> {
+    let release = store.release.as_ref()?;
+    if acquires(ordering) {
+      clock.join(release);
+      return store.head;
+    }
+    self.acquire.join(release);
+    self.acquire_head = store.head;
+    None
+  }
+
+  /// The release clock and head event of a store at `event`.
+  fn write(
+    &self,
+    ordering: Ordering,
+    clock: &VectorClock,
+    event: usize,
+  ) -> (Option<VectorClock>, Option<usize>) {
+    if releases(ordering) {
+      return (Some(clock.clone()), Some(event));
+    }
+    match &self.release {
+      Some((clock, event)) => {
+        (Some(clock.clone()), Some(*event))
+      }
+      None => (None, None),
+    }
+  }
+}
+
+struct Location {
+  /// In modification order, the last one is the current value.
+  stores: Vec<Store>,
+  /// The newest store committed under TSO, see [`Memory::tso`].
+  committed: usize,
+  /// Per thread, the index of the newest store it has observed.
+  seen: Vec<usize>,
+}
+
+struct Store {
+  value: u64,
+  /// `None` for the value the location had when first accessed.
+  tid: Option<usize>,
+  /// The storing thread's clock entry at the time of the store.
+  time: u32,
+  /// The clock an `Acquire` load of this store synchronizes
+  /// with.
+  release: Option<VectorClock>,
+  seq_cst: bool,
+  /// `None` for the initial value.
+  event: Option<usize>,
+  /// The release store heading the release sequence this store
+  /// is in.
+  head: Option<usize>,
+}
+
+/// Per thread, how many of its steps the clock's owner has seen
+/// happen-before, see [`Trace::clock`].
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
+pub struct VectorClock(Vec<u32>);
+
+impl VectorClock {
+  /// The entry of thread `tid`, numbered like [`Event::thread`].
+  pub fn get(&self, tid: usize) -> u32 {
+    self.0.get(tid).copied().unwrap_or(0)
+  }
+
+  /// Whether everything `self` has seen, `other` has seen too.
+  pub fn le(&self, other: &VectorClock) -> bool {
+    (0..self.0.len()).all(|tid| self.get(tid) <= other.get(tid))
+  }
+
+  fn tick(&mut self, tid: usize) -> u32 {
+    if self.0.len() <= tid {
+      self.0.resize(tid + 1, 0);
+    }
+    self.0[tid] += 1;
+    self.0[tid]
+  }
+
+  fn join(&mut self, other: &VectorClock) {
+    if self.0.len() < other.0.len() {
+      self.0.resize(other.0.len(), 0);
+    }
+    for (mine, theirs) in self.0.iter_mut().zip(&other.0) {
+      *mine = (*mine).max(*theirs);
+    }
+  }
+}
+
+/// See [`Thread::release`].
+pub(crate) struct Released(VectorClock);
+
+/// A managed thread taking part in an execution's memory.
+#[derive(Clone)]
+pub(crate) struct Thread {
+  memory: Arc<Memory>,
+  tid: usize,
+}
+
+thread_local! {
+  static CURRENT: RefCell<Option<Thread>> =
+    const { RefCell::new(None) };
+}
+
+impl Memory {
+  pub(crate) fn new(config: &Config) -> Memory {
+    Memory {
+      tso: config.memory_model() == MemoryModel::Tso,
+      window: config.get_reorder_window(),
+      ..Default::default()
+    }
+  }
+
+  /// Shares the lints reported with the other executions using
+  /// `lints`, so that each is printed once.
+  pub(crate) fn lints(
+    mut self,
+    lints: Arc<Mutex<Vec<MissingSync>>>,
+  ) -> Memory {
+    self.lints = lints;
+    self
+  }
+
+  /// Keeps what a [`Trace`] needs beyond the events.
+  fn traced(mut self) -> Memory {
+    self.state.get_mut().unwrap().event_clocks =
+      Some(Vec::new());
+    self
+  }
+
+  fn report(&self, lint: MissingSync) {
+    let mut lints = self.lints.lock().unwrap();
+    if !lints.iter().any(|it| it.same_sites(&lint)) {
+      eprintln!("warning: {lint}");
+      lints.push(lint);
+    }
+  }
+
+  /// Registers a new thread, which happens-after everything
+  /// `parent` did so far.
+  pub(crate) fn spawn(
+    self: &Arc<Memory>,
+    parent: Option<&Thread>,
+  ) -> Thread {
+    let mut state = self.state.lock().unwrap();
+    let tid = state.clocks.len();
+    let mut clock = match parent {
+      Some(parent) => {
+        let clock = &mut state.clocks[parent.tid];
+        clock.tick(parent.tid);
+        clock.clone()
+      }
+      None => VectorClock::default(),
+    };
+    clock.tick(tid);
+    state.clocks.push(clock);
+    state.fences.push(Fences::default());
+    state.unsynchronized.push(HashMap::new());
+    Thread { memory: Arc::clone(self), tid }
+  }
+
+  /// Records the operation and returns the ordering to simulate
+  /// it with.
+  fn ordering(&self, op: Op) -> Ordering {
+    let mut ops = self.ops.lock().unwrap();
+    if !ops.contains(&op) {
+      ops.push(op);
+    }
+    let ordering = self
+      .overrides
+      .get(op.site)
+      .copied()
+      .unwrap_or(op.ordering);
+    if !self.tso {
+      return ordering;
+    }
+    // Every x86 load acquires and every store releases, a
+    // `SeqCst` store and every read-modify-write are full
+    // barriers.
+    match (op.access, ordering) {
+      (Access::Load, _) => Ordering::Acquire,
+      (Access::Store, Ordering::SeqCst) => Ordering::SeqCst,
+      (Access::Store, _) => Ordering::Release,
+      (Access::Rmw, _) => Ordering::SeqCst,
+      (Access::Fence, _) => ordering,
+    }
+  }
+}
+
+impl Thread {
+  pub(crate) fn current() -> Option<Thread> {
+    CURRENT.with(|it| it.borrow().clone())
+  }
+
+  /// Makes `self` the memory identity of the calling OS thread.
+  pub(crate) fn enter(self) {
+    CURRENT.with(|it| *it.borrow_mut() = Some(self));
+  }
+
+  /// Undoes [`Thread::enter`].
+  pub(crate) fn exit() {
+    CURRENT.with(|it| *it.borrow_mut() = None);
+  }
+
+  /// Lets every store made so far reach the thread, as they all
+  /// do eventually, without synchronizing with them. Returns
+  /// whether there was any it couldn't read yet.
+  pub(crate) fn catch_up(&self) -> bool {
+    let mut state = self.memory.state.lock().unwrap();
+    let mut caught_up = false;
+    for location in state.locations.values_mut() {
+      let newest = location.stores.len() - 1;
+      if location.seen.get(self.tid).copied().unwrap_or(0)
+        < newest
+      {
+        location.observe(self.tid, newest);
+        caught_up = true;
+      }
+    }
+    caught_up
+  }
+
+  /// What the thread did so far, for another one to [`acquire`],
+  /// like a message handed over through a channel.
+  ///
+  /// [`acquire`]: Thread::acquire
+  pub(crate) fn release(&self) -> Released {
+    let mut state = self.memory.state.lock().unwrap();
+    let clock = &mut state.clocks[self.tid];
+    clock.tick(self.tid);
+    Released(clock.clone())
+  }
+
+  /// Everything before `released` happens-before what `self`
+  /// does next.
+  pub(crate) fn acquire(&self, released: &Released) {
+    let mut state = self.memory.state.lock().unwrap();
+    state.clocks[self.tid].join(&released.0);
+  }
+
+  /// Everything `other` did happens-before what `self` does
+  /// next.
+  pub(crate) fn join(&self, other: &Thread) {
+    let mut state = self.memory.state.lock().unwrap();
+    let theirs = state.clocks[other.tid].clone();
+    state.clocks[self.tid].join(&theirs);
+  }
+
+  fn load(
+    &self,
+    addr: usize,
+    latest: u64,
+    ordering: Ordering,
+    site: Site,
+  ) -> u64 {
+    let access = Access::Load;
+    let ordering =
+      self.memory.ordering(Op { site, access, ordering });
+    let mut state = self.memory.state.lock().unwrap();
+    let tid = self.tid;
+    state.clocks[tid].tick(tid);
+    let oldest = {
+      let State { clocks, locations, .. } = &mut *state;
+      let location = Location::get(locations, addr, latest);
+      location.oldest_visible(tid, &clocks[tid], ordering)
+    };
+    let newest = state.locations[&addr].stores.len() - 1;
+    let stale = (newest - oldest)
+      .min(self.memory.window.unwrap_or(usize::MAX));
+    let index = if stale == 0 {
+      newest
+    } else {
+      // 0 reads the latest value, `n` the `n`-th one before it.
+      drop(state);
+      let choice = crate::loom_compat::choose(stale);
+      state = self.memory.state.lock().unwrap();
+      newest - choice
+    };
+    let State {
+      clocks,
+      fences,
+      locations,
+      events,
+      unsynchronized,
+      ..
+    } = &mut *state;
+    let location = locations.get_mut(&addr).unwrap();
+    location.observe(tid, index);
+    let store = &location.stores[index];
+    let mut lint = None;
+    if let Some(writer) = store.tid.filter(|&it| it != tid) {
+      let synchronized = clocks[tid].get(writer);
+      if let Some(flag) = unsynchronized[tid].get(&writer) {
+        if store.time < flag.time && synchronized < store.time {
+          lint = Some(MissingSync {
+            flag_store: flag.store,
+            flag_load: flag.load,
+            data_load: site,
+          });
+        }
+      }
+    }
+    let synchronizes_with =
+      fences[tid].read(ordering, store, &mut clocks[tid]);
+    if let Some(writer) = store.tid.filter(|&it| it != tid) {
+      let newest = unsynchronized[tid]
+        .get(&writer)
+        .is_none_or(|it| it.time < store.time);
+      if clocks[tid].get(writer) < store.time && newest {
+        let event = &events[store.event.unwrap()];
+        unsynchronized[tid].insert(
+          writer,
+          Unsynchronized {
+            time: store.time,
+            store: event.site,
+            load: site,
+          },
+        );
+      }
+    }
+    let (value, reads_from) = (store.value, store.event);
+    let (writer, time) = (store.tid, store.time);
+    if let Some(writer) = writer.filter(|&it| it != tid) {
+      if self.memory.tso {
+        commit(locations, writer, time);
+      }
+    }
+    events.push(Event {
+      thread: tid,
+      site,
+      access,
+      ordering,
+      value,
+      reads_from,
+      synchronizes_with,
+    });
+    state.record(tid);
+    drop(state);
+    if let Some(lint) = lint {
+      self.memory.report(lint);
+    }
+    value
+  }
+
+  fn store(
+    &self,
+    addr: usize,
+    old: u64,
+    new: u64,
+    ordering: Ordering,
+    site: Site,
+  ) {
+    let access = Access::Store;
+    let ordering =
+      self.memory.ordering(Op { site, access, ordering });
+    let mut state = self.memory.state.lock().unwrap();
+    let State { clocks, fences, locations, events, .. } =
+      &mut *state;
+    let clock = &mut clocks[self.tid];
+    let time = clock.tick(self.tid);
+    let location = Location::get(locations, addr, old);
+    let event = events.len();
+    let (release, head) =
+      fences[self.tid].write(ordering, clock, event);
+    location.stores.push(Store {
+      value: new,
+      tid: Some(self.tid),
+      time,
+      release,
+      seq_cst: ordering == Ordering::SeqCst,
+      event: Some(event),
+      head,
+    });
+    location.observe(self.tid, location.stores.len() - 1);
+    if self.memory.tso && ordering == Ordering::SeqCst {
+      commit(locations, self.tid, time);
+    }
+    events.push(Event {
+      thread: self.tid,
+      site,
+      access,
+      ordering,
+      value: new,
+      reads_from: None,
+      synchronizes_with: None,
+    });
+    state.record(self.tid);
+  }
+
+  #[cfg(any(test, feature = "pause"))]
+  fn forget(&self, addr: usize) {
+    self.memory.state.lock().unwrap().locations.remove(&addr);
+  }
+
+  fn rmw(
+    &self,
+    addr: usize,
+    old: u64,
+    new: u64,
+    ordering: Ordering,
+    site: Site,
+  ) {
+    let access = Access::Rmw;
+    let ordering =
+      self.memory.ordering(Op { site, access, ordering });
+    let mut state = self.memory.state.lock().unwrap();
+    let State { clocks, fences, locations, events, .. } =
+      &mut *state;
+    let clock = &mut clocks[self.tid];
+    let fences = &mut fences[self.tid];
+    let time = clock.tick(self.tid);
+    let location = Location::get(locations, addr, old);
+    let read = location.stores.last().unwrap();
+    let synchronizes_with = fences.read(ordering, read, clock);
+    // A read-modify-write continues the release sequence it
+    // reads.
+    let event = events.len();
+    let (mut release, mut head) =
+      (read.release.clone(), read.head);
+    if let (Some(own), own_head) =
+      fences.write(ordering, clock, event)
+    {
+      release.get_or_insert_with(Default::default).join(&own);
+      head = own_head;
+    }
+    let reads_from = read.event;
+    let writer = read.tid.map(|it| (it, read.time));
+    location.stores.push(Store {
+      value: new,
+      tid: Some(self.tid),
+      time,
+      release,
+      seq_cst: ordering == Ordering::SeqCst,
+      event: Some(event),
+      head,
+    });
+    location.observe(self.tid, location.stores.len() - 1);
+    if self.memory.tso {
+      if let Some((writer, time)) = writer {
+        commit(locations, writer, time);
+      }
+      commit(locations, self.tid, time);
+    }
+    events.push(Event {
+      thread: self.tid,
+      site,
+      access,
+      ordering,
+      value: new,
+      reads_from,
+      synchronizes_with,
+    });
+    state.record(self.tid);
+  }
+
+  fn fence(&self, ordering: Ordering, site: Site) {
+    let access = Access::Fence;
+    let ordering =
+      self.memory.ordering(Op { site, access, ordering });
+    let mut state = self.memory.state.lock().unwrap();
+    let State {
+      clocks, fences, seq_cst, locations, events, ..
+    } = &mut *state;
+    let clock = &mut clocks[self.tid];
+    let fences = &mut fences[self.tid];
+    let time = clock.tick(self.tid);
+    let event = events.len();
+    let mut synchronizes_with = None;
+    if acquires(ordering) {
+      clock.join(&fences.acquire);
+      synchronizes_with = fences.acquire_head.take();
+    }
+    if ordering == Ordering::SeqCst {
+      clock.join(seq_cst);
+      *seq_cst = clock.clone();
+      if self.memory.tso {
+        commit(locations, self.tid, time);
+      }
+    }
+    if releases(ordering) {
+      fences.release = Some((clock.clone(), event));
+    }
+    events.push(Event {
+      thread: self.tid,
+      site,
+      access,
+      ordering,
+      value: 0,
+      reads_from: None,
+      synchronizes_with,
+    });
+    state.record(self.tid);
+  }
+}
+
+impl Location {
+  /// Looks up the history of `addr`, whose current value is
+  /// `latest`.
+  ///
+  /// A mismatch means something outside of the model wrote to
+  /// the address, most likely a new atomic allocated where a
+  /// dead one used to be, so the history starts over.
+  fn get(
+    locations: &mut HashMap<usize, Location>,
+    addr: usize,
+    latest: u64,
+  ) -> &mut Location {
+    let location =
+      locations.entry(addr).or_insert_with(|| Location {
+        stores: Vec::new(),
+        committed: 0,
+        seen: Vec::new(),
+      });
+    if location.stores.last().map(|it| it.value) != Some(latest)
+    {
+      location.stores = vec![Store {
+        value: latest,
+        tid: None,
+        time: 0,
+        release: None,
+        seq_cst: false,
+        event: None,
+        head: None,
+      }];
+      location.committed = 0;
+      location.seen.clear();
+    }
+    location
+  }
+
+  fn oldest_visible(
+    &self,
+    tid: usize,
+    clock: &VectorClock,
+    ordering: Ordering,
+  ) -> usize {
+    let mut seen = self.seen.get(tid).copied().unwrap_or(0);
+    if ordering == Ordering::SeqCst {
+      let seq_cst =
+        self.stores.iter().rposition(|it| it.seq_cst);
+      seen = seen.max(seq_cst.unwrap_or(0));
+    }
+    let happened_before = self
+      .stores
+      .iter()
+      .rposition(|store| match store.tid {
+        None => true,
+        Some(t) => store.time <= clock.get(t),
+      })
+      .unwrap();
+    seen.max(happened_before).max(self.committed)
+  }
+
+  fn observe(&mut self, tid: usize, index: usize) {
+    if self.seen.len() <= tid {
+      self.seen.resize(tid + 1, 0);
+    }
+    self.seen[tid] = self.seen[tid].max(index);
+  }
+}
+
+/// Under TSO, commits the stores `tid` made up to `time`.
+fn commit(
+  locations: &mut HashMap<usize, Location>,
+  tid: usize,
+  time: u32,
+) {
+  for location in locations.values_mut() {
+    let newest = location.stores.iter().rposition(|store| {
+      store.tid == Some(tid) && store.time <= time
+    });
+    if let Some(newest) = newest {
+      location.committed = location.committed.max(newest);
+    }
+  }
+}
+
+fn acquires(ordering: Ordering) -> bool {
+  matches!(
+    ordering,
+    Ordering::Acquire | Ordering::AcqRel | Ordering::SeqCst
+  )
+}
+
+fn releases(ordering: Ordering) -> bool {
+  matches!(
+    ordering,
+    Ordering::Release | Ordering::AcqRel | Ordering::SeqCst
+  )
+}
+
+/// The value a load of `addr` returns, given that the current
+/// one is `latest`.
+pub(crate) fn load(
+  addr: usize,
+  latest: u64,
+  ordering: Ordering,
+  site: Site,
+) -> u64 {
+  match Thread::current() {
+    Some(thread) => thread.load(addr, latest, ordering, site),
+    None => latest,
+  }
+}
+
+/// Records a store of `new` over `old`.
+pub(crate) fn store(
+  addr: usize,
+  old: u64,
+  new: u64,
+  ordering: Ordering,
+  site: Site,
+) {
+  if let Some(thread) = Thread::current() {
+    thread.store(addr, old, new, ordering, site)
+  }
+}
+
+pub(crate) fn fence(ordering: Ordering, site: Site) {
+  if let Some(thread) = Thread::current() {
+    thread.fence(ordering, site)
+  }
+}
+
+/// Drops the history of a dead atomic. Otherwise an atomic
+/// allocated at the same address with the latest value would
+/// inherit it, and whether that happens is up to the allocator.
+#[cfg(any(test, feature = "pause"))]
+pub(crate) fn forget(addr: usize) {
+  if let Some(thread) = Thread::current() {
+    thread.forget(addr)
+  }
+}
+
+/// Records a read-modify-write which replaced `old` with `new`.
+pub(crate) fn rmw(
+  addr: usize,
+  old: u64,
+  new: u64,
+  ordering: Ordering,
+  site: Site,
+) {
+  if let Some(thread) = Thread::current() {
+    thread.rmw(addr, old, new, ordering, site)
+  }
+}
+
+/// A load which read a store it doesn't synchronize with, from a
+/// thread it had already seen make a later store, also without
+/// synchronizing. That is message passing, with a flag and the
+/// data it guards, done with relaxed orderings: the data load
+/// works only if it happens to read the newest value, so the
+/// flag store likely needs to be `Release`, and the flag load
+/// `Acquire`.
+///
+/// Reported even if the data load did read the newest value.
+#[derive(Clone, Copy, Debug)]
+pub struct MissingSync {
+  flag_store: Site,
+  flag_load: Site,
+  data_load: Site,
+}
+
+impl MissingSync {
+  pub fn flag_store(
+    &self,
+  ) -> &'static std::panic::Location<'static> {
+    self.flag_store
+  }
+
+  pub fn flag_load(
+    &self,
+  ) -> &'static std::panic::Location<'static> {
+    self.flag_load
+  }
+
+  pub fn data_load(
+    &self,
+  ) -> &'static std::panic::Location<'static> {
+    self.data_load
+  }
+
+  fn same_sites(&self, other: &MissingSync) -> bool {
+    (self.flag_store, self.flag_load, self.data_load)
+      == (other.flag_store, other.flag_load, other.data_load)
+  }
+}
+
+impl fmt::Display for MissingSync {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} reads data published by the store at {} and the load \
+      at {} without synchronization, consider Release and \
+      Acquire",
+      self.data_load, self.flag_store, self.flag_load
+    )
+  }
+}
+
+/// The atomic operations of one execution, in the order they
+/// ran.
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+  events: Vec<Event>,
+  /// See [`State::event_clocks`].
+  clocks: Vec<VectorClock>,
+}
+
+/// One atomic operation. Events are referred to by their index
+/// in [`Trace::events`].
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+  thread: usize,
+  site: Site,
+  access: Access,
+  ordering: Ordering,
+  value: u64,
+  reads_from: Option<usize>,
+  synchronizes_with: Option<usize>,
+}
+
+impl Trace {
+  pub fn events(&self) -> &[Event] {
+    &self.events
+  }
+
+  /// The trace as a Graphviz graph: a column of operations per
+  /// thread, with reads-from edges dashed and synchronizes-with
+  /// edges bold.
+  pub fn dot(&self) -> String {
+    let mut out = columns("trace", &self.events);
+    for (i, event) in self.events.iter().enumerate() {
+      if let Some(store) = event.reads_from {
+        out.push_str(&format!(
+          "  e{store} -> e{i} \
+          [style=dashed, constraint=false];\n"
+        ));
+      }
+      if let Some(release) = event.synchronizes_with {
+        out.push_str(&format!(
+          "  e{release} -> e{i} [style=bold, color=blue];\n"
+        ));
+      }
+    }
+    out.push_str("}\n");
+    out
+  }
+
+  /// What the synchronization in the trace made happen-before
+  /// what.
+  pub fn happens_before(&self) -> HappensBefore {
+    let mut edges = Vec::new();
+    for b in 0..self.events.len() {
+      // Per thread, the latest event happening-before `b`: every
+      // earlier one on that thread happens-before it.
+      let mut latest: Vec<usize> = Vec::new();
+      for a in (0..b).rev() {
+        let thread = self.events[a].thread;
+        let seen = latest
+          .iter()
+          .any(|&it| self.events[it].thread == thread);
+        if !seen && self.ordered(a, b) {
+          latest.push(a);
+        }
+      }
+      for &a in &latest {
+        let implied = latest
+          .iter()
+          .any(|&other| other != a && self.ordered(a, other));
+        if !implied {
+          edges.push((a, b));
+        }
+      }
+    }
+    edges.sort();
+    HappensBefore { events: self.events.clone(), edges }
+  }
+
+  /// The clock of the thread of event `i` right after it. A
+  /// thread's own entry counts more than its atomic operations,
+  /// it also steps for spawns and the synchronization of locks
+  /// and channels.
+  pub fn clock(&self, i: usize) -> &VectorClock {
+    &self.clocks[i]
+  }
+
+  /// Whether event `a` happens-before event `b`: it comes
+  /// earlier on the same thread, or synchronization ordered the
+  /// two. Otherwise they are concurrent, or `b` comes first.
+  pub fn ordered(&self, a: usize, b: usize) -> bool {
+    let thread = self.events[a].thread;
+    a != b
+      && self.clocks[a].get(thread) <= self.clocks[b].get(thread)
+  }
+}
+
+/// The happens-before relation of a [`Trace`], as a graph over
+/// its events with only the edges which don't follow from
+/// others.
+#[derive(Clone, Debug)]
+pub struct HappensBefore {
+  events: Vec<Event>,
+  edges: Vec<(usize, usize)>,
+}
+
+impl HappensBefore {
+  /// Indexed like [`Trace::events`].
+  pub fn events(&self) -> &[Event] {
+    &self.events
+  }
+
+  /// Pairs of events where the first happens-before the second,
+  /// sorted. The relation is their transitive closure.
+  pub fn edges(&self) -> &[(usize, usize)] {
+    &self.edges
+  }
+
+  /// The graph in Graphviz, laid out like [`Trace::dot`], with
+  /// edges between threads bold.
+  pub fn dot(&self) -> String {
+    let mut out = columns("happens_before", &self.events);
+    for &(a, b) in &self.edges {
+      if self.events[a].thread != self.events[b].thread {
+        out.push_str(&format!(
+          "  e{a} -> e{b} [style=bold, color=blue];\n"
+        ));
+      }
+    }
+    out.push_str("}\n");
+    out
+  }
+}
+
+/// The opening of a Graphviz graph of `events`, a column of them
+/// per thread in program order.
+fn columns(name: &str, events: &[Event]) -> String {
+  let mut threads: Vec<usize> =
+    events.iter().map(|it| it.thread).collect();
+  threads.sort();
+  threads.dedup();
+  let mut out =
+    format!("digraph {name} {{\n  node [shape=box];\n");
+  for thread in threads {
+    out.push_str(&format!(
+      "  subgraph cluster_{thread} {{\n    \
+      label=\"thread {thread}\";\n"
+    ));
+    let column =
+      (0..events.len()).filter(|&i| events[i].thread == thread);
+    let mut previous = None;
+    for i in column {
+      let event = &events[i];
+      out.push_str(&format!(
+        "    e{i} [label=\"{} {}\\n{}\"];\n",
+        event.operation(),
+        event.value,
+        event.site
+      ));
+      if let Some(previous) = previous {
+        out.push_str(&format!("    e{previous} -> e{i};\n"));
+      }
+      previous = Some(i);
+    }
+    out.push_str("  }\n");
+  }
+  out
+}
+
+impl Event {
+  /// The memory model's thread id: the first thread is 0, the
+  /// others are numbered in spawn order.
+  pub fn thread(&self) -> usize {
+    self.thread
+  }
+
+  pub fn site(&self) -> &'static std::panic::Location<'static> {
+    self.site
+  }
+
+  /// The ordering simulated, which may differ from the one
+  /// written, see [`minimal_orderings`].
+  pub fn ordering(&self) -> Ordering {
+    self.ordering
+  }
+
+  /// For stores and read-modify-writes, the value written, for
+  /// loads the one read.
+  pub fn value(&self) -> u64 {
+    self.value
+  }
+
+  /// The store this read from, `None` for stores and for reads
+  /// of a location's initial value.
+  pub fn reads_from(&self) -> Option<usize> {
+    self.reads_from
+  }
+
+  /// The release store this acquire synchronized with.
+  pub fn synchronizes_with(&self) -> Option<usize> {
+    self.synchronizes_with
+  }
+
+  pub(crate) fn operation(&self) -> String {
+    let access = match self.access {
+      Access::Load => "load",
+      Access::Store => "store",
+      Access::Rmw => "rmw",
+      Access::Fence => {
+        return format!("fence({:?})", self.ordering)
+      }
+    };
+    format!("{access}({:?})", self.ordering)
+  }
+}
+
+impl fmt::Display for Trace {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (i, event) in self.events.iter().enumerate() {
+      write!(
+        f,
+        "{i:>3}: thread {} {} {} at {}",
+        event.thread,
+        event.operation(),
+        event.value,
+        event.site
+      )?;
+      if let Some(store) = event.reads_from {
+        write!(f, ", reads {store}")?;
+      }
+      if let Some(release) = event.synchronizes_with {
+        write!(f, ", synchronizes with {release}")?;
+      }
+      writeln!(f)?;
+    }
+    Ok(())
+  }
+}
+
+/// Runs `f` once along `schedule`, a schedule printed by a
+/// failed weak memory exploration, and returns the trace of its
+/// atomic operations. The trace is returned even if `f` fails.
+pub fn trace<F>(schedule: &str, f: F) -> Trace
+where
+  F: Fn() + Send + Sync + 'static,
+{
+  let config = Config::new().replay(schedule).weak_memory();
+  let last = Arc::new(Mutex::new(None::<Arc<Memory>>));
+  let result = panic::catch_unwind(AssertUnwindSafe(|| {
+    crate::loom_compat::run_in(
+      config.clone(),
+      || {
+        let memory = Arc::new(Memory::new(&config).traced());
+        *last.lock().unwrap() = Some(Arc::clone(&memory));
+        memory
+      },
+      f,
+    )
+  }));
+  drop(result);
+  let memory = last.lock().unwrap().take();
+  match memory {
+    Some(memory) => {
+      let state = memory.state.lock().unwrap();
+      let clocks =
+        state.event_clocks.clone().unwrap_or_default();
+      Trace { events: state.events.clone(), clocks }
+    }
+    None => Trace::default(),
+  }
+}
+
+/// An atomic operation, with the weakest ordering found to be
+/// enough.
+#[derive(Clone, Copy, Debug)]
+pub struct MinimalOrdering {
+  site: Site,
+  ordering: Ordering,
+  weakest: Ordering,
+}
+
+impl MinimalOrdering {
+  pub fn site(&self) -> &'static std::panic::Location<'static> {
+    self.site
+  }
+
+  /// The ordering written in the source.
+  pub fn ordering(&self) -> Ordering {
+    self.ordering
+  }
+
+  pub fn weakest(&self) -> Ordering {
+    self.weakest
+  }
+}
+
+impl fmt::Display for MinimalOrdering {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.weakest == self.ordering {
+      write!(f, "{}: {:?} is needed", self.site, self.ordering)
+    } else {
+      write!(
+        f,
+        "{}: {:?} could be {:?}",
+        self.site, self.ordering, self.weakest
+      )
+    }
+  }
+}
+
+/// Explores `f` like [`loom_compat::model`] with `config`, then,
+/// for every atomic operation it performed, re-explores with
+/// that operation's ordering weakened, and reports the weakest
+/// one which still passes. Panics if `f` fails to begin with.
+///
+/// Operations are weakened one at a time in source order,
+/// keeping the weakenings found so far, so the reported
+/// orderings pass together. Another order might find a different
+/// combination. The runs of too weak candidates print their
+/// failures as usual.
+///
+/// [`loom_compat::model`]: crate::loom_compat::model
+pub fn minimal_orderings<F>(
+  config: Config,
+  f: F,
+) -> Vec<MinimalOrdering>
+where
+  F: Fn() + Send + Sync + 'static,
+{
+  let f = Arc::new(f);
+  let config = config.weak_memory();
+  let explore = |overrides: HashMap<Site, Ordering>| {
+    let ops = Arc::new(Mutex::new(Vec::new()));
+    let f = Arc::clone(&f);
+    crate::loom_compat::run_in(
+      config.clone(),
+      {
+        let ops = Arc::clone(&ops);
+        let config = config.clone();
+        move || {
+          Arc::new(Memory {
+            overrides: overrides.clone(),
+            ops: Arc::clone(&ops),
+            ..Memory::new(&config)
+          })
+        }
+      },
+      move || f(),
+    );
+    let mut ops = ops.lock().unwrap().clone();
+    ops.sort_by_key(|op| {
+      (op.site.file(), op.site.line(), op.site.column())
+    });
+    ops
+  };
+
+  let ops = explore(HashMap::new());
+  let mut overrides = HashMap::new();
+  let mut result = Vec::new();
+  for op in ops {
+    let weakest = weaker(op)
+      .into_iter()
+      .find(|&ordering| {
+        let mut overrides = overrides.clone();
+        overrides.insert(op.site, ordering);
+        panic::catch_unwind(AssertUnwindSafe(|| {
+          explore(overrides)
+        }))
+        .is_ok()
+      })
+      .unwrap_or(op.ordering);
+    overrides.insert(op.site, weakest);
+    let it = MinimalOrdering {
+      site: op.site,
+      ordering: op.ordering,
+      weakest,
+    };
+    eprintln!("{it}");
+    result.push(it);
+  }
+  result
+}
+
+/// Orderings valid for `op` and weaker than its own, weakest
+/// first.
+fn weaker(op: Op) -> Vec<Ordering> {
+  use Ordering::*;
+  let valid: &[Ordering] = match op.access {
+    Access::Load => &[Relaxed, Acquire],
+    Access::Store => &[Relaxed, Release],
+    Access::Rmw => &[Relaxed, Acquire, Release, AcqRel],
+    Access::Fence => &[Acquire, Release, AcqRel],
+  };
+  let strength = |it| match it {
+    Relaxed => 0,
+    Acquire | Release => 1,
+    AcqRel => 2,
+    _ => 3,
+  };
+  valid
+    .iter()
+    .copied()
+    .filter(|&it| {
+      strength(it) < strength(op.ordering)
+        && (acquires(it) <= acquires(op.ordering))
+        && (releases(it) <= releases(op.ordering))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+fn message_passing(store: Ordering, load: Ordering) {
+  use crate::loom_compat::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+  };
+
+  let config =
+    crate::explore::Config::new().exhaustive().weak_memory();
+  crate::loom_compat::run(config, move || {
+    let data = Arc::new(AtomicUsize::new(0));
+    let flag = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let (data, flag) = (Arc::clone(&data), Arc::clone(&flag));
+      move || {
+        data.store(92, Ordering::Relaxed);
+        flag.store(1, store);
+      }
+    });
+    if flag.load(load) == 1 {
+      assert_eq!(data.load(Ordering::Relaxed), 92);
+    }
+    t.join().unwrap();
+  })
+}
+
+#[test]
+fn release_acquire_message_passing() {
+  message_passing(Ordering::Release, Ordering::Acquire)
+}
+
+#[test]
+#[should_panic]
+fn relaxed_message_passing() {
+  message_passing(Ordering::Relaxed, Ordering::Relaxed)
+}
+
+#[test]
+fn minimal_message_passing() {
+  use crate::loom_compat::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+  };
+  use Ordering::*;
+
+  let config = Config::new().exhaustive();
+  let report = minimal_orderings(config, || {
+    let data = Arc::new(AtomicUsize::new(0));
+    let flag = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let (data, flag) = (Arc::clone(&data), Arc::clone(&flag));
+      move || {
+        data.store(92, SeqCst);
+        flag.store(1, SeqCst);
+      }
+    });
+    if flag.load(SeqCst) == 1 {
+      assert_eq!(data.load(SeqCst), 92);
+    }
+    t.join().unwrap();
+  });
+  let weakest: Vec<Ordering> =
+    report.iter().map(|it| it.weakest()).collect();
+  assert_eq!(weakest, [Relaxed, Release, Acquire, Relaxed]);
+}
+
+#[test]
+fn trace_shows_synchronization() {
+  use crate::loom_compat::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+  };
+
+  // The spawned thread runs to completion before main looks at
+  // the flag.
+  let trace = trace("0.1.1.1.1.0.0.0", || {
+    let data = Arc::new(AtomicUsize::new(0));
+    let flag = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let (data, flag) = (Arc::clone(&data), Arc::clone(&flag));
+      move || {
+        data.store(92, Ordering::Relaxed);
+        flag.store(1, Ordering::Release);
+      }
+    });
+    while flag.load(Ordering::Acquire) == 0 {}
+    assert_eq!(data.load(Ordering::Relaxed), 92);
+    t.join().unwrap();
+  });
+  let events = trace.events();
+  let data =
+    events.iter().position(|it| it.value() == 92).unwrap();
+  let flag = events
+    .iter()
+    .position(|it| it.reads_from().is_none() && it.value() == 1)
+    .unwrap();
+  let acquire = events
+    .iter()
+    .position(|it| it.synchronizes_with() == Some(flag))
+    .unwrap();
+  assert_eq!(events[acquire].reads_from(), Some(flag));
+  assert!(events[acquire + 1..]
+    .iter()
+    .any(|it| it.reads_from() == Some(data)));
+  assert!(trace
+    .dot()
+    .contains(&format!("e{flag} -> e{acquire} [style=bold")));
+}
+
+#[test]
+fn happens_before_follows_synchronization() {
+  use crate::loom_compat::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+  };
+
+  let edges = |load: Ordering| {
+    let trace = trace("0.1.1.1.1.0.0.0", move || {
+      let data = Arc::new(AtomicUsize::new(0));
+      let flag = Arc::new(AtomicUsize::new(0));
+      let t = thread::spawn({
+        let (data, flag) =
+          (Arc::clone(&data), Arc::clone(&flag));
+        move || {
+          data.store(92, Ordering::Relaxed);
+          flag.store(1, Ordering::Release);
+        }
+      });
+      while flag.load(load) == 0 {}
+      data.load(Ordering::Relaxed);
+      t.join().unwrap();
+    });
+    let graph = trace.happens_before();
+    let events = graph.events();
+    graph
+      .edges()
+      .iter()
+      .filter(|&&(a, b)| {
+        events[a].thread() != events[b].thread()
+      })
+      .map(|&(a, b)| {
+        (events[a].operation(), events[b].operation())
+      })
+      .collect::<Vec<_>>()
+  };
+  let store = "store(Release)".to_string();
+  let load = "load(Acquire)".to_string();
+  assert_eq!(edges(Ordering::Acquire), [(store, load)]);
+  assert_eq!(edges(Ordering::Relaxed), []);
+}
+
+#[test]
+fn clocks_order_events() {
+  use crate::loom_compat::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+  };
+
+  let trace = trace("0.1.1.1.1.0.0.0", || {
+    let data = Arc::new(AtomicUsize::new(0));
+    let flag = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let (data, flag) = (Arc::clone(&data), Arc::clone(&flag));
+      move || {
+        data.store(92, Ordering::Release);
+        flag.store(1, Ordering::Relaxed);
+      }
+    });
+    // Only the data store is synchronized with.
+    data.fetch_add(0, Ordering::Acquire);
+    flag.load(Ordering::Relaxed);
+    t.join().unwrap();
+  });
+  let [store, flag, rmw, load] = [0, 1, 2, 3];
+  let events = trace.events();
+  assert_eq!(events[rmw].reads_from(), Some(store));
+  assert_eq!(events[load].reads_from(), Some(flag));
+  assert!(trace.ordered(store, flag));
+  assert!(trace.ordered(store, load));
+  assert!(!trace.ordered(flag, load));
+  assert!(!trace.ordered(load, flag));
+  assert!(trace.clock(store).le(trace.clock(rmw)));
+  assert!(!trace.clock(flag).le(trace.clock(load)));
+  let writer = events[store].thread();
+  assert_eq!(
+    trace.clock(load).get(writer),
+    trace.clock(store).get(writer)
+  );
+}
+
+/// Values `x` may have once `y` is seen set.
+#[cfg(test)]
+fn stale_values(
+  config: Config,
+) -> std::collections::BTreeSet<usize> {
+  use std::collections::BTreeSet;
+
+  use crate::loom_compat::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+  };
+
+  let seen = Arc::new(Mutex::new(BTreeSet::new()));
+  crate::loom_compat::run(config, {
+    let seen = Arc::clone(&seen);
+    move || {
+      let x = Arc::new(AtomicUsize::new(0));
+      let y = Arc::new(AtomicUsize::new(0));
+      let t = thread::spawn({
+        let (x, y) = (Arc::clone(&x), Arc::clone(&y));
+        move || {
+          x.store(1, Ordering::Relaxed);
+          x.store(2, Ordering::Relaxed);
+          y.store(1, Ordering::Relaxed);
+        }
+      });
+      if y.load(Ordering::Relaxed) == 1 {
+        let value = x.load(Ordering::Relaxed);
+        seen.lock().unwrap().insert(value);
+      }
+      t.join().unwrap();
+    }
+  });
+  let seen = seen.lock().unwrap().clone();
+  seen
+}
+
+#[test]
+fn forgotten_addresses_start_over() {
+  use std::collections::BTreeSet;
+
+  use crate::loom_compat::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+  };
+
+  let seen = Arc::new(Mutex::new(BTreeSet::new()));
+  let config = Config::new().exhaustive().weak_memory();
+  crate::loom_compat::run(config, {
+    let seen = Arc::clone(&seen);
+    move || {
+      // Stands in for two atomics, one after the other.
+      let addr = 8;
+      let site = panic::Location::caller();
+      let y = Arc::new(AtomicUsize::new(0));
+      let t = thread::spawn({
+        let y = Arc::clone(&y);
+        move || {
+          store(addr, 0, 1, Ordering::Relaxed, site);
+          store(addr, 1, 2, Ordering::Relaxed, site);
+          forget(addr);
+          y.store(1, Ordering::Relaxed);
+        }
+      });
+      if y.load(Ordering::Relaxed) == 1 {
+        let value = load(addr, 2, Ordering::Relaxed, site);
+        seen.lock().unwrap().insert(value);
+      }
+      t.join().unwrap();
+    }
+  });
+  assert_eq!(*seen.lock().unwrap(), [2].into());
+}
+
+#[test]
+fn exhaustive_reads_every_stale_value() {
+  let config = Config::new().exhaustive().weak_memory();
+  assert_eq!(stale_values(config), [0, 1, 2].into());
+}
+
+#[test]
+fn reorder_window_bounds_staleness() {
+  let config = Config::new().exhaustive().weak_memory();
+  assert_eq!(
+    stale_values(config.clone().reorder_window(1)),
+    [1, 2].into()
+  );
+  assert_eq!(stale_values(config.reorder_window(0)), [2].into());
+}
+
+#[test]
+fn fence_message_passing() {
+  use crate::loom_compat::{
+    sync::{
+      atomic::{fence, AtomicUsize},
+      Arc,
+    },
+    thread,
+  };
+
+  crate::loom_compat::model(|| {
+    let data = Arc::new(AtomicUsize::new(0));
+    let flag = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let (data, flag) = (Arc::clone(&data), Arc::clone(&flag));
+      move || {
+        data.store(92, Ordering::Relaxed);
+        fence(Ordering::Release);
+        flag.store(1, Ordering::Relaxed);
+      }
+    });
+    if flag.load(Ordering::Relaxed) == 1 {
+      fence(Ordering::Acquire);
+      assert_eq!(data.load(Ordering::Relaxed), 92);
+    }
+    t.join().unwrap();
+  })
+}
+
+#[test]
+fn seq_cst_fences_forbid_store_buffering() {
+  use crate::loom_compat::{
+    sync::{
+      atomic::{fence, AtomicUsize},
+      Arc,
+    },
+    thread,
+  };
+
+  crate::loom_compat::model(|| {
+    let x = Arc::new(AtomicUsize::new(0));
+    let y = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let (x, y) = (Arc::clone(&x), Arc::clone(&y));
+      move || {
+        y.store(1, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        x.load(Ordering::Relaxed)
+      }
+    });
+    x.store(1, Ordering::Relaxed);
+    fence(Ordering::SeqCst);
+    let y = y.load(Ordering::Relaxed);
+    let x = t.join().unwrap();
+    assert!(x == 1 || y == 1);
+  })
+}
+
+#[cfg(test)]
+fn store_buffering(config: Config, fence: bool) {
+  use crate::loom_compat::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+  };
+
+  let barrier = move || {
+    if fence {
+      crate::atomic::fence(Ordering::SeqCst)
+    }
+  };
+  crate::loom_compat::run(config, move || {
+    let x = Arc::new(AtomicUsize::new(0));
+    let y = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let (x, y) = (Arc::clone(&x), Arc::clone(&y));
+      move || {
+        y.store(1, Ordering::Release);
+        barrier();
+        x.load(Ordering::Acquire)
+      }
+    });
+    x.store(1, Ordering::Release);
+    barrier();
+    let y = y.load(Ordering::Acquire);
+    let x = t.join().unwrap();
+    assert!(x == 1 || y == 1);
+  })
+}
+
+#[test]
+#[should_panic]
+fn tso_allows_store_buffering() {
+  store_buffering(Config::new().exhaustive().tso(), false)
+}
+
+#[test]
+fn tso_forbids_store_buffering_with_fences() {
+  store_buffering(Config::new().exhaustive().tso(), true)
+}
+
+#[test]
+fn tso_message_passing() {
+  use crate::loom_compat::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+  };
+
+  // Relaxed is as good as release/acquire on x86.
+  let config = Config::new().exhaustive().tso();
+  crate::loom_compat::run(config, || {
+    let data = Arc::new(AtomicUsize::new(0));
+    let flag = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let (data, flag) = (Arc::clone(&data), Arc::clone(&flag));
+      move || {
+        data.store(92, Ordering::Relaxed);
+        flag.store(1, Ordering::Relaxed);
+      }
+    });
+    if flag.load(Ordering::Relaxed) == 1 {
+      assert_eq!(data.load(Ordering::Relaxed), 92);
+    }
+    t.join().unwrap();
+  })
+}
+
+#[cfg(test)]
+fn message_passing_lints(
+  store: Ordering,
+  load: Ordering,
+) -> Vec<MissingSync> {
+  use crate::loom_compat::{sync::atomic::AtomicUsize, thread};
+
+  let lints = Arc::new(Mutex::new(Vec::new()));
+  let config = Config::new().exhaustive().weak_memory();
+  let memory = {
+    let lints = Arc::clone(&lints);
+    move || {
+      let memory = Memory::new(&Config::new().weak_memory());
+      Arc::new(memory.lints(Arc::clone(&lints)))
+    }
+  };
+  crate::loom_compat::run_in(config, memory, move || {
+    let data = Arc::new(AtomicUsize::new(0));
+    let flag = Arc::new(AtomicUsize::new(0));
+    let t = thread::spawn({
+      let (data, flag) = (Arc::clone(&data), Arc::clone(&flag));
+      move || {
+        data.store(92, Ordering::Relaxed);
+        flag.store(1, store);
+      }
+    });
+    if flag.load(load) == 1 {
+      // Doesn't assert, the lint fires regardless.
+      data.load(Ordering::Relaxed);
+    }
+    t.join().unwrap();
+  });
+  let lints = lints.lock().unwrap().clone();
+  lints
+}
+
+#[test]
+fn lints_relaxed_message_passing() {
+  let lints =
+    message_passing_lints(Ordering::Relaxed, Ordering::Relaxed);
+  assert_eq!(lints.len(), 1);
+  assert!(
+    lints[0].flag_store().line() < lints[0].flag_load().line()
+  );
+  assert!(message_passing_lints(
+    Ordering::Release,
+    Ordering::Acquire
+  )
+  .is_empty());
+}
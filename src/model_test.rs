@@ -0,0 +1,1038 @@
+//! Model-based testing of a concurrent type, the `pbt` test as a
+//! library.
+//!
+//! Describe the operations as a type implementing [`Op`],
+//! usually an enum, and hand a [`ModelTest`] the check that the
+//! real value and the model agree:
+//!
+//! ```text
+//! ModelTest::<Counter, u32>::new(Config::new().exhaustive())
+//!   .run::<Increment>(|real, model| real.get() == *model);
+//! ```
+//!
+//! Each iteration submits ops to a few managed threads, applying
+//! each one to the model as it is submitted, and interleaves the
+//! threads as the explorer picks. Once every thread is done, the
+//! real value must agree with the model. For types which promise
+//! less than that, see
+//! [`ModelTest::run_sequentially_consistent`].
+//!
+//! A failing iteration is shrunk before it is reported: ops are
+//! dropped, then the decisions behind the arguments of the rest
+//! and the interleaving are simplified, as long as it still
+//! fails the same way.
+
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  fmt,
+  marker::PhantomData,
+  ops::Range,
+  panic::{self, AssertUnwindSafe},
+  sync::{
+    atomic::{AtomicU64, Ordering::SeqCst},
+    Mutex,
+  },
+};
+
+use crate::{
+  explore::{self, Choices, Config, Failure, Schedule, Stats},
+  managed_thread,
+};
+
+pub use properly_concurrent_macros::ConcurrentOps;
+
+/// An operation on both the real type and its sequential model.
+pub trait Op<Real, Model>: fmt::Debug + Send + Sized {
+  /// What an op returns, like the popped element of a `pop`.
+  type Output: fmt::Debug + Send;
+
+  /// The ops to generate, see [`Generators`].
+  fn generators() -> Generators<Model, Self>;
+
+  /// Runs on a managed thread, concurrently with other ops.
+  fn apply(&self, real: &Real) -> Self::Output;
+
+  fn apply_model(&self, model: &mut Model) -> Self::Output;
+
+  /// Checks what the op returned against what the model did. Ops
+  /// on other threads may run in between, so with more than one
+  /// thread this usually only holds for some ops. By default,
+  /// anything goes.
+  fn postcondition(
+    &self,
+    real: &Self::Output,
+    model: &Self::Output,
+  ) -> bool {
+    let _ = (real, model);
+    true
+  }
+}
+
+/// A generated field of an op, see [`ConcurrentOps`]. Integers
+/// are between 0 and 2, as every distinct value multiplies the
+/// ops which exhaustive exploration has to go through.
+pub trait Generate {
+  fn generate(g: &mut Choices) -> Self;
+}
+
+impl Generate for bool {
+  fn generate(g: &mut Choices) -> bool {
+    g.flip()
+  }
+}
+
+macro_rules! generate_int {
+  ($($int:ty)*) => {$(
+    impl Generate for $int {
+      fn generate(g: &mut Choices) -> $int {
+        g.gen(2) as $int
+      }
+    }
+  )*};
+}
+
+generate_int!(u8 u16 u32 u64 usize i8 i16 i32 i64 isize);
+
+type Precondition<Model> = Box<dyn Fn(&Model) -> bool>;
+type Make<O> = Box<dyn Fn(&mut Choices) -> O>;
+
+/// A weighted choice between kinds of ops, each of which may
+/// only make sense in some states of the model:
+///
+/// ```text
+/// Generators::new()
+///   .op(2, |g| Op::Push(g.gen(9)))
+///   .op_if(1, |model| !model.is_empty(), |_| Op::Pop)
+/// ```
+pub struct Generators<Model, O> {
+  entries: Vec<(u32, Precondition<Model>, Make<O>)>,
+}
+
+impl<Model, O> Default for Generators<Model, O> {
+  fn default() -> Self {
+    Generators { entries: Vec::new() }
+  }
+}
+
+impl<Model, O> Generators<Model, O> {
+  pub fn new() -> Generators<Model, O> {
+    Generators::default()
+  }
+
+  pub fn op(
+    self,
+    weight: u32,
+    make: impl Fn(&mut Choices) -> O + 'static,
+  ) -> Self {
+    self.op_if(weight, |_| true, make)
+  }
+
+  /// Generated only in the states of the model where
+  /// `precondition` holds.
+  pub fn op_if(
+    mut self,
+    weight: u32,
+    precondition: impl Fn(&Model) -> bool + 'static,
+    make: impl Fn(&mut Choices) -> O + 'static,
+  ) -> Self {
+    self.entries.push((
+      weight,
+      Box::new(precondition),
+      Box::new(make),
+    ));
+    self
+  }
+
+  /// Returns `None` if no op is possible in this state.
+  fn generate(
+    &self,
+    g: &mut Choices,
+    model: &Model,
+  ) -> Option<O> {
+    let weights: Vec<u32> = self
+      .entries
+      .iter()
+      .map(|(w, pre, _)| if pre(model) { *w } else { 0 })
+      .collect();
+    if weights.iter().all(|&w| w == 0) {
+      return None;
+    }
+    let (_, _, make) = &self.entries[g.weighted(&weights)];
+    Some(make(g))
+  }
+}
+
+pub struct ModelTest<Real, Model> {
+  config: Config,
+  threads: usize,
+  ops: usize,
+  types: PhantomData<fn() -> (Real, Model)>,
+}
+
+impl<Real, Model> ModelTest<Real, Model>
+where
+  Real: Default + Sync,
+  Model: Default,
+{
+  /// Two threads, four ops per iteration.
+  pub fn new(config: Config) -> ModelTest<Real, Model> {
+    ModelTest { config, threads: 2, ops: 4, types: PhantomData }
+  }
+
+  pub fn threads(mut self, threads: usize) -> Self {
+    self.threads = threads;
+    self
+  }
+
+  /// How many ops to submit per iteration, across all threads.
+  /// Fewer if the model gets to a state where no op is possible.
+  pub fn ops(mut self, ops: usize) -> Self {
+    self.ops = ops;
+    self
+  }
+
+  /// Returns the [`Stats`] of the exploration, panics if
+  /// `equivalent` or a postcondition fails for an iteration.
+  /// Postconditions are checked first, in the order the ops were
+  /// submitted, as a wrong result can hide behind a right final
+  /// state.
+  pub fn run<O: Op<Real, Model>>(
+    self,
+    equivalent: impl Fn(&Real, &Model) -> bool,
+  ) -> Stats {
+    self.run_with::<O>(|execution| {
+      for entry in execution.history.entries() {
+        let Entry { thread, op, output, expected, .. } = entry;
+        assert!(
+          op.postcondition(output, expected),
+          "postcondition failed on thread {thread}: {op:?} \
+           returned {output:?}, the model {expected:?}, after \
+           {:?}",
+          execution.trace
+        );
+      }
+      assert!(
+        equivalent(&execution.real, &execution.model),
+        "real and model diverged after {:?}",
+        execution.trace
+      );
+    })
+  }
+
+  /// Like [`ModelTest::run`], but rather than in the order they
+  /// were submitted, the ops may have taken effect in any order
+  /// which keeps the order of each thread's own ops. Fails if no
+  /// such order explains every postcondition and the final
+  /// state.
+  pub fn run_sequentially_consistent<O: Op<Real, Model>>(
+    self,
+    equivalent: impl Fn(&Real, &Model) -> bool,
+  ) -> Stats
+  where
+    Model: Clone,
+  {
+    let threads = self.threads;
+    self.run_with::<O>(|execution| {
+      let per_thread: Vec<Vec<&Entry<O, O::Output>>> = (0
+        ..threads)
+        .map(|t| execution.history.thread(t).collect())
+        .collect();
+      let mut next = vec![0; threads];
+      assert!(
+        explain(
+          &execution.real,
+          &per_thread,
+          &Model::default(),
+          &mut next,
+          &equivalent
+        ),
+        "no sequentially consistent order explains {:?}",
+        execution.trace
+      );
+    })
+  }
+
+  /// Hands the [`History`] of every iteration, with the final
+  /// real value, to `analyze`, which panics to fail the
+  /// iteration.
+  pub fn run_history<O: Op<Real, Model>>(
+    self,
+    analyze: impl Fn(&Real, &History<O, O::Output>),
+  ) -> Stats {
+    self.run_with::<O>(|execution| {
+      analyze(&execution.real, &execution.history)
+    })
+  }
+
+  /// For every pair of kinds of ops in [`Op::generators`], whose
+  /// preconditions hold in the default state, runs one op of
+  /// each from that state: one after the other in both orders,
+  /// and on two threads, interleaved. Pairs which don't always
+  /// end up the same, by their outputs and by `observe` of the
+  /// real value, are worth exploring further, and are returned.
+  pub fn commutativity<O: Op<Real, Model>, S: fmt::Debug>(
+    self,
+    observe: impl Fn(&Real) -> S,
+  ) -> Vec<String> {
+    let generators = O::generators();
+    let model = Model::default();
+    let entries = &generators.entries;
+    let mut disagree = Vec::new();
+    for i in 0..entries.len() {
+      for j in i..entries.len() {
+        if !entries[i].1(&model) || !entries[j].1(&model) {
+          continue;
+        }
+        let mut outcomes =
+          BTreeMap::<String, BTreeSet<_>>::new();
+        self.config.clone().run(|g| {
+          let a = entries[i].2(g);
+          let b = entries[j].2(g);
+          let pair = format!("{a:?} and {b:?}");
+          let sequential = |first: &O, second: &O| {
+            let real = Real::default();
+            let first = first.apply(&real);
+            let second = second.apply(&real);
+            (first, second, observe(&real))
+          };
+          let (oa, ob, ab) = sequential(&a, &b);
+          let (ob2, oa2, ba) = sequential(&b, &a);
+          let interleaved = interleave(g, a, b);
+          let outcomes = outcomes.entry(pair).or_default();
+          outcomes.insert(format!("{oa:?} {ob:?} {ab:?}"));
+          outcomes.insert(format!("{oa2:?} {ob2:?} {ba:?}"));
+          let (oa, ob, real) = interleaved;
+          outcomes.insert(format!(
+            "{oa:?} {ob:?} {:?}",
+            observe(&real)
+          ));
+        });
+        for (pair, outcomes) in outcomes {
+          if outcomes.len() > 1 {
+            disagree.push(pair);
+          }
+        }
+      }
+    }
+    disagree
+  }
+
+  fn run_with<O: Op<Real, Model>>(
+    self,
+    check: impl Fn(Execution<Real, Model, O>),
+  ) -> Stats {
+    let ModelTest { config, threads, ops, .. } = self;
+    let config = config.env();
+    let generators = O::generators();
+    let iteration =
+      |g: &mut Choices,
+       ops: usize,
+       spans: &mut Vec<Range<usize>>| {
+        let real = Real::default();
+        let mut model = Model::default();
+        let mut trace = Vec::new();
+        let mut stuck = false;
+        let time = AtomicU64::new(0);
+        let done = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+          let handles: Vec<_> = (0..threads)
+            .map(|thread| {
+              managed_thread::spawn_with(
+                scope,
+                (&real, &done, &time),
+                move |(real, done, time), message| {
+                  let message: Message<O, O::Output> = message;
+                  let (op, expected, invoked) = message;
+                  let output = op.apply(real);
+                  done.lock().unwrap().push(Entry {
+                    thread,
+                    op,
+                    output,
+                    invoked,
+                    returned: time.load(SeqCst),
+                    expected,
+                  });
+                },
+              )
+            })
+            .collect();
+          loop {
+            let ready: Vec<usize> = (0..threads)
+              .filter(|&t| {
+                handles[t].is_paused()
+                  || (!stuck && trace.len() < ops)
+              })
+              .collect();
+            if ready.is_empty() {
+              break;
+            }
+            let start = g.schedule().len();
+            let t = ready[g.pick_thread(&ready)];
+            let now = time.fetch_add(1, SeqCst) + 1;
+            if handles[t].is_paused() {
+              handles[t].unpause();
+              continue;
+            }
+            let Some(op) = generators.generate(g, &model) else {
+              stuck = true;
+              continue;
+            };
+            let expected = op.apply_model(&mut model);
+            trace.push(format!("{t}: {op:?}"));
+            spans.push(start..g.schedule().len());
+            handles[t].send((op, expected, now));
+          }
+          for t in handles {
+            t.join();
+          }
+        });
+        let mut entries = done.into_inner().unwrap();
+        entries.sort_by_key(|it| it.invoked);
+        let history = History { entries };
+        check(Execution { real, model, trace, history })
+      };
+    let mut failed = None;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+      config.run(|g| {
+        let result =
+          panic::catch_unwind(AssertUnwindSafe(|| {
+            iteration(g, ops, &mut Vec::new())
+          }));
+        if let Err(payload) = result {
+          failed = Some(g.schedule().clone());
+          panic::resume_unwind(payload);
+        }
+      })
+    }));
+    let payload = match result {
+      Ok(stats) => return stats,
+      Err(payload) => payload,
+    };
+    let replay = |schedule: &Schedule, ops: usize| {
+      let mut spans = Vec::new();
+      let result = explore::check(schedule, |g| {
+        iteration(g, ops, &mut spans)
+      });
+      result
+        .err()
+        .map(|failure| Counterexample { spans, failure })
+    };
+    // Not reproducible if the body is nondeterministic, which is
+    // for `Config::detect_nondeterminism` to tell.
+    let Some(original) = failed.and_then(|it| replay(&it, ops))
+    else {
+      panic::resume_unwind(payload)
+    };
+    let submitted = original.spans.len();
+    let shrunk = shrink(original, replay);
+    panic!(
+      "{}\nshrunk from {submitted} ops, replay with `.ops({})` \
+       and `Config::replay(\"{}\")`",
+      shrunk.failure.message(),
+      shrunk.spans.len(),
+      shrunk.schedule()
+    )
+  }
+}
+
+/// A failing iteration of a [`ModelTest`], with where the
+/// decisions behind each op it submitted are in its schedule:
+/// the pick of the thread, then those of [`Op::generators`].
+struct Counterexample {
+  spans: Vec<Range<usize>>,
+  failure: Failure,
+}
+
+impl Counterexample {
+  /// Without the zeros at the end, which replays pad with
+  /// anyway.
+  fn schedule(&self) -> Schedule {
+    let decisions = self.failure.schedule().decisions();
+    let len = decisions
+      .iter()
+      .rposition(|&d| d > 0)
+      .map_or(0, |i| i + 1);
+    Schedule::from(decisions[..len].to_vec())
+  }
+
+  /// Fewer ops first, then shorter and smaller schedules.
+  fn size(&self) -> (usize, usize, Vec<usize>) {
+    let schedule = self.schedule();
+    (
+      self.spans.len(),
+      schedule.len(),
+      schedule.decisions().to_vec(),
+    )
+  }
+}
+
+/// What a failure is about, for shrinking to stay on the same
+/// bug rather than to slide onto a shallower one. The checks of
+/// this module are told apart by the kind of op, if any, but not
+/// by the values involved, which shrinking changes. Any other
+/// panic, like one in the real type or in a custom analysis, by
+/// its message.
+fn kind(message: &str) -> String {
+  const POSTCONDITION: &str = "postcondition failed on thread ";
+  if let Some(rest) = message.strip_prefix(POSTCONDITION) {
+    let op = rest.split_once(": ").map_or(rest, |(_, op)| op);
+    let variant = op
+      .split(|c: char| !c.is_alphanumeric() && c != '_')
+      .next()
+      .unwrap_or(op);
+    return format!("postcondition of {variant}");
+  }
+  for check in [
+    "real and model diverged",
+    "no sequentially consistent order",
+  ] {
+    if message.starts_with(check) {
+      return check.to_string();
+    }
+  }
+  message.to_string()
+}
+
+/// Greedily takes the first simpler candidate which still fails
+/// with the same [`kind`] of failure, until there is none.
+/// Dropping an op means submitting one fewer, without its
+/// decisions. The decisions left are then shrunk as any
+/// schedule, which makes arguments smaller along with the
+/// interleaving, as both are decisions.
+fn shrink(
+  mut current: Counterexample,
+  replay: impl Fn(&Schedule, usize) -> Option<Counterexample>,
+) -> Counterexample {
+  let kind = self::kind(current.failure.message());
+  'shrink: loop {
+    let schedule = current.schedule();
+    let ops = current.spans.len();
+    let spans = &current.spans;
+    // With the steps up to the next op too, which are likely to
+    // be those of the dropped op.
+    let drops = (0..ops).rev().flat_map(|i| {
+      let next =
+        spans.get(i + 1).map_or(usize::MAX, |it| it.start);
+      [spans[i].start..next, spans[i].clone()]
+    });
+    let drops = drops.map(|range| {
+      let mut decisions = schedule.decisions().to_vec();
+      decisions.resize(decisions.len().max(range.start), 0);
+      decisions
+        .drain(range.start..range.end.min(decisions.len()));
+      (Schedule::from(decisions), ops - 1)
+    });
+    let candidates: Vec<_> = drops
+      .chain(schedule.shrink().map(|it| (it, ops)))
+      .collect();
+    for (candidate, ops) in candidates {
+      if let Some(smaller) = replay(&candidate, ops) {
+        if smaller.size() < current.size()
+          && self::kind(smaller.failure.message()) == kind
+        {
+          current = smaller;
+          continue 'shrink;
+        }
+      }
+    }
+    return current;
+  }
+}
+
+/// Runs `a` and `b` on two threads from the default state, as
+/// the explorer picks.
+fn interleave<Real, Model, O>(
+  g: &mut Choices,
+  a: O,
+  b: O,
+) -> (O::Output, O::Output, Real)
+where
+  Real: Default + Sync,
+  O: Op<Real, Model>,
+{
+  let real = Real::default();
+  let outputs = [Mutex::new(None), Mutex::new(None)];
+  std::thread::scope(|scope| {
+    let handles: Vec<_> = outputs
+      .iter()
+      .map(|output| {
+        managed_thread::spawn_with(
+          scope,
+          (&real, output),
+          |(real, output), op: O| {
+            *output.lock().unwrap() = Some(op.apply(real))
+          },
+        )
+      })
+      .collect();
+    let mut unsent = [Some(a), Some(b)];
+    loop {
+      let ready: Vec<usize> = (0..2)
+        .filter(|&t| {
+          handles[t].is_paused() || unsent[t].is_some()
+        })
+        .collect();
+      if ready.is_empty() {
+        break;
+      }
+      let t = ready[g.pick_thread(&ready)];
+      match unsent[t].take() {
+        Some(op) => handles[t].send(op),
+        None => handles[t].unpause(),
+      }
+    }
+    for t in handles {
+      t.join();
+    }
+  });
+  let [a, b] =
+    outputs.map(|it| it.into_inner().unwrap().unwrap());
+  (a, b, real)
+}
+
+/// An op, what the model returned for it, and when it was
+/// submitted.
+type Message<O, Output> = (O, Output, u64);
+
+/// One iteration of a [`ModelTest`], once every thread is done.
+struct Execution<Real, Model, O: Op<Real, Model>> {
+  real: Real,
+  /// After every op, in the order they were submitted.
+  model: Model,
+  trace: Vec<String>,
+  history: History<O, O::Output>,
+}
+
+/// The ops of one iteration of a [`ModelTest`], for checkers of
+/// histories like linearizability checkers.
+///
+/// Time is counted in scheduling steps: an op is invoked at the
+/// step it is submitted, and it returns at the step it finishes,
+/// which may be the same one. Ops overlap unless one returns at
+/// an earlier step than the other is invoked.
+pub struct History<O, Output> {
+  entries: Vec<Entry<O, Output>>,
+}
+
+impl<O, Output> History<O, Output> {
+  /// Every op, in the order they were invoked.
+  pub fn entries(&self) -> &[Entry<O, Output>] {
+    &self.entries
+  }
+
+  /// The ops of one thread, in program order.
+  pub fn thread(
+    &self,
+    thread: usize,
+  ) -> impl Iterator<Item = &Entry<O, Output>> {
+    self.entries.iter().filter(move |it| it.thread == thread)
+  }
+}
+
+#[derive(Debug)]
+pub struct Entry<O, Output> {
+  pub thread: usize,
+  pub op: O,
+  pub output: Output,
+  pub invoked: u64,
+  pub returned: u64,
+  /// What the op returned on the model, when submitted.
+  expected: Output,
+}
+
+/// Searches for an order of the ops after those in `next`, one
+/// index per thread, which ends up `equivalent` to the real
+/// value.
+fn explain<Real, Model: Clone, O: Op<Real, Model>>(
+  real: &Real,
+  per_thread: &[Vec<&Entry<O, O::Output>>],
+  model: &Model,
+  next: &mut [usize],
+  equivalent: &impl Fn(&Real, &Model) -> bool,
+) -> bool {
+  let mut finished = true;
+  for t in 0..next.len() {
+    let Some(entry) = per_thread[t].get(next[t]) else {
+      continue;
+    };
+    finished = false;
+    let mut model = model.clone();
+    let expected = entry.op.apply_model(&mut model);
+    if !entry.op.postcondition(&entry.output, &expected) {
+      continue;
+    }
+    next[t] += 1;
+    let explained =
+      explain(real, per_thread, &model, next, equivalent);
+    next[t] -= 1;
+    if explained {
+      return true;
+    }
+  }
+  finished && equivalent(real, model)
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct Increment;
+
+#[cfg(test)]
+impl Op<crate::Counter, u32> for Increment {
+  type Output = ();
+
+  fn generators() -> Generators<u32, Increment> {
+    Generators::new().op(1, |_| Increment)
+  }
+
+  fn apply(&self, real: &crate::Counter) {
+    real.increment()
+  }
+
+  fn apply_model(&self, model: &mut u32) {
+    *model += 1
+  }
+}
+
+#[test]
+fn sequential_increments_agree() {
+  ModelTest::<crate::Counter, u32>::new(
+    Config::new().exhaustive(),
+  )
+  .threads(1)
+  .run::<Increment>(|real, model| real.get() == *model);
+}
+
+#[test]
+#[should_panic(expected = "real and model diverged")]
+fn concurrent_increments_diverge() {
+  ModelTest::<crate::Counter, u32>::new(
+    Config::new().exhaustive(),
+  )
+  .run::<Increment>(|real, model| real.get() == *model);
+}
+
+#[test]
+fn failures_shrink_to_fewer_ops() {
+  let payload = panic::catch_unwind(|| {
+    ModelTest::<crate::Counter, u32>::new(
+      Config::new().exhaustive(),
+    )
+    .ops(6)
+    .run::<Increment>(|real, model| real.get() == *model)
+  })
+  .unwrap_err();
+  let message = payload.downcast_ref::<String>().unwrap();
+  assert!(
+    message.contains(
+      "diverged after [\"0: Increment\", \"1: Increment\"]"
+    ),
+    "{message}"
+  );
+  assert!(message.contains("shrunk from 6 ops"), "{message}");
+  assert!(message.contains("`.ops(2)`"), "{message}");
+}
+
+#[test]
+fn shrinking_keeps_to_the_same_failure() {
+  let payload = panic::catch_unwind(|| {
+    ModelTest::<crate::Counter, u32>::new(
+      Config::new().exhaustive(),
+    )
+    .threads(1)
+    .ops(3)
+    .run_history::<Increment>(|_, history| {
+      let ops = history.entries().len();
+      assert!(ops < 3, "deep bug");
+      assert!(ops < 2, "shallow bug");
+    })
+  })
+  .unwrap_err();
+  let message = payload.downcast_ref::<String>().unwrap();
+  assert!(message.starts_with("deep bug"), "{message}");
+
+  assert_eq!(
+    kind("postcondition failed on thread 1: Push(2) returned"),
+    kind("postcondition failed on thread 0: Push(0) returned")
+  );
+  assert_ne!(
+    kind("postcondition failed on thread 0: Pop returned"),
+    kind("postcondition failed on thread 0: Push(0) returned")
+  );
+}
+
+/// A stack which is a queue when `FIFO`.
+#[cfg(test)]
+#[derive(Default)]
+struct Stack<const FIFO: bool>(std::sync::Mutex<Vec<u32>>);
+
+#[cfg(test)]
+impl<const FIFO: bool> Stack<FIFO> {
+  fn push(&self, value: u32) {
+    self.0.lock().unwrap().push(value)
+  }
+
+  fn pop(&self) -> Option<u32> {
+    let mut stack = self.0.lock().unwrap();
+    if FIFO && !stack.is_empty() {
+      Some(stack.remove(0))
+    } else {
+      stack.pop()
+    }
+  }
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+enum StackOp {
+  Push(u32),
+  Pop,
+}
+
+#[cfg(test)]
+impl<const FIFO: bool> Op<Stack<FIFO>, Vec<u32>> for StackOp {
+  type Output = Option<u32>;
+
+  fn generators() -> Generators<Vec<u32>, StackOp> {
+    Generators::new()
+      .op(2, |g| StackOp::Push(g.gen(1) as u32))
+      .op_if(
+        1,
+        |model: &Vec<u32>| !model.is_empty(),
+        |_| StackOp::Pop,
+      )
+  }
+
+  fn apply(&self, real: &Stack<FIFO>) -> Option<u32> {
+    let mut stack = real.0.lock().unwrap();
+    match self {
+      StackOp::Push(value) => {
+        stack.push(*value);
+        None
+      }
+      StackOp::Pop if FIFO => Some(stack.remove(0)),
+      StackOp::Pop => stack.pop(),
+    }
+  }
+
+  fn apply_model(&self, model: &mut Vec<u32>) -> Option<u32> {
+    match self {
+      StackOp::Push(value) => {
+        model.push(*value);
+        None
+      }
+      StackOp::Pop => Some(model.pop().unwrap()),
+    }
+  }
+
+  fn postcondition(
+    &self,
+    real: &Option<u32>,
+    model: &Option<u32>,
+  ) -> bool {
+    real == model
+  }
+}
+
+#[test]
+fn pops_follow_the_model() {
+  ModelTest::<Stack<false>, Vec<u32>>::new(
+    Config::new().exhaustive(),
+  )
+  .threads(1)
+  .run::<StackOp>(|real, model| {
+    *real.0.lock().unwrap() == *model
+  });
+}
+
+#[test]
+#[should_panic(
+  expected = "postcondition failed on thread 0: Pop"
+)]
+fn queue_is_not_a_stack() {
+  ModelTest::<Stack<true>, Vec<u32>>::new(
+    Config::new().exhaustive(),
+  )
+  .threads(1)
+  .run::<StackOp>(|_, _| true);
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+enum RegisterOp {
+  Write(u32),
+  Read,
+}
+
+#[cfg(test)]
+impl Op<managed_thread::AtomicU32, u32> for RegisterOp {
+  type Output = Option<u32>;
+
+  fn generators() -> Generators<u32, RegisterOp> {
+    Generators::new()
+      .op(1, |_| RegisterOp::Write(1))
+      .op(1, |_| RegisterOp::Read)
+  }
+
+  fn apply(
+    &self,
+    real: &managed_thread::AtomicU32,
+  ) -> Option<u32> {
+    use std::sync::atomic::Ordering::SeqCst;
+    match self {
+      RegisterOp::Write(value) => {
+        real.store(*value, SeqCst);
+        None
+      }
+      RegisterOp::Read => Some(real.load(SeqCst)),
+    }
+  }
+
+  fn apply_model(&self, model: &mut u32) -> Option<u32> {
+    match self {
+      RegisterOp::Write(value) => {
+        *model = *value;
+        None
+      }
+      RegisterOp::Read => Some(*model),
+    }
+  }
+
+  fn postcondition(
+    &self,
+    real: &Option<u32>,
+    model: &Option<u32>,
+  ) -> bool {
+    real == model
+  }
+}
+
+#[test]
+#[should_panic(expected = "postcondition failed")]
+fn reads_race_submission_order() {
+  ModelTest::<managed_thread::AtomicU32, u32>::new(
+    Config::new().exhaustive(),
+  )
+  .ops(2)
+  .run::<RegisterOp>(|real, model| {
+    real.load(std::sync::atomic::Ordering::SeqCst) == *model
+  });
+}
+
+#[test]
+fn reads_are_sequentially_consistent() {
+  ModelTest::<managed_thread::AtomicU32, u32>::new(
+    Config::new().exhaustive(),
+  )
+  .ops(2)
+  .run_sequentially_consistent::<RegisterOp>(|real, model| {
+    real.load(std::sync::atomic::Ordering::SeqCst) == *model
+  });
+}
+
+#[test]
+#[should_panic(expected = "no sequentially consistent order")]
+fn lost_increments_are_not_sequentially_consistent() {
+  ModelTest::<crate::Counter, u32>::new(
+    Config::new().exhaustive(),
+  )
+  .run_sequentially_consistent::<Increment>(|real, model| {
+    real.get() == *model
+  });
+}
+
+#[test]
+fn histories_show_overlapping_ops() {
+  use std::sync::atomic::AtomicBool;
+
+  static OVERLAP: AtomicBool = AtomicBool::new(false);
+
+  ModelTest::<crate::Counter, u32>::new(
+    Config::new().exhaustive(),
+  )
+  .ops(2)
+  .run_history::<Increment>(|_, history| {
+    let entries = history.entries();
+    assert_eq!(entries.len(), 2);
+    assert!(entries.iter().all(|it| it.invoked <= it.returned));
+    if entries[1].invoked <= entries[0].returned {
+      OVERLAP.store(true, SeqCst);
+    }
+  });
+  assert!(OVERLAP.load(SeqCst));
+}
+
+#[test]
+fn racy_increments_do_not_commute() {
+  let disagree = ModelTest::<crate::Counter, u32>::new(
+    Config::new().exhaustive(),
+  )
+  .commutativity::<Increment, _>(|real| real.get());
+  assert_eq!(disagree, ["Increment and Increment"]);
+}
+
+#[test]
+fn writes_do_not_commute_with_reads() {
+  let disagree =
+    ModelTest::<managed_thread::AtomicU32, u32>::new(
+      Config::new().exhaustive(),
+    )
+    .commutativity::<RegisterOp, _>(|real| {
+      real.load(std::sync::atomic::Ordering::SeqCst)
+    });
+  assert_eq!(disagree, ["Write(1) and Read"]);
+}
+
+#[cfg(test)]
+#[derive(ConcurrentOps)]
+#[ops(real = Stack<true>, model = Vec<u32>)]
+enum DerivedStackOp {
+  #[op(when = |model| model.len() < 3, weight = 2)]
+  Push(u32),
+  #[op(when = |model| !model.is_empty())]
+  Pop,
+}
+
+#[test]
+#[should_panic(
+  expected = "postcondition failed on thread 0: Pop returned"
+)]
+fn derived_ops_check_outputs() {
+  assert_eq!(
+    format!("{:?}", DerivedStackOp::Push(1)),
+    "Push(1)"
+  );
+  ModelTest::<Stack<true>, Vec<u32>>::new(
+    Config::new().exhaustive(),
+  )
+  .threads(1)
+  .run::<DerivedStackOp>(|_, _| true);
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct Map(std::sync::Mutex<BTreeMap<u32, u32>>);
+
+#[cfg(test)]
+impl Map {
+  fn insert(&self, key: u32, value: u32) -> Option<u32> {
+    self.0.lock().unwrap().insert(key, value)
+  }
+}
+
+#[cfg(test)]
+#[derive(ConcurrentOps)]
+#[ops(real = Map, model = BTreeMap<u32, u32>)]
+enum DerivedMapOp {
+  Insert(u32, u32),
+}
+
+#[test]
+fn derived_ops_take_generic_models() {
+  ModelTest::<Map, BTreeMap<u32, u32>>::new(
+    Config::new().exhaustive(),
+  )
+  .threads(1)
+  .run::<DerivedMapOp>(|real, model| {
+    *real.0.lock().unwrap() == *model
+  });
+}
@@ -0,0 +1,140 @@
+//! A bounded multi-producer multi-consumer queue.
+//!
+//! Pushing to a full queue blocks until some consumer pops,
+//! popping an empty one blocks until some producer pushes, and
+//! both are scheduling points. When several threads are waiting
+//! and one can go, the explorer picks which, so every wake order
+//! gets explored.
+
+use std::{
+  collections::VecDeque,
+  sync::{Arc, Mutex},
+};
+
+use crate::managed_thread;
+
+/// A handle to a queue, clones share it.
+pub struct Queue<T> {
+  items: Arc<Mutex<VecDeque<T>>>,
+  capacity: usize,
+}
+
+impl<T> Clone for Queue<T> {
+  fn clone(&self) -> Queue<T> {
+    Queue {
+      items: Arc::clone(&self.items),
+      capacity: self.capacity,
+    }
+  }
+}
+
+impl<T: Send + 'static> Queue<T> {
+  pub fn new(capacity: usize) -> Queue<T> {
+    assert!(capacity > 0, "a queue needs room for an item");
+    Queue { items: Arc::default(), capacity }
+  }
+
+  /// Blocks until there is room.
+  pub fn push(&self, mut item: T) {
+    loop {
+      self.wait(|len, capacity| len < capacity);
+      match self.try_push_now(item) {
+        Ok(()) => return,
+        Err(back) => item = back,
+      }
+    }
+  }
+
+  /// Blocks until there is an item.
+  pub fn pop(&self) -> T {
+    loop {
+      self.wait(|len, _| len > 0);
+      if let Some(item) = self.items.lock().unwrap().pop_front()
+      {
+        return item;
+      }
+    }
+  }
+
+  /// Gives the item back if the queue is full.
+  pub fn try_push(&self, item: T) -> Result<(), T> {
+    managed_thread::pause();
+    self.try_push_now(item)
+  }
+
+  pub fn try_pop(&self) -> Option<T> {
+    managed_thread::pause();
+    self.items.lock().unwrap().pop_front()
+  }
+
+  pub fn len(&self) -> usize {
+    self.items.lock().unwrap().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  fn try_push_now(&self, item: T) -> Result<(), T> {
+    let mut items = self.items.lock().unwrap();
+    if items.len() == self.capacity {
+      return Err(item);
+    }
+    items.push_back(item);
+    Ok(())
+  }
+
+  /// Pauses, then blocks until `ready(len, capacity)`. Outside
+  /// of exploration the condition may not hold by the time the
+  /// caller looks again, so callers retry.
+  fn wait(&self, ready: fn(usize, usize) -> bool) {
+    managed_thread::pause();
+    let (items, capacity) =
+      (Arc::clone(&self.items), self.capacity);
+    managed_thread::block_until(Arc::new(move || {
+      ready(items.lock().unwrap().len(), capacity)
+    }));
+  }
+}
+
+#[cfg(test)]
+fn produce_and_consume(consumed: usize) -> Vec<u32> {
+  use crate::{
+    explore::Config,
+    loom_compat::{self, thread},
+  };
+
+  static SEEN: Mutex<Vec<Vec<u32>>> = Mutex::new(Vec::new());
+
+  loom_compat::run(Config::new().exhaustive(), move || {
+    let queue = Queue::new(1);
+    let producers: Vec<_> = [1, 2]
+      .into_iter()
+      .map(|item| {
+        let queue = queue.clone();
+        thread::spawn(move || queue.push(item))
+      })
+      .collect();
+    let items = (0..consumed).map(|_| queue.pop()).collect();
+    for producer in producers {
+      producer.join().unwrap();
+    }
+    SEEN.lock().unwrap().push(items);
+  });
+  let mut seen =
+    SEEN.lock().unwrap().drain(..).collect::<Vec<_>>();
+  seen.sort();
+  seen.dedup();
+  seen.concat()
+}
+
+#[test]
+fn producers_take_turns() {
+  assert_eq!(produce_and_consume(2), [1, 2, 2, 1]);
+}
+
+#[test]
+#[should_panic(expected = "deadlock")]
+fn pushing_to_a_full_queue_blocks() {
+  produce_and_consume(0);
+}
@@ -0,0 +1,64 @@
+//! Randomness for the code under test, like backoff jitter or
+//! random eviction, which replays along with the schedule.
+//!
+//! Every [`crate::loom_compat`] thread gets a generator of its
+//! own, seeded by the explorer the first time the thread asks
+//! for a number, see [`Choices::seed`]. Outside of a model,
+//! numbers come from a generator per OS thread, seeded from the
+//! OS.
+//!
+//! [`Choices::seed`]: crate::explore::Choices::seed
+
+use std::{
+  cell::Cell,
+  collections::hash_map::RandomState,
+  hash::{BuildHasher, Hasher},
+};
+
+use crate::{explore::Rng, loom_compat};
+
+pub fn u64() -> u64 {
+  if let Some(value) = loom_compat::random() {
+    return value;
+  }
+  thread_local! {
+    static STATE: Cell<u64> = Cell::new(
+      RandomState::new().build_hasher().finish()
+    );
+  }
+  STATE.with(|state| {
+    let mut rng = Rng(state.get());
+    let value = rng.next();
+    state.set(rng.0);
+    value
+  })
+}
+
+/// A number in `0..n`, for a non-zero `n`.
+pub fn below(n: u64) -> u64 {
+  assert!(n > 0);
+  u64() % n
+}
+
+#[test]
+fn seeds_reproduce_numbers() {
+  use std::sync::Mutex;
+
+  use crate::{explore::Config, loom_compat::thread};
+
+  static SEEN: Mutex<Vec<[u64; 3]>> = Mutex::new(Vec::new());
+
+  let body = || {
+    let t = thread::spawn(|| below(1000));
+    let mine = [below(1000), below(1000)];
+    let theirs = t.join().unwrap();
+    SEEN.lock().unwrap().push([mine[0], mine[1], theirs]);
+  };
+  loom_compat::run(Config::new().seed(7), body);
+  loom_compat::run(Config::new().seed(7), body);
+  loom_compat::run(Config::new().seed(8), body);
+  let seen = std::mem::take(&mut *SEEN.lock().unwrap());
+  assert_eq!(seen[0], seen[1]);
+  assert_ne!(seen[0], seen[2]);
+  assert_ne!(seen[0][0], seen[0][1]);
+}
@@ -0,0 +1,103 @@
+//! Read-copy-update grace periods, instrumented.
+//!
+//! Readers bracket their accesses with [`Rcu::read_lock`] and
+//! the guard it returns, updaters wait out the readers which may
+//! still see an old version with [`Rcu::synchronize`]. Every
+//! step is a scheduling point, in particular the window between
+//! a reader picking its counter and announcing itself on it,
+//! where a `synchronize` may begin.
+//!
+//! Readers count themselves on one of two counters, chosen by
+//! the phase, so that new readers can't keep an updater waiting
+//! forever.
+
+use std::sync::atomic::Ordering::SeqCst;
+
+use crate::{loom_compat::hint, managed_thread::AtomicUsize};
+
+#[derive(Default)]
+pub struct Rcu {
+  phase: AtomicUsize,
+  readers: [AtomicUsize; 2],
+}
+
+impl Rcu {
+  pub fn new() -> Rcu {
+    Rcu::default()
+  }
+
+  pub fn read_lock(&self) -> RcuReadGuard<'_> {
+    let index = self.phase.load(SeqCst) % 2;
+    self.readers[index].fetch_add(1, SeqCst);
+    RcuReadGuard { rcu: self, index }
+  }
+
+  /// Waits until every reader which started before the call is
+  /// done. Updaters must not call it concurrently.
+  pub fn synchronize(&self) {
+    let phase = self.phase.load(SeqCst);
+    // A reader which read an older phase, but only announced
+    // itself after the last `synchronize` stopped waiting for
+    // it.
+    self.wait((phase + 1) % 2);
+    self.phase.store(phase + 1, SeqCst);
+    self.wait(phase % 2);
+  }
+
+  fn wait(&self, index: usize) {
+    while self.readers[index].load(SeqCst) != 0 {
+      hint::spin_loop()
+    }
+  }
+}
+
+pub struct RcuReadGuard<'a> {
+  rcu: &'a Rcu,
+  index: usize,
+}
+
+impl Drop for RcuReadGuard<'_> {
+  fn drop(&mut self) {
+    self.rcu.readers[self.index].fetch_sub(1, SeqCst);
+  }
+}
+
+#[test]
+fn readers_keep_their_version() {
+  use crate::{
+    explore::Config,
+    loom_compat::{self, sync::Arc, thread},
+  };
+
+  // The reader needs to pick its counter just before the first
+  // `synchronize` and announce itself just after, a few priority
+  // changes deep.
+  let config = Config::new().iterations(10_000).pct(4);
+  loom_compat::run(config, || {
+    let rcu = Arc::new(Rcu::new());
+    let version = Arc::new(AtomicUsize::new(0));
+    let freed = Arc::new(AtomicUsize::new(0));
+    let reader = thread::spawn({
+      let (rcu, version, freed) = (
+        Arc::clone(&rcu),
+        Arc::clone(&version),
+        Arc::clone(&freed),
+      );
+      move || {
+        let _guard = rcu.read_lock();
+        let v = version.load(SeqCst);
+        assert_eq!(
+          freed.load(SeqCst) & 1 << v,
+          0,
+          "use after free"
+        );
+      }
+    });
+    for v in 0..2 {
+      version.store(v + 1, SeqCst);
+      rcu.synchronize();
+      freed.fetch_add(1 << v, SeqCst);
+    }
+    reader.join().unwrap();
+  })
+}
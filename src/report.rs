@@ -0,0 +1,226 @@
+//! A self-contained HTML page summing up exploration runs,
+//! behind the `report` feature, for publishing the results of
+//! long jobs as build artifacts.
+//!
+//! ```text
+//! let mut report = Report::new("nightly");
+//! report.stats("queue", &stats);
+//! report.failure("stack", &failure, Some(&trace));
+//! report.write("target/exploration.html")?;
+//! ```
+//!
+//! The page has no scripts and no external resources, so it can
+//! be opened straight from a CI artifact.
+
+use std::{fmt::Write, io, path::Path};
+
+use crate::{
+  explore::{Failure, Stats},
+  loom_compat::Coverage,
+  memory::Trace,
+};
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+td, th { border: 1px solid #ccc; padding: 0.2em 0.6em; }
+th { background: #eee; text-align: left; }
+code, td.event { font-family: monospace; }
+.failed { color: #b00; }
+.dead { background: #fee; }";
+
+/// Sections are rendered in the order they're added.
+pub struct Report {
+  title: String,
+  body: String,
+}
+
+impl Report {
+  pub fn new(title: &str) -> Report {
+    Report { title: title.to_string(), body: String::new() }
+  }
+
+  pub fn stats(
+    &mut self,
+    name: &str,
+    stats: &Stats,
+  ) -> &mut Report {
+    let rows = [
+      ("iterations", stats.iterations.to_string()),
+      (
+        "distinct schedules",
+        stats.distinct_schedules.to_string(),
+      ),
+      (
+        "duplicate schedules",
+        stats.duplicate_schedules.to_string(),
+      ),
+      ("max depth", stats.max_depth.to_string()),
+      ("pause points", stats.pause_points.to_string()),
+      ("elapsed", format!("{:.2?}", stats.elapsed)),
+    ];
+    self.heading(name, "");
+    self.body.push_str("<table>\n");
+    for (key, value) in rows {
+      self.row("td", &[key, &value]);
+    }
+    self.body.push_str("</table>\n");
+    self
+  }
+
+  /// Sites which never interleaved are highlighted.
+  pub fn coverage(
+    &mut self,
+    name: &str,
+    coverage: &Coverage,
+  ) -> &mut Report {
+    self.heading(name, "");
+    let _ = writeln!(
+      self.body,
+      "<p>{} iterations</p>\n<table>",
+      coverage.iterations()
+    );
+    self.row("th", &["site", "hits", "iterations", "choices"]);
+    for (site, reached) in coverage.sites() {
+      let class = if reached.choices == 0 { "dead" } else { "" };
+      let _ = write!(self.body, "<tr class=\"{class}\">");
+      for cell in [
+        site,
+        reached.hits.to_string(),
+        reached.iterations.to_string(),
+        reached.choices.to_string(),
+      ] {
+        let _ = write!(self.body, "<td>{}</td>", escape(&cell));
+      }
+      self.body.push_str("</tr>\n");
+    }
+    self.body.push_str("</table>\n");
+    self
+  }
+
+  /// A failure, with the trace of its schedule if there is one,
+  /// see [`crate::memory::trace`].
+  pub fn failure(
+    &mut self,
+    name: &str,
+    failure: &Failure,
+    trace: Option<&Trace>,
+  ) -> &mut Report {
+    self.heading(name, "failed");
+    let _ = writeln!(
+      self.body,
+      "<pre>{}</pre>\n<p>Schedule: <code>{}</code></p>",
+      escape(failure.message()),
+      failure.schedule()
+    );
+    if let Some(trace) = trace {
+      self.trace(trace);
+    }
+    self
+  }
+
+  /// A column per thread, a row per event.
+  fn trace(&mut self, trace: &Trace) {
+    let events = trace.events();
+    let threads =
+      events.iter().map(|it| it.thread() + 1).max().unwrap_or(0);
+    self.body.push_str("<table>\n<tr><th>#</th>");
+    for thread in 0..threads {
+      let _ = write!(self.body, "<th>thread {thread}</th>");
+    }
+    self.body.push_str("</tr>\n");
+    for (i, event) in events.iter().enumerate() {
+      let mut text = format!(
+        "{} {} at {}",
+        event.operation(),
+        event.value(),
+        event.site()
+      );
+      if let Some(store) = event.reads_from() {
+        let _ = write!(text, ", reads {store}");
+      }
+      if let Some(release) = event.synchronizes_with() {
+        let _ = write!(text, ", synchronizes with {release}");
+      }
+      let _ = write!(self.body, "<tr><td>{i}</td>");
+      for thread in 0..threads {
+        let cell = if thread == event.thread() {
+          escape(&text)
+        } else {
+          "".into()
+        };
+        let _ =
+          write!(self.body, "<td class=\"event\">{cell}</td>");
+      }
+      self.body.push_str("</tr>\n");
+    }
+    self.body.push_str("</table>\n");
+  }
+
+  pub fn html(&self) -> String {
+    let title = escape(&self.title);
+    format!(
+      "<!DOCTYPE html>\n<html>\n<head>\n\
+      <meta charset=\"utf-8\">\n<title>{title}</title>\n\
+      <style>\n{STYLE}\n</style>\n</head>\n<body>\n\
+      <h1>{title}</h1>\n{}</body>\n</html>\n",
+      self.body
+    )
+  }
+
+  pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+    std::fs::write(path, self.html())
+  }
+
+  fn heading(&mut self, name: &str, class: &str) {
+    let _ = writeln!(
+      self.body,
+      "<h2 class=\"{class}\">{}</h2>",
+      escape(name)
+    );
+  }
+
+  fn row(&mut self, cell: &str, values: &[&str]) {
+    self.body.push_str("<tr>");
+    for value in values {
+      let _ =
+        write!(self.body, "<{cell}>{}</{cell}>", escape(value));
+    }
+    self.body.push_str("</tr>\n");
+  }
+}
+
+fn escape(text: &str) -> String {
+  let mut out = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '<' => out.push_str("&lt;"),
+      '>' => out.push_str("&gt;"),
+      '&' => out.push_str("&amp;"),
+      '"' => out.push_str("&quot;"),
+      c => out.push(c),
+    }
+  }
+  out
+}
+
+#[test]
+fn failures_are_escaped() {
+  use crate::explore::{check, Config};
+
+  let stats = Config::new().iterations(3).run(|g| {
+    g.flip();
+  });
+  let failure = check(&vec![1].into(), |g| {
+    assert!(!g.flip(), "<Vec<u8>> & more");
+  })
+  .unwrap_err();
+  let html = Report::new("nightly")
+    .stats("flips", &stats)
+    .failure("flips fail", &failure, Some(&Trace::default()))
+    .html();
+  assert!(html.contains("<td>iterations</td><td>3</td>"));
+  assert!(html.contains("&lt;Vec&lt;u8&gt;&gt; &amp; more"));
+  assert!(html.contains("Schedule: <code>1</code>"));
+  assert!(!html.contains("<Vec"));
+}
@@ -0,0 +1,149 @@
+//! Exact interleavings of managed threads, written out step by
+//! step, for turning a known-bad schedule into a readable
+//! regression test.
+//!
+//! ```text
+//! t1.submit(|c| {
+//!   let v = c.load(SeqCst);
+//!   label("load");
+//!   ...
+//! });
+//! t2.submit(|c| ...);
+//! Script::new()
+//!   .to(0, "load")
+//!   .finish(1)
+//!   .finish(0)
+//!   .run(&[&t1, &t2]);
+//! ```
+//!
+//! Threads are referred to by their index in the slice given to
+//! [`Script::run`], and stop at the [`label`]s in their ops.
+//!
+//! [`label`]: crate::managed_thread::label
+
+use std::fmt;
+
+use crate::managed_thread::ManagedHandle;
+
+#[derive(Clone, Default, Debug)]
+pub struct Script {
+  steps: Vec<Step>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Step {
+  To(usize, &'static str),
+  Finish(usize),
+  Once(usize),
+}
+
+impl Script {
+  pub fn new() -> Script {
+    Script::default()
+  }
+
+  /// Runs `thread` until it pauses at `label`, without moving it
+  /// if it's there already.
+  pub fn to(
+    mut self,
+    thread: usize,
+    label: &'static str,
+  ) -> Script {
+    self.steps.push(Step::To(thread, label));
+    self
+  }
+
+  /// Runs `thread` until it's done with its op.
+  pub fn finish(mut self, thread: usize) -> Script {
+    self.steps.push(Step::Finish(thread));
+    self
+  }
+
+  /// Runs `thread` to its next pause point, labelled or not.
+  pub fn step(mut self, thread: usize) -> Script {
+    self.steps.push(Step::Once(thread));
+    self
+  }
+
+  /// Panics, naming the step, if a thread can't do what its step
+  /// asks, for example because it finished before the label.
+  pub fn run<T, M>(&self, threads: &[&ManagedHandle<'_, T, M>]) {
+    for (i, &step) in self.steps.iter().enumerate() {
+      let failed = |why: &str| -> ! {
+        panic!("step {i} of the script, {step}, failed: {why}")
+      };
+      match step {
+        Step::To(t, label) => {
+          while threads[t].label() != Some(label) {
+            if !threads[t].is_paused() {
+              failed("the thread is done")
+            }
+            threads[t].unpause();
+          }
+        }
+        Step::Finish(t) => {
+          while threads[t].is_paused() {
+            threads[t].unpause();
+          }
+        }
+        Step::Once(t) => {
+          if !threads[t].is_paused() {
+            failed("the thread is done")
+          }
+          threads[t].unpause();
+        }
+      }
+    }
+  }
+}
+
+impl fmt::Display for Step {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Step::To(t, label) => write!(f, "thread {t} to `{label}`"),
+      Step::Finish(t) => write!(f, "thread {t} to completion"),
+      Step::Once(t) => write!(f, "thread {t} one step"),
+    }
+  }
+}
+
+#[test]
+fn scripts_lose_an_update() {
+  use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::Ordering::SeqCst,
+  };
+
+  use crate::managed_thread::{self, label, AtomicU32};
+
+  let counter = AtomicU32::default();
+  std::thread::scope(|scope| {
+    let threads = [(); 2].map(|()| {
+      let t = managed_thread::spawn(scope, &counter);
+      t.submit(|c| {
+        let value = c.load(SeqCst);
+        label("loaded");
+        c.store(value + 1, SeqCst)
+      });
+      t
+    });
+    let [t1, t2] = &threads;
+    let debug = format!("{t1:?}");
+    assert!(debug.contains("state: Paused"), "{debug}");
+    assert!(debug.contains("label: None"), "{debug}");
+    Script::new().to(0, "loaded").run(&[t1, t2]);
+    assert!(
+      format!("{t1:?}").contains("label: Some(\"loaded\")")
+    );
+    Script::new().finish(1).finish(0).run(&[t1, t2]);
+    assert_eq!(counter.load(SeqCst), 1);
+    let done = AssertUnwindSafe(|| {
+      Script::new().to(0, "loaded").run(&[t1, t2])
+    });
+    let result = panic::catch_unwind(done);
+    assert!(result.is_err());
+    for t in threads {
+      t.join()
+    }
+  });
+}
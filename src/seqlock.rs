@@ -0,0 +1,83 @@
+//! A sequence lock over a few words, built from the instrumented
+//! atomics, so that the read-retry protocol is explored down to
+//! the torn reads it has to reject.
+//!
+//! Every word is its own relaxed atomic, so a read which races a
+//! write can see some words of each. The version is odd during a
+//! write, and a read only counts if it saw the same even version
+//! before and after.
+
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+use crate::atomic::{fence, AtomicUsize};
+
+pub struct SeqLock<const N: usize> {
+  version: AtomicUsize,
+  words: [AtomicUsize; N],
+}
+
+impl<const N: usize> SeqLock<N> {
+  pub fn new(value: [usize; N]) -> SeqLock<N> {
+    SeqLock {
+      version: AtomicUsize::new(0),
+      words: value.map(AtomicUsize::new),
+    }
+  }
+
+  /// One attempt at a read, `None` if it raced a write. Under
+  /// exhaustive exploration, prefer this to [`SeqLock::read`],
+  /// whose retries a scheduler could keep failing forever.
+  pub fn try_read(&self) -> Option<[usize; N]> {
+    let before = self.version.load(Acquire);
+    if before % 2 == 1 {
+      return None;
+    }
+    let value =
+      core::array::from_fn(|i| self.words[i].load(Relaxed));
+    fence(Acquire);
+    let after = self.version.load(Relaxed);
+    (before == after).then_some(value)
+  }
+
+  pub fn read(&self) -> [usize; N] {
+    loop {
+      if let Some(value) = self.try_read() {
+        return value;
+      }
+    }
+  }
+
+  /// Writers must not overlap, for example by holding a lock.
+  pub fn write(&self, value: [usize; N]) {
+    let version = self.version.load(Relaxed);
+    self.version.store(version + 1, Relaxed);
+    fence(Release);
+    for (word, value) in self.words.iter().zip(value) {
+      word.store(value, Relaxed);
+    }
+    self.version.store(version + 2, Release);
+  }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn reads_are_never_torn() {
+  use crate::{
+    explore::Config,
+    loom_compat::{self, sync::Arc, thread},
+  };
+
+  // Exhaustive exploration of weak memory takes minutes.
+  let config = Config::new().iterations(1_000).weak_memory();
+  loom_compat::run(config, || {
+    let lock = Arc::new(SeqLock::new([0, 0]));
+    let writer = thread::spawn({
+      let lock = Arc::clone(&lock);
+      move || lock.write([1, 1])
+    });
+    if let Some([a, b]) = lock.try_read() {
+      assert_eq!(a, b);
+    }
+    writer.join().unwrap();
+  })
+}
@@ -0,0 +1,82 @@
+//! [shuttle]'s entry points on top of this crate's schedulers.
+//!
+//! Threads and atomics are the ones from [`loom_compat`], which
+//! shuttle tests use the same way.
+//!
+//! [shuttle]: https://docs.rs/shuttle
+//! [`loom_compat`]: crate::loom_compat
+
+use crate::{explore::Config, loom_compat};
+
+pub use crate::loom_compat::{sync, thread};
+
+/// Runs `f` `iterations` times, picking threads uniformly at
+/// random.
+pub fn check_random<F>(f: F, iterations: usize)
+where
+  F: Fn() + Send + Sync + 'static,
+{
+  loom_compat::run(
+    Config::new().iterations(iterations as u32),
+    f,
+  )
+}
+
+/// Runs `f` `iterations` times under PCT with the given bug
+/// depth, see [`Config::pct`].
+pub fn check_pct<F>(f: F, iterations: usize, depth: usize)
+where
+  F: Fn() + Send + Sync + 'static,
+{
+  let config = Config::new()
+    .iterations(iterations as u32)
+    .pct(depth as u32);
+  loom_compat::run(config, f)
+}
+
+/// Runs `f` once, following a schedule printed by a failed
+/// [`check_random`] or [`check_pct`].
+pub fn replay<F>(f: F, schedule: &str)
+where
+  F: Fn() + Send + Sync + 'static,
+{
+  loom_compat::run(Config::new().replay(schedule), f)
+}
+
+#[cfg(test)]
+fn lost_update() {
+  use sync::{atomic::AtomicU32, atomic::Ordering::SeqCst, Arc};
+
+  let counter = Arc::new(AtomicU32::new(0));
+  let threads: Vec<_> = (0..2)
+    .map(|_| {
+      let counter = Arc::clone(&counter);
+      thread::spawn(move || {
+        counter.store(counter.load(SeqCst) + 1, SeqCst)
+      })
+    })
+    .collect();
+  for t in threads {
+    t.join().unwrap();
+  }
+  assert_eq!(counter.load(SeqCst), 2);
+}
+
+#[test]
+#[should_panic]
+fn pct_finds_lost_update() {
+  check_pct(lost_update, 100, 2);
+}
+
+#[test]
+fn replay_sequential() {
+  // Always picking the oldest runnable thread runs the children
+  // one after another.
+  replay(lost_update, "");
+}
+
+#[test]
+#[should_panic]
+fn replay_racy() {
+  replay(lost_update, "0.0.0.0.1.1.1.1.1.0.0");
+}
@@ -0,0 +1,104 @@
+//! An instrumented spinlock.
+//!
+//! Each failed attempt to take the lock goes through
+//! [`hint::spin_loop`], so under exploration the waiting thread
+//! only retries once another thread has done something, rather
+//! than the explorer trying ever longer runs of failed attempts.
+//!
+//! [`hint::spin_loop`]: crate::loom_compat::hint::spin_loop
+
+use std::{
+  cell::UnsafeCell,
+  ops::{Deref, DerefMut},
+  sync::atomic::Ordering::{Acquire, Relaxed, Release},
+};
+
+use crate::{loom_compat::hint, managed_thread::AtomicU8};
+
+pub struct SpinLock<T> {
+  locked: AtomicU8,
+  value: UnsafeCell<T>,
+}
+
+// SAFETY: `value` is only accessed through the guard, which the
+// `locked` flag makes exclusive.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+  pub fn new(value: T) -> SpinLock<T> {
+    SpinLock {
+      locked: AtomicU8::new(0),
+      value: UnsafeCell::new(value),
+    }
+  }
+
+  pub fn lock(&self) -> SpinLockGuard<'_, T> {
+    while self
+      .locked
+      .compare_exchange_weak(0, 1, Acquire, Relaxed)
+      .is_err()
+    {
+      hint::spin_loop()
+    }
+    SpinLockGuard { lock: self }
+  }
+}
+
+pub struct SpinLockGuard<'a, T> {
+  lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // SAFETY: the guard holds the lock.
+    unsafe { &*self.lock.value.get() }
+  }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    // SAFETY: the guard holds the lock.
+    unsafe { &mut *self.lock.value.get() }
+  }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+  fn drop(&mut self) {
+    self.lock.locked.store(0, Release)
+  }
+}
+
+#[cfg(test)]
+fn contend(leak: bool) {
+  use crate::loom_compat::{self, sync::Arc, thread};
+
+  loom_compat::model(move || {
+    let lock = Arc::new(SpinLock::new(0));
+    let t = thread::spawn({
+      let lock = Arc::clone(&lock);
+      move || *lock.lock() += 1
+    });
+    let mut guard = lock.lock();
+    *guard += 1;
+    if leak {
+      std::mem::forget(guard);
+    } else {
+      drop(guard);
+      t.join().unwrap();
+      assert_eq!(*lock.lock(), 2);
+    }
+  })
+}
+
+#[test]
+fn contended_locks_terminate() {
+  contend(false)
+}
+
+#[test]
+#[should_panic(expected = "deadlock")]
+fn spinning_on_a_leaked_lock_deadlocks() {
+  contend(true)
+}
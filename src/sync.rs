@@ -0,0 +1,34 @@
+//! Mirror of `std::sync`.
+//!
+//! Code under test imports `properly_concurrent::sync` instead
+//! of `std::sync`. Normally every item is the std one. In this
+//! crate's own tests, or with the `instrument` feature, only
+//! the atomics in [`atomic`] and [`LazyLock`] are swapped for
+//! instrumented ones. Everything else, `Mutex` and `mpsc`
+//! included, is still std's, so the explorer doesn't see it:
+//! the instrumented versions are in [`crate::loom_compat`].
+
+pub use std::sync::*;
+
+#[cfg(any(test, feature = "instrument"))]
+pub use crate::loom_compat::sync::LazyLock;
+
+pub mod atomic {
+  #[cfg(any(test, feature = "instrument"))]
+  pub use super::instrumented_atomic::*;
+  #[cfg(not(any(test, feature = "instrument")))]
+  pub use std::sync::atomic::*;
+}
+
+/// `std::sync::atomic` with the atomics instrumented whatever
+/// the features, for `#[instrument_atomics]` to point at, see
+/// [`crate::instrument_atomics`].
+pub mod instrumented_atomic {
+  pub use std::sync::atomic::*;
+
+  pub use crate::atomic::fence;
+  pub use crate::managed_thread::{
+    AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize,
+    AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize,
+  };
+}
@@ -0,0 +1,91 @@
+//! Mirror of `std::thread`, for code under test which spawns
+//! threads of its own.
+//!
+//! [`spawn`] is [`std::thread::spawn`], except under
+//! [`loom_compat::model`], where the child is a managed thread
+//! of the exploration, as if from
+//! [`loom_compat::thread::spawn`]. A library which spawns a
+//! helper internally then comes under the scheduler without an
+//! API to hand it threads. Everything else is the std item, see
+//! [`crate::time::sleep`] for a virtual `sleep`.
+//!
+//! [`loom_compat::model`]: crate::loom_compat::model
+
+pub use std::thread::*;
+
+use std::fmt;
+
+use crate::loom_compat;
+
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  let inner = if loom_compat::active() {
+    Inner::Managed(loom_compat::thread::spawn(f))
+  } else {
+    Inner::Std(std::thread::spawn(f))
+  };
+  JoinHandle { inner }
+}
+
+pub struct JoinHandle<T> {
+  inner: Inner<T>,
+}
+
+enum Inner<T> {
+  Std(std::thread::JoinHandle<T>),
+  Managed(loom_compat::thread::JoinHandle<T>),
+}
+
+impl<T> JoinHandle<T> {
+  pub fn join(self) -> Result<T> {
+    match self.inner {
+      Inner::Std(it) => it.join(),
+      Inner::Managed(it) => it.join(),
+    }
+  }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.inner {
+      Inner::Std(it) => it.fmt(f),
+      Inner::Managed(it) => it.fmt(f),
+    }
+  }
+}
+
+#[test]
+fn outside_of_models_threads_are_plain() {
+  let t = spawn(|| 92);
+  assert!(format!("{t:?}").starts_with("JoinHandle"));
+  assert_eq!(t.join().unwrap(), 92);
+}
+
+#[test]
+fn models_interleave_spawned_threads() {
+  use std::{collections::BTreeSet, sync::Mutex};
+
+  use crate::sync::{
+    atomic::{AtomicUsize, Ordering::SeqCst},
+    Arc,
+  };
+
+  static SEEN: Mutex<BTreeSet<usize>> =
+    Mutex::new(BTreeSet::new());
+
+  loom_compat::model(|| {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let t = spawn({
+      let counter = Arc::clone(&counter);
+      move || counter.store(counter.load(SeqCst) + 1, SeqCst)
+    });
+    counter.store(counter.load(SeqCst) + 1, SeqCst);
+    t.join().unwrap();
+    SEEN.lock().unwrap().insert(counter.load(SeqCst));
+  });
+  let seen = SEEN.lock().unwrap();
+  assert_eq!(*seen, BTreeSet::from([1, 2]));
+}
@@ -0,0 +1,390 @@
+//! Virtual time for the code under test: drop-in [`Instant`],
+//! [`SystemTime`] and [`sleep`].
+//!
+//! Under [`loom_compat::model`] and the explorers built on it,
+//! the clock starts at zero every iteration and only ever jumps
+//! to the earliest wake-up of a sleeping thread. It does so when
+//! no thread can run, and otherwise whenever the explorer picks
+//! it like it picks threads, so a timeout racing with what it
+//! waits for is explored both ways. Timeouts cost nothing, and
+//! debouncing, expiry or backoff logic sees the same times on
+//! every replay. Elsewhere the real clock is used.
+//!
+//! A failure which needed a [`sleep`] to end while other threads
+//! could still run points at a test which orders threads by
+//! sleeping long enough, see [`suspicious_sleeps`].
+//!
+//! [`loom_compat::model`]: crate::loom_compat::model
+
+use std::{
+  ops::{Add, AddAssign, Sub},
+  panic::Location,
+  sync::{Arc, Mutex, OnceLock},
+  time::Duration,
+};
+
+use crate::{loom_compat, managed_thread};
+
+/// The clock of one iteration.
+#[derive(Default)]
+pub(crate) struct Clock {
+  state: Mutex<ClockState>,
+}
+
+#[derive(Default)]
+struct ClockState {
+  now: Duration,
+  /// When sleeping threads wake up, one entry per thread.
+  deadlines: Vec<Duration>,
+  /// The deadlines of [`sleep`]s rather than of timeouts.
+  sleeps: Vec<(Duration, Site)>,
+}
+
+type Site = &'static Location<'static>;
+
+impl Clock {
+  fn now(&self) -> Duration {
+    self.state.lock().unwrap().now
+  }
+
+  /// Blocks until `until` holds or `timeout` passes.
+  pub(crate) fn wait(
+    self: &Arc<Clock>,
+    timeout: Duration,
+    until: impl Fn() -> bool + Send + Sync + 'static,
+  ) {
+    let deadline = {
+      let mut state = self.state.lock().unwrap();
+      let deadline = state.now + timeout;
+      state.deadlines.push(deadline);
+      deadline
+    };
+    let clock = Arc::clone(self);
+    managed_thread::block_until(Arc::new(move || {
+      until() || clock.now() >= deadline
+    }));
+    let mut state = self.state.lock().unwrap();
+    let i =
+      state.deadlines.iter().position(|&it| it == deadline);
+    state.deadlines.swap_remove(i.unwrap());
+  }
+
+  fn sleep(self: &Arc<Clock>, duration: Duration, site: Site) {
+    let deadline = {
+      let mut state = self.state.lock().unwrap();
+      let deadline = state.now + duration;
+      state.sleeps.push((deadline, site));
+      deadline
+    };
+    self.wait(duration, || false);
+    let mut state = self.state.lock().unwrap();
+    let i =
+      state.sleeps.iter().position(|&(it, _)| it == deadline);
+    state.sleeps.swap_remove(i.unwrap());
+  }
+
+  /// The [`sleep`] which ends first if time passes now, unless a
+  /// timeout does.
+  pub(crate) fn next_sleep(&self) -> Option<Site> {
+    let state = self.state.lock().unwrap();
+    let now = state.now;
+    let next = state
+      .deadlines
+      .iter()
+      .copied()
+      .filter(|&it| it > now)
+      .min()?;
+    let (_, site) =
+      state.sleeps.iter().find(|&&(it, _)| it == next)?;
+    Some(*site)
+  }
+
+  /// Whether some thread waits for time to pass.
+  pub(crate) fn pending(&self) -> bool {
+    let state = self.state.lock().unwrap();
+    state.deadlines.iter().any(|&it| it > state.now)
+  }
+
+  /// Moves to the earliest deadline still in the future, returns
+  /// `false` if nobody is waiting for one.
+  pub(crate) fn advance(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+    let now = state.now;
+    let next = state
+      .deadlines
+      .iter()
+      .copied()
+      .filter(|&it| it > now)
+      .min();
+    match next {
+      Some(next) => {
+        state.now = next;
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+/// Time since the start of the iteration, or of the process
+/// outside of one.
+fn elapsed() -> Duration {
+  static START: OnceLock<std::time::Instant> = OnceLock::new();
+  match loom_compat::clock() {
+    Some(clock) => {
+      loom_compat::injected_yield();
+      clock.now()
+    }
+    None => START.get_or_init(std::time::Instant::now).elapsed(),
+  }
+}
+
+/// Like [`std::thread::sleep`], but on the virtual clock.
+#[track_caller]
+pub fn sleep(duration: Duration) {
+  match loom_compat::clock() {
+    Some(clock) => clock.sleep(duration, Location::caller()),
+    None => std::thread::sleep(duration),
+  }
+}
+
+static SUSPICIOUS: Mutex<Vec<Site>> = Mutex::new(Vec::new());
+
+/// Warns, once per site, about the [`sleep`] at `site`, which
+/// ended while other threads could still run in an iteration
+/// which then failed.
+pub(crate) fn suspect(site: Site) {
+  let mut suspicious = SUSPICIOUS.lock().unwrap();
+  if !suspicious.contains(&site) {
+    eprintln!(
+      "warning: the failure needs the sleep at {site} to end \
+      while other threads could still run. If the test relies \
+      on the sleep to let them go first, it only passes as long \
+      as it is long enough; wait for them instead"
+    );
+    suspicious.push(site);
+  }
+}
+
+/// The sleeps warned about so far in the process, for a test to
+/// fail on rather than to only print.
+pub fn suspicious_sleeps() -> Vec<&'static Location<'static>> {
+  SUSPICIOUS.lock().unwrap().clone()
+}
+
+/// Like [`std::time::Instant`], but on the virtual clock.
+#[derive(
+  Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug,
+)]
+pub struct Instant(Duration);
+
+impl Instant {
+  pub fn now() -> Instant {
+    Instant(elapsed())
+  }
+
+  pub fn duration_since(&self, earlier: Instant) -> Duration {
+    self.0.saturating_sub(earlier.0)
+  }
+
+  pub fn elapsed(&self) -> Duration {
+    Instant::now().duration_since(*self)
+  }
+
+  pub fn checked_add(
+    &self,
+    duration: Duration,
+  ) -> Option<Instant> {
+    self.0.checked_add(duration).map(Instant)
+  }
+
+  pub fn checked_sub(
+    &self,
+    duration: Duration,
+  ) -> Option<Instant> {
+    self.0.checked_sub(duration).map(Instant)
+  }
+}
+
+impl Add<Duration> for Instant {
+  type Output = Instant;
+
+  fn add(self, duration: Duration) -> Instant {
+    Instant(self.0 + duration)
+  }
+}
+
+impl AddAssign<Duration> for Instant {
+  fn add_assign(&mut self, duration: Duration) {
+    self.0 += duration
+  }
+}
+
+impl Sub<Duration> for Instant {
+  type Output = Instant;
+
+  fn sub(self, duration: Duration) -> Instant {
+    Instant(self.0 - duration)
+  }
+}
+
+impl Sub<Instant> for Instant {
+  type Output = Duration;
+
+  fn sub(self, earlier: Instant) -> Duration {
+    self.duration_since(earlier)
+  }
+}
+
+/// Like [`std::time::SystemTime`], but on the virtual clock. An
+/// iteration starts at a fixed date, so that replays agree.
+#[derive(
+  Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug,
+)]
+pub struct SystemTime(std::time::SystemTime);
+
+/// 2024-07-05, for [`SystemTime`] under exploration.
+const VIRTUAL_EPOCH: Duration =
+  Duration::from_secs(1_720_137_600);
+
+impl SystemTime {
+  pub const UNIX_EPOCH: SystemTime =
+    SystemTime(std::time::SystemTime::UNIX_EPOCH);
+
+  pub fn now() -> SystemTime {
+    match loom_compat::clock() {
+      Some(clock) => {
+        loom_compat::injected_yield();
+        SystemTime::UNIX_EPOCH + VIRTUAL_EPOCH + clock.now()
+      }
+      None => SystemTime(std::time::SystemTime::now()),
+    }
+  }
+
+  pub fn duration_since(
+    &self,
+    earlier: SystemTime,
+  ) -> Result<Duration, std::time::SystemTimeError> {
+    self.0.duration_since(earlier.0)
+  }
+
+  pub fn elapsed(
+    &self,
+  ) -> Result<Duration, std::time::SystemTimeError> {
+    SystemTime::now().duration_since(*self)
+  }
+}
+
+impl Add<Duration> for SystemTime {
+  type Output = SystemTime;
+
+  fn add(self, duration: Duration) -> SystemTime {
+    SystemTime(self.0 + duration)
+  }
+}
+
+impl Sub<Duration> for SystemTime {
+  type Output = SystemTime;
+
+  fn sub(self, duration: Duration) -> SystemTime {
+    SystemTime(self.0 - duration)
+  }
+}
+
+impl From<SystemTime> for std::time::SystemTime {
+  fn from(time: SystemTime) -> std::time::SystemTime {
+    time.0
+  }
+}
+
+#[test]
+fn sleeping_is_instant() {
+  let start = std::time::Instant::now();
+  loom_compat::model(|| {
+    let before = Instant::now();
+    sleep(Duration::from_secs(3600));
+    assert_eq!(before.elapsed(), Duration::from_secs(3600));
+  });
+  assert!(start.elapsed() < Duration::from_secs(60));
+}
+
+#[test]
+fn sleeps_wake_in_order() {
+  use loom_compat::{
+    sync::atomic::{AtomicU8, Ordering::SeqCst},
+    thread,
+  };
+
+  loom_compat::model(|| {
+    let done = Arc::new(AtomicU8::new(0));
+    let t = thread::spawn({
+      let done = Arc::clone(&done);
+      move || {
+        sleep(Duration::from_secs(2));
+        done.store(1, SeqCst)
+      }
+    });
+    sleep(Duration::from_secs(1));
+    assert!(Instant::now() >= Instant(Duration::from_secs(1)));
+    t.join().unwrap();
+    assert_eq!(done.load(SeqCst), 1);
+    // 3s if `t` was done sleeping before this thread started to.
+    let end = Instant::now().0.as_secs();
+    assert!(end == 2 || end == 3, "{end}");
+  });
+}
+
+#[test]
+fn sleeping_instead_of_waiting_is_suspicious() {
+  use loom_compat::{
+    sync::atomic::{AtomicU8, Ordering::SeqCst},
+    thread,
+  };
+
+  static LINE: std::sync::atomic::AtomicU32 =
+    std::sync::atomic::AtomicU32::new(0);
+
+  let result = std::panic::catch_unwind(|| {
+    loom_compat::model(|| {
+      let done = Arc::new(AtomicU8::new(0));
+      let t = thread::spawn({
+        let done = Arc::clone(&done);
+        move || done.store(1, SeqCst)
+      });
+      let ((), line) = (sleep(Duration::from_secs(1)), line!());
+      LINE.store(line, SeqCst);
+      assert_eq!(done.load(SeqCst), 1);
+      t.join().unwrap();
+    })
+  });
+  assert!(result.is_err());
+  let line = LINE.load(SeqCst);
+  assert!(suspicious_sleeps()
+    .iter()
+    .any(|it| it.line() == line));
+}
+
+#[test]
+fn timeouts_race_unpark() {
+  use loom_compat::thread;
+  use std::collections::HashSet;
+
+  static TIMED_OUT: Mutex<Option<HashSet<bool>>> =
+    Mutex::new(None);
+
+  loom_compat::model(|| {
+    let main = thread::current();
+    let t = thread::spawn(move || main.unpark());
+    thread::park_timeout(Duration::from_secs(1));
+    let timed_out =
+      Instant::now() == Instant(Duration::from_secs(1));
+    TIMED_OUT
+      .lock()
+      .unwrap()
+      .get_or_insert_default()
+      .insert(timed_out);
+    t.join().unwrap();
+  });
+  let seen = TIMED_OUT.lock().unwrap().take().unwrap();
+  assert_eq!(seen.len(), 2);
+}
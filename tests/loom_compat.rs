@@ -0,0 +1,44 @@
+//! The fault options of [`explore::Config`], reached the way a
+//! dependent crate reaches them.
+
+#![cfg(feature = "std")]
+
+use std::{collections::BTreeSet, sync::Mutex};
+
+use properly_concurrent::{
+  explore::Config,
+  loom_compat::{
+    self,
+    sync::{
+      atomic::{AtomicU8, Ordering::SeqCst},
+      Arc,
+    },
+    thread,
+  },
+};
+
+#[test]
+fn crashed_threads_fail_their_join() {
+  static OUTCOMES: Mutex<BTreeSet<(bool, u8)>> =
+    Mutex::new(BTreeSet::new());
+
+  let config = Config::new().exhaustive().thread_crashes();
+  loom_compat::model_with(config, || {
+    let flag = Arc::new(AtomicU8::new(0));
+    let t = thread::spawn({
+      let flag = Arc::clone(&flag);
+      move || flag.store(1, SeqCst)
+    });
+    let crashed = t.join().is_err();
+    OUTCOMES
+      .lock()
+      .unwrap()
+      .insert((crashed, flag.load(SeqCst)));
+  });
+  // The thread may crash before its store or after it.
+  let outcomes = OUTCOMES.lock().unwrap().clone();
+  assert_eq!(
+    outcomes,
+    [(false, 1), (true, 0), (true, 1)].into()
+  );
+}
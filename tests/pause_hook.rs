@@ -0,0 +1,26 @@
+//! A pause hook stays for the rest of the process, so installing
+//! one gets a test binary of its own.
+
+#![cfg(feature = "pause")]
+
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+use properly_concurrent::atomic::{self, PauseHook};
+
+#[test]
+fn custom_hook() {
+  struct Counting(AtomicUsize);
+
+  impl PauseHook for Counting {
+    fn pause(&self) {
+      self.0.fetch_add(1, SeqCst);
+    }
+  }
+
+  static HOOK: Counting = Counting(AtomicUsize::new(0));
+  atomic::set_pause_hook(&HOOK).ok().unwrap();
+  assert!(atomic::set_pause_hook(&HOOK).is_err());
+
+  atomic::AtomicU32::new(92).store(62, SeqCst);
+  assert_eq!(HOOK.0.load(SeqCst), 2);
+}
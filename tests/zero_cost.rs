@@ -0,0 +1,63 @@
+//! Links against the library as a dependent crate would, without
+//! `cfg(test)`, so that the build without the `pause` feature
+//! can be checked to call no hooks and to keep the layout of
+//! `core`: run with
+//! `cargo test --no-default-features --features std`.
+
+use std::{
+  mem::{align_of, size_of},
+  sync::atomic::{AtomicUsize, Ordering::SeqCst},
+};
+
+use properly_concurrent::atomic::{self, PauseHook};
+
+#[test]
+fn thin_types_pause_only_with_the_feature() {
+  struct Counting(AtomicUsize);
+
+  impl PauseHook for Counting {
+    fn pause(&self) {
+      self.0.fetch_add(1, SeqCst);
+    }
+  }
+
+  static HOOK: Counting = Counting(AtomicUsize::new(0));
+  atomic::set_pause_hook(&HOOK).ok().unwrap();
+
+  let value = atomic::AtomicU64::new(0);
+  value.store(1, SeqCst);
+  value.fetch_add(1, SeqCst);
+  let _ = value.compare_exchange(2, 3, SeqCst, SeqCst);
+  atomic::fence(SeqCst);
+  properly_concurrent::checkpoint();
+  assert_eq!(value.load(SeqCst), 3);
+
+  let pauses = HOOK.0.load(SeqCst);
+  if cfg!(feature = "pause") {
+    assert_eq!(pauses, 10);
+  } else {
+    assert_eq!(pauses, 0);
+  }
+}
+
+#[test]
+fn thin_types_have_the_core_layout() {
+  use std::sync::atomic as core;
+
+  assert_eq!(
+    size_of::<atomic::AtomicU8>(),
+    size_of::<core::AtomicU8>()
+  );
+  assert_eq!(
+    size_of::<atomic::AtomicU64>(),
+    size_of::<core::AtomicU64>()
+  );
+  assert_eq!(
+    align_of::<atomic::AtomicU64>(),
+    align_of::<core::AtomicU64>()
+  );
+  assert_eq!(
+    size_of::<Option<atomic::AtomicUsize>>(),
+    size_of::<Option<core::AtomicUsize>>()
+  );
+}